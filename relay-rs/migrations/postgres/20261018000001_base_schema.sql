@@ -0,0 +1,50 @@
+-- Tables the relay reads and writes, matching the Prisma models in
+-- prisma/schema.prisma. On a database already set up by `npm run db:push`
+-- this is a no-op; on a fresh database it lets the relay start on its own.
+
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'WhitelistStatus') THEN
+        CREATE TYPE "WhitelistStatus" AS ENUM ('PENDING', 'ACTIVE', 'PAUSED', 'REVOKED', 'VANISHED');
+    END IF;
+END
+$$;
+
+CREATE TABLE IF NOT EXISTS "users" (
+    "id" TEXT NOT NULL,
+    "npub" TEXT NOT NULL,
+    "pubkey" TEXT NOT NULL,
+    "createdAt" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "updatedAt" TIMESTAMP(3) NOT NULL,
+    "isAdmin" BOOLEAN NOT NULL DEFAULT false,
+    "whitelistStatus" "WhitelistStatus" NOT NULL DEFAULT 'PENDING',
+
+    CONSTRAINT "users_pkey" PRIMARY KEY ("id")
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS "users_npub_key" ON "users"("npub");
+CREATE UNIQUE INDEX IF NOT EXISTS "users_pubkey_key" ON "users"("pubkey");
+
+CREATE TABLE IF NOT EXISTS "events" (
+    "id" TEXT NOT NULL,
+    "eventId" TEXT NOT NULL,
+    "pubkey" TEXT NOT NULL,
+    "kind" INTEGER NOT NULL,
+    "content" TEXT NOT NULL,
+    "tags" JSONB NOT NULL,
+    "sig" TEXT NOT NULL,
+    "createdAt" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "receivedAt" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT "events_pkey" PRIMARY KEY ("id"),
+    CONSTRAINT "events_pubkey_fkey" FOREIGN KEY ("pubkey") REFERENCES "users"("pubkey") ON DELETE RESTRICT ON UPDATE CASCADE
+);
+
+-- NIP-40 expiration was added after the first deployments
+ALTER TABLE "events" ADD COLUMN IF NOT EXISTS "expiresAt" TIMESTAMP(3);
+
+CREATE UNIQUE INDEX IF NOT EXISTS "events_eventId_key" ON "events"("eventId");
+CREATE INDEX IF NOT EXISTS "events_kind_idx" ON "events"("kind");
+CREATE INDEX IF NOT EXISTS "events_pubkey_idx" ON "events"("pubkey");
+CREATE INDEX IF NOT EXISTS "events_createdAt_idx" ON "events"("createdAt");
+CREATE INDEX IF NOT EXISTS "events_eventId_idx" ON "events"("eventId");
@@ -0,0 +1,11 @@
+-- Store Nostr created_at as an integer epoch so since/until filters can use
+-- an index instead of EXTRACT(EPOCH FROM "createdAt")
+--
+-- Adding a column with a constant default only touches the catalog, so this
+-- doesn't rewrite `events`. Existing rows keep 0 until `PostgresStore::maintain`
+-- backfills them in small batches after startup; until then the relay reads
+-- their time from "createdAt". `maintain` also builds the indexes CONCURRENTLY,
+-- which a migration can't, since sqlx runs each one in a transaction.
+
+ALTER TABLE events
+ADD COLUMN IF NOT EXISTS created_at BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,73 @@
+-- Persistent lists behind the NIP-86 relay management API
+
+-- Ban/allow reasons are kept in the existing notes column
+ALTER TABLE "users" ADD COLUMN IF NOT EXISTS "whitelistNotes" TEXT;
+
+CREATE TABLE IF NOT EXISTS "banned_events" (
+    "event_id" TEXT NOT NULL,
+    "reason" TEXT,
+    "created_at" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT "banned_events_pkey" PRIMARY KEY ("event_id")
+);
+
+-- When non-empty, only these kinds are accepted
+CREATE TABLE IF NOT EXISTS "allowed_kinds" (
+    "kind" INTEGER NOT NULL,
+    "created_at" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT "allowed_kinds_pkey" PRIMARY KEY ("kind")
+);
+
+CREATE TABLE IF NOT EXISTS "blocked_ips" (
+    "ip" TEXT NOT NULL,
+    "reason" TEXT,
+    "created_at" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT "blocked_ips_pkey" PRIMARY KEY ("ip")
+);
+
+-- Overrides for the relay information document (name, description, icon)
+CREATE TABLE IF NOT EXISTS "relay_settings" (
+    "key" TEXT NOT NULL,
+    "value" TEXT NOT NULL,
+    "updated_at" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT "relay_settings_pkey" PRIMARY KEY ("key")
+);
+
+-- Reports awaiting moderation live on the web app's admin message board
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'AdminMessageType') THEN
+        CREATE TYPE "AdminMessageType" AS ENUM ('BLACKLIST_REQUEST', 'WHITELIST_REQUEST', 'SUPPORT_REQUEST', 'APPEAL', 'REPORT');
+    END IF;
+    IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'AdminMessageStatus') THEN
+        CREATE TYPE "AdminMessageStatus" AS ENUM ('PENDING', 'IN_REVIEW', 'APPROVED', 'DENIED', 'RESOLVED');
+    END IF;
+END
+$$;
+
+CREATE TABLE IF NOT EXISTS "admin_messages" (
+    "id" TEXT NOT NULL,
+    "type" "AdminMessageType" NOT NULL,
+    "status" "AdminMessageStatus" NOT NULL DEFAULT 'PENDING',
+    "subject" TEXT NOT NULL,
+    "content" TEXT NOT NULL,
+    "submitterNpub" TEXT,
+    "submitterPubkey" TEXT,
+    "targetNpub" TEXT,
+    "targetPubkey" TEXT,
+    "metadata" JSONB,
+    "resolvedById" TEXT,
+    "resolvedAt" TIMESTAMP(3),
+    "resolution" TEXT,
+    "createdAt" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "updatedAt" TIMESTAMP(3) NOT NULL,
+
+    CONSTRAINT "admin_messages_pkey" PRIMARY KEY ("id")
+);
+
+CREATE INDEX IF NOT EXISTS "admin_messages_type_idx" ON "admin_messages"("type");
+CREATE INDEX IF NOT EXISTS "admin_messages_status_idx" ON "admin_messages"("status");
+CREATE INDEX IF NOT EXISTS "admin_messages_targetPubkey_idx" ON "admin_messages"("targetPubkey");
@@ -0,0 +1,27 @@
+-- Append-only record of every management action (NIP-86 and CLI)
+
+CREATE TABLE IF NOT EXISTS "admin_audit_log" (
+    "id" BIGSERIAL NOT NULL,
+    "admin_pubkey" TEXT,
+    "source" TEXT NOT NULL,
+    "method" TEXT NOT NULL,
+    "params" JSONB NOT NULL,
+    "result" JSONB NOT NULL,
+    "success" BOOLEAN NOT NULL,
+    "created_at" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT "admin_audit_log_pkey" PRIMARY KEY ("id")
+);
+
+CREATE INDEX IF NOT EXISTS "admin_audit_log_admin_pubkey_idx" ON "admin_audit_log"("admin_pubkey");
+
+CREATE OR REPLACE FUNCTION admin_audit_log_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'admin_audit_log is append-only';
+END
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS admin_audit_log_append_only ON "admin_audit_log";
+CREATE TRIGGER admin_audit_log_append_only
+    BEFORE UPDATE OR DELETE ON "admin_audit_log"
+    FOR EACH ROW EXECUTE FUNCTION admin_audit_log_append_only();
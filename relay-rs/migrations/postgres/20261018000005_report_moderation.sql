@@ -0,0 +1,3 @@
+-- NIP-56 reports can hide an event until an admin bans or allows it
+
+ALTER TABLE "banned_events" ADD COLUMN IF NOT EXISTS "pending_review" BOOLEAN NOT NULL DEFAULT false;
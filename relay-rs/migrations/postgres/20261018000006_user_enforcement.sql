@@ -0,0 +1,5 @@
+-- Permanent bans and violation counts are set by the web app and enforced by the relay
+
+ALTER TABLE "users" ADD COLUMN IF NOT EXISTS "blacklistViolations" INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE "users" ADD COLUMN IF NOT EXISTS "lastBlacklistViolation" TIMESTAMP(3);
+ALTER TABLE "users" ADD COLUMN IF NOT EXISTS "permanentlyBanned" BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,9 @@
+-- Where each REQ mirroring source left off, so a restart doesn't refetch its history
+
+CREATE TABLE IF NOT EXISTS "mirror_cursors" (
+    "source" TEXT NOT NULL,
+    "last_created_at" BIGINT NOT NULL,
+    "updated_at" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT "mirror_cursors_pkey" PRIMARY KEY ("source")
+);
@@ -0,0 +1,8 @@
+-- Kinds refused by NIP-86 disallowkind, whether or not allowed_kinds is empty
+
+CREATE TABLE IF NOT EXISTS "disallowed_kinds" (
+    "kind" INTEGER NOT NULL,
+    "created_at" TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT "disallowed_kinds_pkey" PRIMARY KEY ("kind")
+);
@@ -0,0 +1,3 @@
+-- Marks the relay's own user row so member listings leave it out
+
+ALTER TABLE "users" ADD COLUMN IF NOT EXISTS "isRelayIdentity" BOOLEAN NOT NULL DEFAULT false;
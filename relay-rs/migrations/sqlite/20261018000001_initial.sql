@@ -0,0 +1,22 @@
+CREATE TABLE IF NOT EXISTS events (
+    event_id    TEXT PRIMARY KEY,
+    pubkey      TEXT NOT NULL,
+    kind        INTEGER NOT NULL,
+    content     TEXT NOT NULL,
+    tags        TEXT NOT NULL,
+    sig         TEXT NOT NULL,
+    created_at  INTEGER NOT NULL,
+    received_at INTEGER NOT NULL,
+    expires_at  INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS events_created_at_idx ON events(created_at);
+CREATE INDEX IF NOT EXISTS events_kind_created_at_idx ON events(kind, created_at);
+CREATE INDEX IF NOT EXISTS events_pubkey_created_at_idx ON events(pubkey, created_at);
+
+CREATE TABLE IF NOT EXISTS users (
+    pubkey           TEXT PRIMARY KEY,
+    npub             TEXT NOT NULL,
+    is_admin         INTEGER NOT NULL DEFAULT 0,
+    whitelist_status TEXT NOT NULL DEFAULT 'PENDING'
+);
@@ -0,0 +1,42 @@
+-- Persistent lists behind the NIP-86 relay management API
+
+ALTER TABLE users ADD COLUMN notes TEXT;
+
+CREATE TABLE IF NOT EXISTS banned_events (
+    event_id   TEXT PRIMARY KEY,
+    reason     TEXT,
+    created_at INTEGER NOT NULL DEFAULT (CAST(strftime('%s', 'now') AS INTEGER))
+);
+
+-- When non-empty, only these kinds are accepted
+CREATE TABLE IF NOT EXISTS allowed_kinds (
+    kind       INTEGER PRIMARY KEY,
+    created_at INTEGER NOT NULL DEFAULT (CAST(strftime('%s', 'now') AS INTEGER))
+);
+
+CREATE TABLE IF NOT EXISTS blocked_ips (
+    ip         TEXT PRIMARY KEY,
+    reason     TEXT,
+    created_at INTEGER NOT NULL DEFAULT (CAST(strftime('%s', 'now') AS INTEGER))
+);
+
+-- Overrides for the relay information document (name, description, icon)
+CREATE TABLE IF NOT EXISTS relay_settings (
+    key   TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+
+-- Reports awaiting moderation (the Postgres backend uses the web app's admin_messages)
+CREATE TABLE IF NOT EXISTS admin_messages (
+    id               TEXT PRIMARY KEY,
+    type             TEXT NOT NULL,
+    status           TEXT NOT NULL DEFAULT 'PENDING',
+    subject          TEXT NOT NULL,
+    content          TEXT NOT NULL,
+    submitter_pubkey TEXT,
+    target_pubkey    TEXT,
+    event_id         TEXT,
+    created_at       INTEGER NOT NULL DEFAULT (CAST(strftime('%s', 'now') AS INTEGER))
+);
+
+CREATE INDEX IF NOT EXISTS admin_messages_status_idx ON admin_messages(status);
@@ -0,0 +1,24 @@
+-- Append-only record of every management action (NIP-86 and CLI)
+
+CREATE TABLE IF NOT EXISTS admin_audit_log (
+    id           INTEGER PRIMARY KEY AUTOINCREMENT,
+    admin_pubkey TEXT,
+    source       TEXT NOT NULL,
+    method       TEXT NOT NULL,
+    params       TEXT NOT NULL,
+    result       TEXT NOT NULL,
+    success      INTEGER NOT NULL,
+    created_at   INTEGER NOT NULL DEFAULT (CAST(strftime('%s', 'now') AS INTEGER))
+);
+
+CREATE INDEX IF NOT EXISTS admin_audit_log_admin_pubkey_idx ON admin_audit_log(admin_pubkey);
+
+CREATE TRIGGER IF NOT EXISTS admin_audit_log_no_update BEFORE UPDATE ON admin_audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'admin_audit_log is append-only');
+END;
+
+CREATE TRIGGER IF NOT EXISTS admin_audit_log_no_delete BEFORE DELETE ON admin_audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'admin_audit_log is append-only');
+END;
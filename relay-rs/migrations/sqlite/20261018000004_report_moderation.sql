@@ -0,0 +1,9 @@
+-- NIP-56 reports can hide an event until an admin bans or allows it
+
+ALTER TABLE banned_events ADD COLUMN pending_review INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE admin_messages ADD COLUMN report_type TEXT;
+ALTER TABLE admin_messages ADD COLUMN resolution TEXT;
+ALTER TABLE admin_messages ADD COLUMN resolved_at INTEGER;
+
+CREATE INDEX IF NOT EXISTS admin_messages_event_id_idx ON admin_messages(event_id);
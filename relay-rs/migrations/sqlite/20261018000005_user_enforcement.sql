@@ -0,0 +1,5 @@
+-- Permanent bans and counts of events rejected by relay policy
+
+ALTER TABLE users ADD COLUMN permanently_banned INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN blacklist_violations INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN last_violation_at INTEGER;
@@ -0,0 +1,7 @@
+-- Where each REQ mirroring source left off, so a restart doesn't refetch its history
+
+CREATE TABLE IF NOT EXISTS mirror_cursors (
+    source          TEXT PRIMARY KEY,
+    last_created_at INTEGER NOT NULL,
+    updated_at      INTEGER NOT NULL DEFAULT (CAST(strftime('%s', 'now') AS INTEGER))
+);
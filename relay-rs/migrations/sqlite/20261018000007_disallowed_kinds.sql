@@ -0,0 +1,6 @@
+-- Kinds refused by NIP-86 disallowkind, whether or not allowed_kinds is empty
+
+CREATE TABLE IF NOT EXISTS disallowed_kinds (
+    kind       INTEGER PRIMARY KEY,
+    created_at INTEGER NOT NULL DEFAULT (CAST(strftime('%s', 'now') AS INTEGER))
+);
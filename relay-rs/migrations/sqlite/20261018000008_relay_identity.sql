@@ -0,0 +1,3 @@
+-- Marks the relay's own user row so member listings leave it out
+
+ALTER TABLE users ADD COLUMN is_relay_identity INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,92 @@
+// Audit trail for management actions
+//
+// Every NIP-86 call (websocket or HTTP) and every `relay-rs admin` command is
+// appended to `admin_audit_log`. With `RELAY_PUBLISH_AUDIT` set, each entry is
+// also published as a NIP-78 event so it can be followed over nostr. Published
+// events carry only the method, its target and whether it succeeded: reasons,
+// results and IP addresses stay in the table. They are signed by the relay's persistent key, which must already be registered as a
+// user (`identity::register`) for Postgres to store them; without a key they
+// aren't published.
+
+use crate::{
+    fanout,
+    store::{AuditEntry, AuditRecord},
+    AppState,
+};
+use nostr::{EventBuilder, Keys, Kind, Tag};
+use serde_json::{json, Value};
+use std::{net::IpAddr, sync::Arc};
+use tracing::error;
+
+/// NIP-78 application-specific data; each entry gets its own `d` tag
+const AUDIT_EVENT_KIND: u64 = 30078;
+
+pub async fn record(state: &Arc<AppState>, entry: AuditEntry) {
+    let record = match state.store.append_audit(&entry).await {
+        Ok(record) => record,
+        Err(e) => {
+            error!("Failed to write audit log entry for {}: {}", entry.method, e);
+            return;
+        }
+    };
+
+    if let Some(keys) = state.keys.as_ref().filter(|_| state.config.publish_audit) {
+        publish(state, keys, &record).await;
+    }
+}
+
+/// How an audit record is shown to NIP-86 clients and the CLI
+pub fn to_json(record: &AuditRecord) -> Value {
+    json!({
+        "id": record.id,
+        "created_at": record.created_at,
+        "admin": record.entry.admin_pubkey,
+        "source": record.entry.source,
+        "method": record.entry.method,
+        "params": record.entry.params,
+        "result": record.entry.result,
+        "success": record.entry.success,
+    })
+}
+
+/// What a published audit event says: no reasons, results or IP addresses
+fn public_json(record: &AuditRecord) -> Value {
+    let target = record.entry.params.get(0).filter(|target| {
+        target.as_str().is_none_or(|target| target.trim().parse::<IpAddr>().is_err())
+    });
+    json!({
+        "id": record.id,
+        "created_at": record.created_at,
+        "admin": record.entry.admin_pubkey,
+        "source": record.entry.source,
+        "method": record.entry.method,
+        "target": target,
+        "success": record.entry.success,
+    })
+}
+
+async fn publish(state: &Arc<AppState>, keys: &Keys, record: &AuditRecord) {
+    let mut tags = vec![Tag::Identifier(format!("relay-audit/{}", record.id))];
+    let mut extra = vec![
+        vec!["action", record.entry.method.as_str()],
+        vec!["source", record.entry.source.as_str()],
+    ];
+    if let Some(admin) = &record.entry.admin_pubkey {
+        extra.push(vec!["p", admin.as_str()]);
+    }
+    tags.extend(extra.into_iter().filter_map(|t| Tag::parse(t).ok()));
+
+    let event = match EventBuilder::new(Kind::from(AUDIT_EVENT_KIND), public_json(record).to_string(), tags).to_event(keys) {
+        Ok(event) => event,
+        Err(e) => {
+            error!("Failed to sign audit event: {}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = state.store.save_event(&event, None).await {
+        error!("Failed to store audit event: {}", e);
+        return;
+    }
+    fanout::broadcast(state, event).await;
+}
@@ -0,0 +1,124 @@
+// Circuit breaker for Redis
+//
+// After FAILURE_THRESHOLD consecutive failures Redis is skipped for OPEN_FOR,
+// and callers fall back to the in-process cache and the store. Once that has
+// passed, calls go through again; the first successful command closes the
+// breaker and the first failure opens it for another OPEN_FOR. Checking a
+// connection out of the pool doesn't count as success.
+
+use std::{
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+const FAILURE_THRESHOLD: u32 = 3;
+const OPEN_FOR: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+pub struct CircuitBreaker {
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// Whether Redis should be tried at all right now
+    pub fn allow(&self) -> bool {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.open_until.is_none_or(|until| Instant::now() >= until)
+    }
+
+    pub fn is_open(&self) -> bool {
+        !self.allow()
+    }
+
+    pub fn success(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.open_until.is_some() {
+            info!("Redis is reachable again; closing the circuit breaker");
+        }
+        *state = BreakerState::default();
+    }
+
+    /// Count a Redis command's outcome, passing the result through
+    pub fn record<T, E: std::fmt::Display>(&self, result: Result<T, E>) -> Result<T, E> {
+        match &result {
+            Ok(_) => self.success(),
+            Err(e) => self.failure(e),
+        }
+        result
+    }
+
+    pub fn failure(&self, error: &dyn std::fmt::Display) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.failures += 1;
+        if state.failures < FAILURE_THRESHOLD {
+            warn!("Redis error: {}", error);
+            return;
+        }
+        if state.open_until.is_none() {
+            warn!("Redis error: {}; skipping Redis for {}s", error, OPEN_FOR.as_secs());
+        }
+        state.open_until = Some(Instant::now() + OPEN_FOR);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fail(breaker: &CircuitBreaker, times: u32) {
+        for _ in 0..times {
+            breaker.failure(&"connection refused");
+        }
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures_only() {
+        let breaker = CircuitBreaker::default();
+        fail(&breaker, FAILURE_THRESHOLD - 1);
+        assert!(breaker.allow());
+        // A success in between starts the count again
+        breaker.success();
+        fail(&breaker, FAILURE_THRESHOLD - 1);
+        assert!(breaker.allow());
+        fail(&breaker, 1);
+        assert!(breaker.is_open());
+    }
+
+    #[test]
+    fn lets_calls_through_after_the_open_period_and_closes_on_success() {
+        let breaker = CircuitBreaker::default();
+        fail(&breaker, FAILURE_THRESHOLD);
+        assert!(breaker.is_open());
+
+        let elapse = |breaker: &CircuitBreaker| {
+            breaker.state.lock().unwrap().open_until = Some(Instant::now() - Duration::from_secs(1));
+        };
+        elapse(&breaker);
+        assert!(breaker.allow());
+        // One more failure while half open is enough to open it again
+        fail(&breaker, 1);
+        assert!(breaker.is_open());
+
+        elapse(&breaker);
+        assert_eq!(breaker.record(Ok::<_, String>(7)), Ok(7));
+        assert!(breaker.allow());
+        fail(&breaker, FAILURE_THRESHOLD - 1);
+        assert!(breaker.allow(), "a success should have reset the failure count");
+    }
+
+    #[test]
+    fn record_counts_errors_and_passes_results_through() {
+        let breaker = CircuitBreaker::default();
+        for _ in 0..FAILURE_THRESHOLD {
+            assert_eq!(breaker.record(Err::<(), _>("timeout")), Err("timeout"));
+        }
+        assert!(breaker.is_open());
+    }
+}
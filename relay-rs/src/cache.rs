@@ -0,0 +1,481 @@
+// Recent-events cache: the newest events in a Redis sorted set, answering REQs
+// that fit inside it without a database query
+//
+// `relay:recent_events` holds up to MAX_CACHED_EVENTS event JSONs scored by
+// created_at. The window key records its floor: every visible event created
+// after the floor is in the set. A REQ is answered from the set when its `since`
+// is above the floor, or when a filter without ids, authors or tag conditions
+// finds its `limit` above it. Anything else goes straight to the store: a
+// selective filter would rarely fill its limit from the newest events, so
+// scanning the set for it only adds a round trip. The floor only ever rises, as
+// the oldest events are trimmed off. A failed write, or a deletion too large to
+// apply piecemeal, drops the window, and the set is rebuilt from the store before
+// it is trusted again. Every removal and invalidation first bumps a generation
+// counter, and a rebuild that sees it change while loading leaves the window
+// closed, since the events it loaded may include one just removed.
+//
+// While Redis is absent or failing, REQs by id are answered from the
+// in-process event LRU instead, and any write skipped meanwhile leaves the
+// window to be rebuilt once Redis is back.
+
+use crate::{redis_conn, store::IndexKey, AppState};
+use nostr::{Event, Filter, JsonUtil};
+use redis::AsyncCommands;
+use serde_json::{json, Value};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+use tracing::{info, warn};
+
+const RECENT_EVENTS_KEY: &str = "relay:recent_events";
+/// Sorted set with the single member `floor`, scored by the floor's created_at
+const WINDOW_KEY: &str = "relay:recent_events:window";
+/// Held by whichever instance is rebuilding the set
+const WARM_LOCK_KEY: &str = "relay:recent_events:warming";
+/// Counts removals and invalidations, so a rebuild can tell it raced one
+const GENERATION_KEY: &str = "relay:recent_events:generation";
+
+const MAX_CACHED_EVENTS: usize = 1000;
+
+/// The window is rebuilt this often even if nothing went wrong, bounding the
+/// damage of any write that raced a rebuild
+const WINDOW_TTL_SECS: i64 = 600;
+const WARM_LOCK_TTL_SECS: u64 = 30;
+
+/// Deletions touching more events than this drop the whole window instead
+const MAX_TARGETED_REMOVALS: usize = 100;
+
+/// Hit and miss counters, served by the `cachestats` NIP-86 method
+#[derive(Default)]
+pub struct EventCache {
+    /// REQ filters answered, or not, from the Redis set
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// The same while Redis is down, for the in-process LRU
+    local_hits: AtomicU64,
+    local_misses: AtomicU64,
+    /// REQs that arrived while the window was missing or stale
+    cold: AtomicU64,
+    /// A write failed or was skipped, so the set can't be trusted until it's rebuilt
+    stale: AtomicBool,
+    warming: AtomicBool,
+}
+
+impl EventCache {
+    fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_local(&self, hit: bool) {
+        let counter = if hit { &self.local_hits } else { &self.local_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.stale.load(Ordering::Relaxed)
+    }
+}
+
+/// Add a newly stored (or un-banned) event, trimming the oldest past the cap
+pub async fn insert(state: &Arc<AppState>, event: &Event) {
+    let Some(mut conn) = redis_conn(state).await else {
+        skipped(state);
+        state.local.remember_events(std::slice::from_ref(event));
+        return;
+    };
+
+    let result: redis::RedisResult<()> = async {
+        conn.zadd::<_, _, _, ()>(RECENT_EVENTS_KEY, event.as_json(), event.created_at.as_u64()).await?;
+        trim(&mut conn).await
+    }
+    .await;
+    match result {
+        Ok(()) => state.breaker.success(),
+        Err(e) => {
+            state.breaker.failure(&format!("failed to cache event {}: {}", event.id, e));
+            invalidate(state, &mut conn).await;
+        }
+    }
+}
+
+/// Drop the oldest events past MAX_CACHED_EVENTS, raising the floor over them
+/// first. Only while a window exists: a rebuild in progress does its own trim.
+async fn trim(conn: &mut deadpool_redis::Connection) -> redis::RedisResult<()> {
+    redis::cmd("WATCH").arg(WINDOW_KEY).query_async::<_, ()>(conn).await?;
+    let floor: Option<f64> = conn.zscore(WINDOW_KEY, "floor").await?;
+    let count: usize = conn.zcard(RECENT_EVENTS_KEY).await?;
+    if floor.is_none() || count <= MAX_CACHED_EVENTS {
+        return redis::cmd("UNWATCH").query_async(conn).await;
+    }
+
+    let excess = (count - MAX_CACHED_EVENTS) as isize;
+    let newest_dropped: Vec<(String, f64)> = conn.zrange_withscores(RECENT_EVENTS_KEY, excess - 1, excess - 1).await?;
+    let Some(&(_, cut)) = newest_dropped.first() else {
+        return redis::cmd("UNWATCH").query_async(conn).await;
+    };
+
+    // Aborted if the window changed meanwhile; the next insert trims instead
+    let _: Option<()> = redis::pipe()
+        .atomic()
+        .cmd("ZADD").arg(WINDOW_KEY).arg("XX").arg("GT").arg(cut).arg("floor").ignore()
+        .cmd("ZREMRANGEBYSCORE").arg(RECENT_EVENTS_KEY).arg("-inf").arg(cut).ignore()
+        .query_async(conn)
+        .await?;
+    Ok(())
+}
+
+/// Drop an event, e.g. once it has been banned or hidden
+pub async fn remove(state: &Arc<AppState>, event: &Event) {
+    remove_keys(state, &[(event.created_at.as_u64(), event.id)]).await;
+}
+
+/// Drop deleted events by (created_at, id)
+pub async fn remove_keys(state: &Arc<AppState>, keys: &[IndexKey]) {
+    if keys.is_empty() {
+        return;
+    }
+    state.local.forget_events(keys);
+    let Some(mut conn) = redis_conn(state).await else {
+        skipped(state);
+        return;
+    };
+
+    if keys.len() > MAX_TARGETED_REMOVALS {
+        invalidate(state, &mut conn).await;
+        return;
+    }
+
+    let result: redis::RedisResult<()> = async {
+        // Before removing, so a rebuild that loaded these events sees the bump
+        conn.incr::<_, _, ()>(GENERATION_KEY, 1).await?;
+        for (created_at, id) in keys {
+            // Members are event JSON; find this one among those sharing its score
+            let members: Vec<String> = conn.zrangebyscore(RECENT_EVENTS_KEY, *created_at, *created_at).await?;
+            for member in members {
+                if Event::from_json(&member).is_ok_and(|cached| cached.id == *id) {
+                    conn.zrem::<_, _, ()>(RECENT_EVENTS_KEY, &member).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+    .await;
+    match result {
+        Ok(()) => state.breaker.success(),
+        Err(e) => {
+            state.breaker.failure(&format!("failed to uncache deleted events: {}", e));
+            invalidate(state, &mut conn).await;
+        }
+    }
+}
+
+/// A write couldn't reach Redis, so the set may be missing it
+fn skipped(state: &Arc<AppState>) {
+    if state.redis.is_some() {
+        state.cache.stale.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Stop serving from the set until it's rebuilt, including by a rebuild already running
+async fn invalidate(state: &Arc<AppState>, conn: &mut deadpool_redis::Connection) {
+    let result = redis::pipe()
+        .atomic()
+        .incr(GENERATION_KEY, 1).ignore()
+        .del(WINDOW_KEY).ignore()
+        .query_async::<_, ()>(conn)
+        .await;
+    if state.breaker.record(result).is_err() {
+        state.cache.stale.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Answer a filter from the set, newest first, or `None` if the set can't be
+/// sure it has every match
+pub async fn query(state: &Arc<AppState>, filter: &Filter, limit: usize) -> Option<Vec<Event>> {
+    // The store ignores NIP-50 search, so the cache mustn't apply it either
+    if filter.search.is_some() {
+        return None;
+    }
+    let Some(mut conn) = redis_conn(state).await else {
+        let found = state.local.query_ids(filter, limit);
+        state.cache.record_local(found.is_some());
+        return found;
+    };
+    if state.cache.is_stale() {
+        state.cache.cold.fetch_add(1, Ordering::Relaxed);
+        spawn_warm(state);
+        return None;
+    }
+
+    let Ok(floor) = state.breaker.record(conn.zscore::<_, _, Option<f64>>(WINDOW_KEY, "floor").await) else {
+        return None;
+    };
+    let Some(floor) = floor else {
+        state.cache.cold.fetch_add(1, Ordering::Relaxed);
+        spawn_warm(state);
+        return None;
+    };
+    let Some(coverage) = coverage(filter, floor as i64) else {
+        state.cache.record(false);
+        return None;
+    };
+    let max = filter.until.map_or("+inf".to_string(), |until| until.as_i64().to_string());
+    let Ok(members) = state.breaker.record(conn.zrevrangebyscore::<_, _, _, Vec<String>>(RECENT_EVENTS_KEY, max, coverage.min).await) else {
+        return None;
+    };
+
+    let found = answer(&members, filter, limit, coverage.complete);
+    state.cache.record(found.is_some());
+    found
+}
+
+/// The part of the set a filter may be answered from
+#[derive(Debug, PartialEq)]
+struct Coverage {
+    /// Lowest score to read, as a ZRANGEBYSCORE bound
+    min: String,
+    /// Whether the set holds every match, rather than only the newest ones
+    complete: bool,
+}
+
+/// How the window with this floor covers a filter, or `None` if it's worth
+/// going straight to the store. A floor of -1 means the set holds every visible event.
+fn coverage(filter: &Filter, floor: i64) -> Option<Coverage> {
+    let since = filter.since.map_or(0, |since| since.as_i64());
+    if since > floor {
+        Some(Coverage { min: since.to_string(), complete: true })
+    } else if unselective(filter) {
+        Some(Coverage { min: format!("({}", floor), complete: false })
+    } else {
+        None
+    }
+}
+
+/// The newest `limit` matches among the members read, unless the window may be
+/// missing older ones the REQ would return
+fn answer(members: &[String], filter: &Filter, limit: usize, complete: bool) -> Option<Vec<Event>> {
+    let events: Vec<Event> = members
+        .iter()
+        .filter_map(|member| Event::from_json(member).ok())
+        .filter(|event| !event.is_expired() && filter.match_event(event))
+        .take(limit)
+        .collect();
+    (complete || events.len() >= limit).then_some(events)
+}
+
+/// Whether a filter matches broadly enough for the newest events to hold its limit
+fn unselective(filter: &Filter) -> bool {
+    filter.ids.as_ref().is_none_or(|ids| ids.is_empty())
+        && filter.authors.as_ref().is_none_or(|authors| authors.is_empty())
+        && filter.generic_tags.is_empty()
+}
+
+/// Keep events just read from the store for by-id REQs, while Redis is down
+pub fn remember(state: &Arc<AppState>, events: &[Event]) {
+    if state.redis.is_none() || state.breaker.is_open() {
+        state.local.remember_events(events);
+    }
+}
+
+/// Rebuild the set from the store in the background, unless this or another
+/// instance already is
+pub fn spawn_warm(state: &Arc<AppState>) {
+    if state.redis.is_none() || state.cache.warming.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let state = state.clone();
+    tokio::spawn(async move {
+        if let Err(e) = warm(&state).await {
+            warn!("Failed to warm the recent-events cache: {}", e);
+        }
+        state.cache.warming.store(false, Ordering::Relaxed);
+    });
+}
+
+async fn warm(state: &Arc<AppState>) -> Result<(), String> {
+    let Some(mut conn) = redis_conn(state).await else {
+        return Err("Redis is unavailable".to_string());
+    };
+
+    let locked: redis::RedisResult<Option<String>> = redis::cmd("SET")
+        .arg(WARM_LOCK_KEY).arg(1).arg("NX").arg("EX").arg(WARM_LOCK_TTL_SECS)
+        .query_async(&mut conn)
+        .await;
+    let locked = state.breaker.record(locked).map_err(|e| e.to_string())?;
+    if locked.is_none() {
+        return Ok(());
+    }
+
+    let result = rebuild(state, &mut conn).await;
+    let _: redis::RedisResult<()> = conn.del(WARM_LOCK_KEY).await;
+    let count = result?;
+    info!("Warmed the recent-events cache with {} events", count);
+    Ok(())
+}
+
+/// Count a Redis error against the breaker and describe it
+fn failed(state: &Arc<AppState>, e: redis::RedisError) -> String {
+    state.breaker.failure(&e);
+    e.to_string()
+}
+
+/// Reload the newest events and open a window over them
+async fn rebuild(state: &Arc<AppState>, conn: &mut deadpool_redis::Connection) -> Result<usize, String> {
+    // Inserts stop trimming as soon as the window is gone
+    conn.del::<_, ()>(&[WINDOW_KEY, RECENT_EVENTS_KEY]).await.map_err(|e| failed(state, e))?;
+    state.cache.stale.store(false, Ordering::Relaxed);
+    let generation: Option<u64> = conn.get(GENERATION_KEY).await.map_err(|e| failed(state, e))?;
+
+    let events = state.store
+        .query(&Filter::new(), MAX_CACHED_EVENTS)
+        .await
+        .map_err(|e| format!("could not load recent events: {}", e))?;
+    let items: Vec<(u64, String)> = events.iter().map(|event| (event.created_at.as_u64(), event.as_json())).collect();
+    if !items.is_empty() {
+        conn.zadd_multiple::<_, _, _, ()>(RECENT_EVENTS_KEY, &items).await.map_err(|e| failed(state, e))?;
+    }
+
+    // Complete back to the oldest event loaded, or to the beginning if the store
+    // has fewer than a full set; events inserted meanwhile may push it higher
+    let mut floor = match events.last() {
+        Some(oldest) if events.len() >= MAX_CACHED_EVENTS => oldest.created_at.as_i64(),
+        _ => -1,
+    };
+    let count: usize = conn.zcard(RECENT_EVENTS_KEY).await.map_err(|e| failed(state, e))?;
+    if count > MAX_CACHED_EVENTS {
+        let excess = (count - MAX_CACHED_EVENTS) as isize;
+        let newest_dropped: Vec<(String, f64)> = conn
+            .zrange_withscores(RECENT_EVENTS_KEY, excess - 1, excess - 1)
+            .await
+            .map_err(|e| failed(state, e))?;
+        if let Some(&(_, cut)) = newest_dropped.first() {
+            conn.zrembyscore::<_, _, _, ()>(RECENT_EVENTS_KEY, "-inf", cut).await.map_err(|e| failed(state, e))?;
+            floor = floor.max(cut as i64);
+        }
+    }
+
+    // Events removed since loading began may have been added back above; leave
+    // the window closed for the next REQ to rebuild it
+    redis::cmd("WATCH").arg(GENERATION_KEY).query_async::<_, ()>(conn).await.map_err(|e| failed(state, e))?;
+    let current: Option<u64> = conn.get(GENERATION_KEY).await.map_err(|e| failed(state, e))?;
+    if current != generation {
+        let _: redis::RedisResult<()> = redis::cmd("UNWATCH").query_async(conn).await;
+        return Err("events were removed during the rebuild".to_string());
+    }
+    let opened: Option<()> = redis::pipe()
+        .atomic()
+        .zadd(WINDOW_KEY, "floor", floor).ignore()
+        .expire(WINDOW_KEY, WINDOW_TTL_SECS).ignore()
+        .query_async(conn)
+        .await
+        .map_err(|e| failed(state, e))?;
+    if opened.is_none() {
+        return Err("events were removed during the rebuild".to_string());
+    }
+    Ok(count.min(MAX_CACHED_EVENTS))
+}
+
+/// `cachestats` NIP-86 method: REQ hit rate and the window currently held
+pub async fn stats(state: &Arc<AppState>) -> Value {
+    let hits = state.cache.hits.load(Ordering::Relaxed);
+    let misses = state.cache.misses.load(Ordering::Relaxed);
+    let cold = state.cache.cold.load(Ordering::Relaxed);
+    let total = hits + misses + cold;
+
+    let (mut cached, mut floor) = (None, None);
+    if let Some(mut conn) = redis_conn(state).await {
+        cached = state.breaker.record(conn.zcard::<_, usize>(RECENT_EVENTS_KEY).await).ok();
+        floor = state.breaker
+            .record(conn.zscore::<_, _, Option<f64>>(WINDOW_KEY, "floor").await)
+            .ok()
+            .flatten()
+            .map(|f| f as i64);
+    }
+
+    json!({
+        "enabled": state.redis.is_some(),
+        "redis_available": state.redis.is_some() && !state.breaker.is_open(),
+        "hits": hits,
+        "misses": misses,
+        "local_hits": state.cache.local_hits.load(Ordering::Relaxed),
+        "local_misses": state.cache.local_misses.load(Ordering::Relaxed),
+        "cold": cold,
+        "hit_rate": if total == 0 { 0.0 } else { hits as f64 / total as f64 },
+        "cached_events": cached,
+        "floor": floor,
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::{Alphabet, EventBuilder, EventId, Keys, Kind, SingleLetterTag, Tag, Timestamp};
+
+    fn members(keys: &Keys, created_ats: &[u64]) -> Vec<String> {
+        created_ats
+            .iter()
+            .map(|created_at| {
+                EventBuilder::new(Kind::TextNote, "", [])
+                    .custom_created_at(Timestamp::from(*created_at))
+                    .to_event(keys)
+                    .unwrap()
+                    .as_json()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_since_above_the_floor_is_answered_in_full() {
+        let filter = Filter::new().author(Keys::generate().public_key()).since(Timestamp::from(101));
+        assert_eq!(coverage(&filter, 100), Some(Coverage { min: "101".to_string(), complete: true }));
+        // Selective filters reaching below the floor go to the store
+        assert_eq!(coverage(&filter.clone().since(Timestamp::from(100)), 100), None);
+        assert_eq!(coverage(&Filter::new().author(Keys::generate().public_key()), 100), None);
+        // A floor of -1 covers everything
+        assert!(coverage(&Filter::new().id(EventId::all_zeros()), -1).is_some_and(|c| c.complete));
+    }
+
+    #[test]
+    fn unselective_filters_only_read_above_the_floor() {
+        assert_eq!(coverage(&Filter::new(), 100), Some(Coverage { min: "(100".to_string(), complete: false }));
+        let by_kind = Filter::new().kind(Kind::TextNote).since(Timestamp::from(50));
+        assert_eq!(coverage(&by_kind, 100), Some(Coverage { min: "(100".to_string(), complete: false }));
+        let by_tag = Filter::new().custom_tag(SingleLetterTag::lowercase(Alphabet::T), ["nostr"]);
+        assert_eq!(coverage(&by_tag, 100), None);
+    }
+
+    #[test]
+    fn incomplete_windows_must_fill_the_limit() {
+        let keys = Keys::generate();
+        let cached = members(&keys, &[300, 200, 150]);
+
+        let newest = answer(&cached, &Filter::new(), 2, false).expect("the newest two are cached");
+        let created: Vec<u64> = newest.iter().map(|e| e.created_at.as_u64()).collect();
+        assert_eq!(created, vec![300, 200]);
+        assert!(answer(&cached, &Filter::new(), 5, false).is_none());
+        // Matches are counted after filtering
+        assert!(answer(&cached, &Filter::new().author(Keys::generate().public_key()), 1, false).is_none());
+
+        // A complete window answers even with fewer than the limit, or none
+        assert_eq!(answer(&cached, &Filter::new(), 5, true).map(|events| events.len()), Some(3));
+        assert_eq!(answer(&cached, &Filter::new().kind(Kind::Metadata), 5, true).map(|events| events.len()), Some(0));
+    }
+
+    #[test]
+    fn expired_and_malformed_members_are_skipped() {
+        let keys = Keys::generate();
+        let expired = EventBuilder::new(Kind::TextNote, "", [Tag::parse(vec!["expiration", "1"]).unwrap()])
+            .to_event(&keys)
+            .unwrap()
+            .as_json();
+        let mut cached = vec![expired, "not an event".to_string()];
+        cached.extend(members(&keys, &[100]));
+
+        let found = answer(&cached, &Filter::new(), 5, true).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].created_at.as_u64(), 100);
+    }
+}
@@ -0,0 +1,271 @@
+// Command-line subcommands. Running `relay-rs` with no subcommand starts the relay.
+
+use crate::{
+    audit, ban_pubkey, config::SyncPeer, identity, invalidate_whitelist_cache, parse_pubkey, process_event,
+    store::{AuditEntry, WhitelistStatus},
+    sync, Accepted, AppState, Origin, UserKey, MISSING_AUTHOR,
+};
+use clap::{Parser, Subcommand};
+use nostr::{Event, Filter, JsonUtil, PublicKey, ToBech32};
+use std::{collections::BTreeMap, sync::Arc};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+#[derive(Parser)]
+#[command(name = "relay-rs", version, about = "Relay Pleb One (Rust Edition)")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Write stored events to stdout as JSON lines, oldest first
+    Export {
+        /// Nostr filter JSON selecting which events to export
+        #[arg(long, default_value = "{}")]
+        filter: String,
+    },
+    /// Load events from a JSON lines file ("-" for stdin)
+    Import {
+        path: String,
+        /// Accept events from authors who aren't whitelisted, adding any the
+        /// database doesn't know as PENDING users
+        #[arg(long)]
+        skip_whitelist: bool,
+    },
+    /// Reconcile with another relay over NIP-77 once, fetching the events it has and we don't
+    Sync {
+        /// Websocket URL of the peer relay
+        url: String,
+        /// Nostr filter JSON selecting which events to reconcile
+        #[arg(long, default_value = "{}")]
+        filter: String,
+        /// Also send the peer the events only we have
+        #[arg(long)]
+        upload: bool,
+    },
+    /// Manage the whitelist directly in the database
+    Admin {
+        #[command(subcommand)]
+        action: AdminAction,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum AdminAction {
+    /// Whitelist a user (creating them if needed)
+    Allow { user: String },
+    /// Revoke a user's whitelist access (creating them if needed)
+    Ban { user: String },
+    /// List users with a whitelist status
+    List {
+        /// PENDING, ACTIVE, PAUSED, REVOKED or VANISHED
+        #[arg(default_value = "ACTIVE")]
+        status: String,
+    },
+    /// Give a user admin rights
+    Promote { user: String },
+    /// Show the newest audit log entries as JSON lines
+    Audit {
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+    },
+}
+
+pub async fn run(command: Command, state: Arc<AppState>) -> Result<(), String> {
+    match command {
+        Command::Export { filter } => export(&filter, state).await,
+        Command::Import { path, skip_whitelist } => import(&path, skip_whitelist, state).await,
+        Command::Sync { url, filter, upload } => sync_once(url, &filter, upload, state).await,
+        Command::Admin { action } => admin(action, state).await,
+    }
+}
+
+async fn export(filter_json: &str, state: Arc<AppState>) -> Result<(), String> {
+    let filter: Filter = serde_json::from_str(filter_json).map_err(|e| format!("invalid filter: {}", e))?;
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Event>(1000);
+    let store = state.store.clone();
+    let query = tokio::spawn(async move { store.stream(&filter, tx).await });
+
+    let mut stdout = tokio::io::stdout();
+    while let Some(event) = rx.recv().await {
+        let mut line = event.as_json();
+        line.push('\n');
+        stdout.write_all(line.as_bytes()).await.map_err(|e| e.to_string())?;
+    }
+    stdout.flush().await.map_err(|e| e.to_string())?;
+
+    let exported = query
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("query failed: {}", e))?;
+    eprintln!("exported {} events", exported);
+    Ok(())
+}
+
+async fn import(path: &str, skip_whitelist: bool, state: Arc<AppState>) -> Result<(), String> {
+    let reader: Box<dyn tokio::io::AsyncRead + Unpin + Send> = if path == "-" {
+        Box::new(tokio::io::stdin())
+    } else {
+        Box::new(tokio::fs::File::open(path).await.map_err(|e| format!("{}: {}", path, e))?)
+    };
+    let mut lines = BufReader::new(reader).lines();
+
+    let mut stored = 0u64;
+    let mut duplicates = 0u64;
+    let mut rejected: BTreeMap<String, u64> = BTreeMap::new();
+
+    while let Some(line) = lines.next_line().await.map_err(|e| e.to_string())? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let event = match Event::from_json(line) {
+            Ok(event) => event,
+            Err(_) => {
+                *rejected.entry("invalid: malformed event JSON".to_string()).or_default() += 1;
+                continue;
+            }
+        };
+
+        // Postgres only stores events whose author has a `users` row
+        let retry = skip_whitelist.then(|| event.clone());
+        let outcome = match (process_event(event, &state, Origin::Import { skip_whitelist }).await, retry) {
+            (Err(reason), Some(event)) if reason == MISSING_AUTHOR => match add_author(&state, &event.pubkey).await {
+                Ok(()) => process_event(event, &state, Origin::Import { skip_whitelist }).await,
+                Err(reason) => Err(reason),
+            },
+            (outcome, _) => outcome,
+        };
+        match outcome {
+            Ok(Accepted::Stored) => stored += 1,
+            Ok(Accepted::Duplicate) => duplicates += 1,
+            Err(reason) => *rejected.entry(reason).or_default() += 1,
+        }
+    }
+
+    eprintln!("imported {} events ({} duplicates)", stored, duplicates);
+    for (reason, count) in &rejected {
+        eprintln!("rejected {:>8}  {}", count, reason);
+    }
+    Ok(())
+}
+
+/// Give an imported event's author a PENDING `users` row: enough to store their
+/// events without whitelisting them
+async fn add_author(state: &Arc<AppState>, pubkey: &PublicKey) -> Result<(), String> {
+    let hex = pubkey.to_hex();
+    let npub = pubkey.to_bech32().map_err(|_| "error: could not encode the author's npub".to_string())?;
+    match state.store.ensure_user(&npub, &hex, WhitelistStatus::Pending, "added by relay-rs import").await {
+        Ok(created) => {
+            if created {
+                invalidate_whitelist_cache(state, &hex).await;
+            }
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("could not add author {}: {}", npub, e);
+            Err("error: could not add the author to the users table".to_string())
+        }
+    }
+}
+
+async fn sync_once(url: String, filter_json: &str, upload: bool, state: Arc<AppState>) -> Result<(), String> {
+    let filter: Filter = serde_json::from_str(filter_json).map_err(|e| format!("invalid filter: {}", e))?;
+    let peer = SyncPeer { url, filter, interval_secs: 0, upload };
+
+    let report = sync::sync_peer(&state, &peer).await?;
+    eprintln!("{} events only here, {} only on {}", report.have, report.need, peer.url);
+    eprintln!("fetched {} events ({} duplicates)", report.stored, report.duplicates);
+    for (reason, count) in &report.rejected {
+        eprintln!("rejected {:>8}  {}", count, reason);
+    }
+    if upload {
+        eprintln!("uploaded {} events ({} rejected by the peer)", report.uploaded, report.upload_rejected);
+    }
+    Ok(())
+}
+
+fn user_arg(user: &str) -> Result<UserKey, String> {
+    parse_pubkey(user).ok_or_else(|| format!("not a valid npub or hex public key: {}", user))
+}
+
+/// Run an admin command, recording changes in the audit log
+async fn admin(action: AdminAction, state: Arc<AppState>) -> Result<(), String> {
+    let (method, params) = match &action {
+        AdminAction::Allow { user } => ("allow", serde_json::json!([user])),
+        AdminAction::Ban { user } => ("ban", serde_json::json!([user])),
+        AdminAction::Promote { user } => ("promote", serde_json::json!([user])),
+        AdminAction::List { .. } | AdminAction::Audit { .. } => return run_admin(action, &state).await,
+    };
+
+    // Published audit events are signed by the relay key, which needs a users row
+    if state.config.publish_audit {
+        identity::register(&state).await;
+    }
+
+    let outcome = run_admin(action, &state).await;
+    audit::record(&state, AuditEntry {
+        admin_pubkey: None,
+        source: "cli".to_string(),
+        method: method.to_string(),
+        params,
+        result: match &outcome {
+            Ok(()) => serde_json::json!(true),
+            Err(e) => serde_json::json!(e),
+        },
+        success: outcome.is_ok(),
+    })
+    .await;
+
+    outcome
+}
+
+async fn run_admin(action: AdminAction, state: &Arc<AppState>) -> Result<(), String> {
+    match action {
+        AdminAction::Allow { user } => {
+            let user = user_arg(&user)?;
+            state.store.allow_user(&user.npub, &user.hex, None).await.map_err(|e| e.to_string())?;
+            invalidate_whitelist_cache(state, &user.hex).await;
+            eprintln!("allowed {}", user.npub);
+        }
+        AdminAction::Ban { user } => {
+            let user = user_arg(&user)?;
+            ban_pubkey(state, &user, None).await.map_err(|e| e.to_string())?;
+            eprintln!("banned {}", user.npub);
+        }
+        AdminAction::List { status } => {
+            let status = WhitelistStatus::parse(&status.to_uppercase())
+                .ok_or_else(|| format!("unknown whitelist status: {}", status))?;
+            let users = state.store.list_users(status).await.map_err(|e| e.to_string())?;
+            for entry in users {
+                let key = match parse_pubkey(&entry.value) {
+                    Some(user) => format!("{}  {}", user.hex, user.npub),
+                    None => entry.value,
+                };
+                match entry.reason {
+                    Some(reason) => println!("{}  {}", key, reason),
+                    None => println!("{}", key),
+                }
+            }
+        }
+        AdminAction::Promote { user } => {
+            let user = user_arg(&user)?;
+            let updated = state.store.set_admin(&user.hex, true).await.map_err(|e| e.to_string())?;
+            if updated == 0 {
+                return Err(format!("no such user: {}", user.npub));
+            }
+            invalidate_whitelist_cache(state, &user.hex).await;
+            eprintln!("promoted {} to admin", user.npub);
+        }
+        AdminAction::Audit { limit } => {
+            let records = state.store.audit_log(limit.max(1), None).await.map_err(|e| e.to_string())?;
+            for record in &records {
+                println!("{}", audit::to_json(record));
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,157 @@
+// Relay settings, read once from the environment at startup
+
+use nostr::Filter;
+use serde::Deserialize;
+
+/// NIP-42 AUTH events must be created within this many seconds of now
+pub const AUTH_MAX_SKEW_SECS: i64 = 600;
+
+/// NIP-98 HTTP auth events must be created within this many seconds of now
+pub const HTTP_AUTH_MAX_SKEW_SECS: i64 = 60;
+
+#[derive(Clone, Debug)]
+pub struct RelayConfig {
+    /// Public websocket URLs of this relay (`RELAY_URLS`, comma-separated).
+    /// The first one is the canonical URL used in relay-authored events.
+    pub relay_urls: Vec<String>,
+    /// Private relay mode (`RELAY_AUTH_REQUIRED`): reads need a NIP-42 AUTH from a
+    /// whitelisted member, not just writes
+    pub auth_required: bool,
+    /// Write auth policy (`RELAY_WRITE_AUTH_REQUIRED`): an EVENT is only accepted
+    /// from a socket authenticated as its author, the author's NIP-26 delegator,
+    /// or an admin
+    pub write_auth_required: bool,
+    /// Take client IPs from `X-Forwarded-For` (`RELAY_TRUST_PROXY`); only safe
+    /// when the relay is reachable solely through a reverse proxy
+    pub trust_proxy: bool,
+    /// Also publish audit log entries as relay-signed events (`RELAY_PUBLISH_AUDIT`)
+    pub publish_audit: bool,
+    /// Hide an event pending review once this many distinct pubkeys have open
+    /// NIP-56 reports against it (`RELAY_REPORT_HIDE_THRESHOLD`, 0 disables)
+    pub report_hide_threshold: u64,
+    /// Largest set of events a NIP-77 `NEG-OPEN` may reconcile
+    /// (`RELAY_NEGENTROPY_MAX_RECORDS`); bigger queries get `NEG-ERR blocked:`
+    pub negentropy_max_records: usize,
+    /// Relays to reconcile with over NIP-77 (`RELAY_SYNC_PEERS`, a JSON array)
+    pub sync_peers: Vec<SyncPeer>,
+    /// Relays to hold REQ subscriptions open on (`RELAY_MIRROR_SOURCES`, a JSON array)
+    pub mirror_sources: Vec<MirrorSource>,
+    /// Cache and fan-out server (`REDIS_URL`)
+    pub redis_url: String,
+    /// Share accepted events and moderation changes with other instances through
+    /// Redis pub/sub (`RELAY_REDIS_FANOUT`), for replicas behind one load balancer
+    pub redis_fanout: bool,
+    /// Where the relay's secret key is kept (`RELAY_KEY_FILE`), generated on first
+    /// start unless `RELAY_SECRET_KEY` supplies one
+    pub key_file: String,
+}
+
+/// A peer relay the sync scheduler reconciles with
+#[derive(Clone, Debug, Deserialize)]
+pub struct SyncPeer {
+    pub url: String,
+    /// Which events to reconcile; everything by default
+    #[serde(default)]
+    pub filter: Filter,
+    /// Seconds between syncs
+    #[serde(default = "default_sync_interval")]
+    pub interval_secs: u64,
+    /// Also send the peer the events only we have
+    #[serde(default)]
+    pub upload: bool,
+}
+
+fn default_sync_interval() -> u64 {
+    3600
+}
+
+/// An upstream relay the mirror streams events from
+#[derive(Clone, Debug, Deserialize)]
+pub struct MirrorSource {
+    pub url: String,
+    /// What to subscribe to; everything by default
+    #[serde(default)]
+    pub filter: Filter,
+    /// Only ask for events authored by the relay's active members
+    #[serde(default)]
+    pub members: bool,
+}
+
+impl RelayConfig {
+    pub fn from_env() -> Self {
+        let mut relay_urls: Vec<String> = std::env::var("RELAY_URLS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|u| u.trim().to_string())
+            .filter(|u| !u.is_empty())
+            .collect();
+        if relay_urls.is_empty() {
+            relay_urls.push("wss://relay.pleb.one".to_string());
+        }
+
+        Self {
+            relay_urls,
+            auth_required: env_flag("RELAY_AUTH_REQUIRED"),
+            write_auth_required: env_flag("RELAY_WRITE_AUTH_REQUIRED"),
+            trust_proxy: env_flag("RELAY_TRUST_PROXY"),
+            publish_audit: env_flag("RELAY_PUBLISH_AUDIT"),
+            report_hide_threshold: env_number("RELAY_REPORT_HIDE_THRESHOLD", 3),
+            negentropy_max_records: env_number("RELAY_NEGENTROPY_MAX_RECORDS", 500_000) as usize,
+            sync_peers: std::env::var("RELAY_SYNC_PEERS")
+                .ok()
+                .filter(|v| !v.trim().is_empty())
+                .map(|v| serde_json::from_str(&v).expect("RELAY_SYNC_PEERS must be a JSON array of peers"))
+                .unwrap_or_default(),
+            mirror_sources: std::env::var("RELAY_MIRROR_SOURCES")
+                .ok()
+                .filter(|v| !v.trim().is_empty())
+                .map(|v| serde_json::from_str(&v).expect("RELAY_MIRROR_SOURCES must be a JSON array of sources"))
+                .unwrap_or_default(),
+            redis_url: std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://redis:6379".to_string()),
+            redis_fanout: env_flag("RELAY_REDIS_FANOUT"),
+            key_file: std::env::var("RELAY_KEY_FILE").unwrap_or_else(|_| "relay.key".to_string()),
+        }
+    }
+
+    /// Whether a NIP-42 `relay` tag points at this relay
+    pub fn is_own_url(&self, url: &str) -> bool {
+        let url = normalize_url(url);
+        self.relay_urls.iter().any(|u| normalize_url(u) == url)
+    }
+
+    /// Whether a NIP-98 `u` tag is the HTTP(S) form of one of our URLs
+    pub fn is_own_http_url(&self, url: &str) -> bool {
+        let url = normalize_url(url);
+        self.relay_urls.iter().any(|u| {
+            let u = normalize_url(u);
+            let http = if let Some(rest) = u.strip_prefix("wss://") {
+                format!("https://{}", rest)
+            } else if let Some(rest) = u.strip_prefix("ws://") {
+                format!("http://{}", rest)
+            } else {
+                u
+            };
+            http == url
+        })
+    }
+}
+
+/// Boolean environment variable; unset means false
+fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .map(|v| matches!(v.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+/// Numeric environment variable; unset or unparseable means `default`
+fn env_number(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+/// Compare URLs loosely: case-insensitive and ignoring a trailing slash
+fn normalize_url(url: &str) -> String {
+    url.trim().trim_end_matches('/').to_lowercase()
+}
@@ -0,0 +1,301 @@
+// Fan-out between relay instances through Redis pub/sub
+//
+// With `RELAY_REDIS_FANOUT` set, each instance publishes what happened on it to
+// one channel and replays what the others published: accepted events go to local
+// subscribers, moderation changes to local sockets, and negentropy index changes
+// to the local index. Events are deduplicated by id, so one that reaches a local
+// subscriber by both routes is only delivered once. Index changes can't be
+// replayed, so an instance that may have missed some reloads its index instead.
+
+use crate::{redis_conn, store::IndexChange, AppState, Control};
+use futures::stream::StreamExt;
+use nostr::{Event, EventId};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+const CHANNEL: &str = "relay:fanout";
+
+/// Recently delivered event ids remembered for deduplication
+const SEEN_CAPACITY: usize = 10_000;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Payload {
+    Event { event: Event },
+    Control { control: Control },
+    /// A user's cached standing is stale
+    Whitelist { pubkey: String },
+    Index { change: IndexChange },
+    /// The sender lost some of its index changes, so every other index may be stale
+    Resync,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    /// The publishing instance, which has already applied the payload itself
+    origin: String,
+    #[serde(flatten)]
+    payload: Payload,
+}
+
+pub struct Fanout {
+    instance_id: String,
+    seen: Mutex<Seen>,
+}
+
+impl Default for Fanout {
+    fn default() -> Self {
+        Self { instance_id: nanoid::nanoid!(), seen: Mutex::default() }
+    }
+}
+
+impl Fanout {
+    /// Whether `id` hasn't been delivered to local subscribers yet, marking it delivered
+    fn first_sighting(&self, id: EventId) -> bool {
+        self.seen.lock().unwrap_or_else(PoisonError::into_inner).insert(id)
+    }
+}
+
+/// Bounded set of recent event ids, forgetting the oldest first
+#[derive(Default)]
+struct Seen {
+    ids: HashSet<EventId>,
+    order: VecDeque<EventId>,
+}
+
+impl Seen {
+    fn insert(&mut self, id: EventId) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Deliver a stored event to subscribers on this instance and every other one
+pub async fn broadcast(state: &Arc<AppState>, event: Event) {
+    if !state.fanout.first_sighting(event.id) {
+        return;
+    }
+    publish(state, Payload::Event { event: event.clone() }).await;
+    let _ = state.tx.send(event);
+}
+
+/// Act on a moderation change here and on every other instance
+pub async fn control(state: &Arc<AppState>, control: Control) {
+    publish(state, Payload::Control { control: control.clone() }).await;
+    let _ = state.control.send(control);
+}
+
+/// Tell other instances a user's standing changed
+pub async fn whitelist_changed(state: &Arc<AppState>, pubkey: &str) {
+    publish(state, Payload::Whitelist { pubkey: pubkey.to_string() }).await;
+}
+
+async fn publish(state: &Arc<AppState>, payload: Payload) {
+    if !state.config.redis_fanout || state.redis.is_none() {
+        return;
+    }
+
+    let envelope = Envelope { origin: state.fanout.instance_id.clone(), payload };
+    let message = match serde_json::to_string(&envelope) {
+        Ok(message) => message,
+        Err(e) => {
+            warn!("Failed to encode fan-out message: {}", e);
+            return;
+        }
+    };
+    let Some(mut conn) = redis_conn(state).await else { return };
+    match conn.publish::<_, _, ()>(CHANNEL, message).await {
+        Ok(()) => state.breaker.success(),
+        Err(e) => state.breaker.failure(&format!("failed to publish fan-out message: {}", e)),
+    }
+}
+
+/// Start relaying local index changes out and other instances' messages in
+pub fn spawn(state: &Arc<AppState>) {
+    if !state.config.redis_fanout || state.redis.is_none() {
+        return;
+    }
+
+    if let Some(mut changes) = state.store.index_changes() {
+        let state = state.clone();
+        tokio::spawn(async move {
+            loop {
+                match changes.recv().await {
+                    Ok(change) => publish(&state, Payload::Index { change }).await,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Fan-out fell behind; {} negentropy index changes not shared, asking other instances to reload", missed);
+                        publish(&state, Payload::Resync).await;
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    let state = state.clone();
+    tokio::spawn(async move {
+        let mut backoff = MIN_BACKOFF;
+        let mut resubscribing = false;
+        loop {
+            let reason = match subscribe(&state, &mut backoff, &mut resubscribing).await {
+                Ok(()) => "connection closed".to_string(),
+                Err(e) => e.to_string(),
+            };
+            warn!("Fan-out subscription lost: {}; retrying in {}s", reason, backoff.as_secs());
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    });
+}
+
+/// Replay other instances' messages until the connection drops
+async fn subscribe(state: &Arc<AppState>, backoff: &mut Duration, resubscribing: &mut bool) -> redis::RedisResult<()> {
+    let client = redis::Client::open(state.config.redis_url.as_str())?;
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(CHANNEL).await?;
+    info!("Subscribed to fan-out channel {}", CHANNEL);
+    *backoff = MIN_BACKOFF;
+    // Index changes published while we weren't subscribed are gone
+    if std::mem::replace(resubscribing, true) {
+        reload_index(state);
+    }
+
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let Ok(payload) = message.get_payload::<String>() else { continue };
+        handle_message(state, &payload);
+    }
+    Ok(())
+}
+
+/// Act on a message from the channel, unless this instance sent it
+fn handle_message(state: &Arc<AppState>, message: &str) {
+    match serde_json::from_str::<Envelope>(message) {
+        Ok(envelope) if envelope.origin != state.fanout.instance_id => receive(state, envelope.payload),
+        Ok(_) => {}
+        Err(e) => warn!("Ignoring malformed fan-out message: {}", e),
+    }
+}
+
+fn receive(state: &Arc<AppState>, payload: Payload) {
+    match payload {
+        Payload::Event { event } => {
+            if state.fanout.first_sighting(event.id) {
+                let _ = state.tx.send(event);
+            }
+        }
+        Payload::Control { control } => {
+            let _ = state.control.send(control);
+        }
+        // The shared Redis entry is already cleared; only the local fallback can be stale
+        Payload::Whitelist { pubkey } => state.local.forget_standing(&pubkey),
+        Payload::Index { change } => {
+            if let IndexChange::Removed { keys } = &change {
+                state.local.forget_events(keys);
+            }
+            state.store.apply_index_change(&change);
+        }
+        Payload::Resync => reload_index(state),
+    }
+}
+
+fn reload_index(state: &Arc<AppState>) {
+    let state = state.clone();
+    tokio::spawn(async move { state.store.reload_index().await });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::state, Standing};
+    use nostr::{EventBuilder, Keys};
+
+    fn note() -> Event {
+        EventBuilder::text_note("fan-out", []).to_event(&Keys::generate()).unwrap()
+    }
+
+    fn message(origin: &str, payload: Payload) -> String {
+        serde_json::to_string(&Envelope { origin: origin.to_string(), payload }).unwrap()
+    }
+
+    fn id(n: usize) -> EventId {
+        let mut bytes = [0u8; 32];
+        bytes[..8].copy_from_slice(&(n as u64).to_be_bytes());
+        EventId::from_slice(&bytes).unwrap()
+    }
+
+    #[test]
+    fn seen_forgets_the_oldest_ids_past_its_capacity() {
+        let mut seen = Seen::default();
+        let first = id(0);
+        assert!(seen.insert(first));
+        assert!(!seen.insert(first));
+
+        for n in 1..=SEEN_CAPACITY {
+            assert!(seen.insert(id(n)));
+        }
+        assert_eq!(seen.ids.len(), SEEN_CAPACITY);
+        assert_eq!(seen.order.len(), SEEN_CAPACITY);
+        assert!(seen.insert(first), "the oldest id should have been forgotten");
+    }
+
+    #[tokio::test]
+    async fn events_reach_local_subscribers_once_by_either_route() {
+        let state = state().await;
+        let mut rx = state.tx.subscribe();
+        let event = note();
+
+        broadcast(&state, event.clone()).await;
+        broadcast(&state, event.clone()).await;
+        handle_message(&state, &message("other-instance", Payload::Event { event: event.clone() }));
+        assert_eq!(rx.try_recv().unwrap().id, event.id);
+        assert!(rx.try_recv().is_err());
+
+        // And the other way round: first over the channel, then stored here
+        let remote = note();
+        handle_message(&state, &message("other-instance", Payload::Event { event: remote.clone() }));
+        broadcast(&state, remote.clone()).await;
+        assert_eq!(rx.try_recv().unwrap().id, remote.id);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn messages_from_this_instance_are_skipped() {
+        let state = state().await;
+        let (mut events, mut controls) = (state.tx.subscribe(), state.control.subscribe());
+        let own = state.fanout.instance_id.clone();
+        let pubkey = Keys::generate().public_key().to_hex();
+        state.local.set_standing(&pubkey, Standing::Active);
+
+        handle_message(&state, &message(&own, Payload::Event { event: note() }));
+        handle_message(&state, &message(&own, Payload::Control { control: Control::PubkeyBanned(pubkey.clone()) }));
+        handle_message(&state, &message(&own, Payload::Whitelist { pubkey: pubkey.clone() }));
+        assert!(events.try_recv().is_err());
+        assert!(controls.try_recv().is_err());
+        assert_eq!(state.local.standing(&pubkey), Some(Standing::Active));
+
+        handle_message(&state, "not an envelope");
+        handle_message(&state, &message("other-instance", Payload::Control { control: Control::PubkeyBanned(pubkey.clone()) }));
+        handle_message(&state, &message("other-instance", Payload::Whitelist { pubkey: pubkey.clone() }));
+        assert!(matches!(controls.try_recv().unwrap(), Control::PubkeyBanned(banned) if banned == pubkey));
+        assert_eq!(state.local.standing(&pubkey), None);
+    }
+}
@@ -0,0 +1,77 @@
+// The relay's own key pair, which signs NIP-66 monitor events, published audit
+// entries and NIP-42 AUTH to peers, and is served as `pubkey` in NIP-11
+//
+// `RELAY_SECRET_KEY` (hex or nsec) takes precedence. Otherwise the key is read
+// from `RELAY_KEY_FILE`, which is generated once and kept, so the relay keeps
+// the same identity across restarts. Only the server and `sync` generate it;
+// other subcommands use an existing key or none at all.
+
+use crate::{config::RelayConfig, AppState};
+use nostr::{Keys, ToBech32};
+use std::{fs, io::Write, path::Path, sync::Arc};
+use tracing::{error, info};
+
+/// The configured key, generating and saving one if there is none yet
+pub fn load(config: &RelayConfig) -> Keys {
+    if let Some(keys) = existing(config) {
+        return keys;
+    }
+    let path = Path::new(&config.key_file);
+    let keys = Keys::generate();
+    save(path, &keys).unwrap_or_else(|e| panic!("Failed to save the relay key to {}: {}", path.display(), e));
+    info!("Generated a relay key and saved it to {}", path.display());
+    keys
+}
+
+/// The configured key, or `None` if neither the variable nor the file holds one
+pub fn existing(config: &RelayConfig) -> Option<Keys> {
+    if let Some(secret) = std::env::var("RELAY_SECRET_KEY").ok().filter(|v| !v.trim().is_empty()) {
+        return Some(Keys::parse(secret.trim()).expect("RELAY_SECRET_KEY must be a hex or nsec secret key"));
+    }
+
+    let path = Path::new(&config.key_file);
+    match fs::read_to_string(path) {
+        Ok(secret) => Some(
+            Keys::parse(secret.trim())
+                .unwrap_or_else(|e| panic!("{} does not hold a hex or nsec secret key: {}", path.display(), e)),
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => panic!("Failed to read the relay key from {}: {}", path.display(), e),
+    }
+}
+
+/// Write the secret key readable by the owner only, never over an existing file
+fn save(path: &Path, keys: &Keys) -> std::io::Result<()> {
+    let secret = keys.secret_key().map_err(std::io::Error::other)?.to_secret_hex();
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    writeln!(file, "{}", secret)?;
+    file.sync_all()
+}
+
+/// Give the relay pubkey a `users` row, which Postgres requires of every event
+/// author, so relay-signed events can be stored. The row is flagged so it isn't
+/// listed among the members.
+pub async fn register(state: &Arc<AppState>) {
+    let Some(keys) = &state.keys else { return };
+    let pubkey = keys.public_key();
+    let npub = match pubkey.to_bech32() {
+        Ok(npub) => npub,
+        Err(e) => {
+            error!("Failed to encode the relay pubkey: {}", e);
+            return;
+        }
+    };
+    match state.store.register_relay_user(&npub, &pubkey.to_hex()).await {
+        Ok(true) => info!("Added the relay pubkey {} as a user", npub),
+        Ok(false) => {}
+        Err(e) => error!("Failed to add the relay pubkey as a user: {}", e),
+    }
+}
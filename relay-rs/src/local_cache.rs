@@ -0,0 +1,207 @@
+// In-process fallback for the Redis caches
+//
+// Bounded LRUs of whitelist standings and events by id, consulted only while
+// Redis is absent or switched off by the circuit breaker. Entries expire after
+// LOCAL_TTL, well short of the Redis TTLs, because changes made through other
+// instances can't reach this one while Redis is down.
+//
+// It also remembers the NIP-98 auth events this instance has accepted, which
+// Redis records too when it's available.
+
+use crate::{store::IndexKey, Standing};
+use lru::LruCache;
+use nostr::{Event, EventId, Filter, Timestamp};
+use std::{
+    collections::{hash_map::Entry as MapEntry, HashMap, HashSet},
+    num::NonZeroUsize,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+const LOCAL_TTL: Duration = Duration::from_secs(60);
+const MAX_STANDINGS: usize = 10_000;
+const MAX_EVENTS: usize = 5_000;
+
+struct Entry<T> {
+    value: T,
+    stored_at: Instant,
+}
+
+pub struct LocalCache {
+    standings: Mutex<LruCache<String, Entry<Standing>>>,
+    events: Mutex<LruCache<EventId, Entry<Event>>>,
+    /// Pubkeys whose cached standing couldn't be cleared from Redis, to clear
+    /// once it's reachable again
+    pending_invalidations: Mutex<HashSet<String>>,
+    /// Accepted NIP-98 auth event ids, with the time after which they'd be
+    /// refused as too old anyway
+    used_auth_events: Mutex<HashMap<EventId, i64>>,
+}
+
+impl Default for LocalCache {
+    fn default() -> Self {
+        Self {
+            standings: Mutex::new(LruCache::new(NonZeroUsize::new(MAX_STANDINGS).unwrap())),
+            events: Mutex::new(LruCache::new(NonZeroUsize::new(MAX_EVENTS).unwrap())),
+            pending_invalidations: Mutex::default(),
+            used_auth_events: Mutex::default(),
+        }
+    }
+}
+
+impl LocalCache {
+    pub fn standing(&self, pubkey: &str) -> Option<Standing> {
+        let mut standings = self.standings.lock().unwrap_or_else(PoisonError::into_inner);
+        let entry = standings.get(pubkey)?;
+        if entry.stored_at.elapsed() >= LOCAL_TTL {
+            standings.pop(pubkey);
+            return None;
+        }
+        Some(entry.value)
+    }
+
+    pub fn set_standing(&self, pubkey: &str, standing: Standing) {
+        let entry = Entry { value: standing, stored_at: Instant::now() };
+        self.standings.lock().unwrap_or_else(PoisonError::into_inner).put(pubkey.to_string(), entry);
+    }
+
+    pub fn forget_standing(&self, pubkey: &str) {
+        self.standings.lock().unwrap_or_else(PoisonError::into_inner).pop(pubkey);
+    }
+
+    pub fn defer_invalidation(&self, pubkey: &str) {
+        self.pending_invalidations.lock().unwrap_or_else(PoisonError::into_inner).insert(pubkey.to_string());
+    }
+
+    pub fn take_pending_invalidations(&self) -> Vec<String> {
+        self.pending_invalidations.lock().unwrap_or_else(PoisonError::into_inner).drain().collect()
+    }
+
+    /// Record an auth event as used until `expires_at`. False if it already was.
+    pub fn claim_auth_event(&self, id: EventId, expires_at: i64) -> bool {
+        let now = Timestamp::now().as_i64();
+        let mut used = self.used_auth_events.lock().unwrap_or_else(PoisonError::into_inner);
+        used.retain(|_, until| *until >= now);
+        match used.entry(id) {
+            MapEntry::Occupied(_) => false,
+            MapEntry::Vacant(entry) => {
+                entry.insert(expires_at);
+                true
+            }
+        }
+    }
+
+    pub fn remember_events(&self, events: &[Event]) {
+        let mut cached = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+        for event in events {
+            cached.put(event.id, Entry { value: event.clone(), stored_at: Instant::now() });
+        }
+    }
+
+    pub fn forget_events(&self, keys: &[IndexKey]) {
+        let mut cached = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+        for (_, id) in keys {
+            cached.pop(id);
+        }
+    }
+
+    /// Answer a filter that names its ids, if every one of them is cached
+    pub fn query_ids(&self, filter: &Filter, limit: usize) -> Option<Vec<Event>> {
+        let ids = filter.ids.as_ref().filter(|ids| !ids.is_empty())?;
+        let mut cached = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let mut events = Vec::with_capacity(ids.len());
+        for id in ids {
+            let entry = cached.get(id)?;
+            if entry.stored_at.elapsed() >= LOCAL_TTL || entry.value.is_expired() {
+                cached.pop(id);
+                return None;
+            }
+            if filter.match_event(&entry.value) {
+                events.push(entry.value.clone());
+            }
+        }
+        events.sort_by_key(|event| std::cmp::Reverse(event.created_at));
+        events.truncate(limit);
+        Some(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cache, tests::state};
+    use nostr::{EventBuilder, Keys, Kind};
+
+    fn note(content: &str) -> Event {
+        EventBuilder::text_note(content, []).to_event(&Keys::generate()).unwrap()
+    }
+
+    fn age(entry: &mut Entry<impl Sized>) {
+        entry.stored_at = Instant::now() - LOCAL_TTL;
+    }
+
+    #[test]
+    fn standings_expire_and_the_least_recent_are_evicted() {
+        let cache = LocalCache::default();
+        cache.set_standing("first", Standing::Active);
+        assert_eq!(cache.standing("first"), Some(Standing::Active));
+
+        for n in 0..MAX_STANDINGS {
+            cache.set_standing(&n.to_string(), Standing::Pending);
+        }
+        assert_eq!(cache.standing("first"), None);
+
+        cache.set_standing("aged", Standing::Admin);
+        age(cache.standings.lock().unwrap().get_mut("aged").unwrap());
+        assert_eq!(cache.standing("aged"), None);
+    }
+
+    #[test]
+    fn id_queries_are_answered_only_when_every_id_is_cached() {
+        let cache = LocalCache::default();
+        let (older, newer, missing) = (note("older"), note("newer"), note("missing"));
+        cache.remember_events(&[older.clone(), newer.clone()]);
+
+        let both = cache.query_ids(&Filter::new().ids([older.id, newer.id]), 10).unwrap();
+        assert_eq!(both.len(), 2);
+        assert!(cache.query_ids(&Filter::new().ids([older.id, missing.id]), 10).is_none());
+        // Only filters naming ids can be answered
+        assert!(cache.query_ids(&Filter::new().kind(Kind::TextNote), 10).is_none());
+        // Cached events the rest of the filter excludes are left out, not a miss
+        let other_kind = cache.query_ids(&Filter::new().id(older.id).kind(Kind::Metadata), 10);
+        assert_eq!(other_kind.map(|events| events.len()), Some(0));
+
+        cache.forget_events(&[(older.created_at.as_u64(), older.id)]);
+        assert!(cache.query_ids(&Filter::new().id(older.id), 10).is_none());
+        age(cache.events.lock().unwrap().get_mut(&newer.id).unwrap());
+        assert!(cache.query_ids(&Filter::new().id(newer.id), 10).is_none());
+    }
+
+    #[test]
+    fn auth_events_are_remembered_until_they_expire() {
+        let cache = LocalCache::default();
+        let now = Timestamp::now().as_i64();
+        let (fresh, stale) = (note("fresh").id, note("stale").id);
+        assert!(cache.claim_auth_event(fresh, now + 60));
+        assert!(!cache.claim_auth_event(fresh, now + 60));
+
+        assert!(cache.claim_auth_event(stale, now - 1));
+        assert!(cache.claim_auth_event(stale, now - 1), "expired ids are forgotten");
+        assert_eq!(cache.used_auth_events.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn without_redis_reqs_by_id_use_the_local_cache() {
+        let state = state().await;
+        let event = note("remembered");
+        let by_id = Filter::new().id(event.id);
+        assert!(cache::query(&state, &by_id, 10).await.is_none());
+
+        cache::remember(&state, std::slice::from_ref(&event));
+        let found = cache::query(&state, &by_id, 10).await.unwrap();
+        assert_eq!(found[0].id, event.id);
+        let stats = cache::stats(&state).await;
+        assert_eq!((stats["local_hits"].as_u64(), stats["local_misses"].as_u64()), (Some(1), Some(1)));
+    }
+}
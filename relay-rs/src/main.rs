@@ -1,58 +1,125 @@
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        ConnectInfo, State,
     },
-    http::HeaderMap,
+    http::{HeaderMap, StatusCode},
     response::{IntoResponse, Response, Json},
     routing::get,
     Router,
 };
 use futures::{sink::SinkExt, stream::StreamExt};
-use nostr::{ClientMessage, Event, Filter, RelayMessage, SubscriptionId, JsonUtil, Tag, Keys, EventBuilder, Timestamp, Kind};
-use sqlx::{postgres::PgPoolOptions, Pool, Postgres, Row};
+use nostr::{nips::nip26, ClientMessage, Event, Filter, RelayMessage, SubscriptionId, JsonUtil, Tag, Keys, EventBuilder, Kind, PublicKey, ToBech32};
 use std::{
-    collections::HashMap,
-    net::SocketAddr,
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
     sync::{Arc},
     time::Duration,
 };
 use tokio::sync::broadcast;
 use tracing::{error, info, warn, debug};
-use negentropy::{Bytes, Negentropy};
-use deadpool_redis::{Pool as RedisPool, Config as RedisConfig, Runtime};
+use deadpool_redis::{Pool as RedisPool, Config as RedisConfig, PoolConfig, Runtime, Timeouts};
 use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
 use tower_http::compression::CompressionLayer;
 
+mod audit;
+mod breaker;
+mod cache;
+mod cli;
+mod config;
+mod fanout;
+mod identity;
+mod local_cache;
+mod mirror;
+mod moderation;
+mod nip77;
+mod nip86;
+mod peer;
+mod store;
+mod sync;
+
+use clap::Parser;
+
+use config::RelayConfig;
+use store::{Deletion, EventStore, PrefixQuery, UserAccess, WhitelistStatus};
+
+/// `process_event` rejection for an author with no `users` row, which Postgres
+/// requires of every stored event
+const MISSING_AUTHOR: &str = "error: author is not in the users table";
+
 // Cache TTL constants
 const CACHE_TTL_WHITELIST: u64 = 300; // 5 minutes for whitelist lookups
-const CACHE_TTL_RECENT_EVENTS: u64 = 60; // 1 minute for recent events
-const RECENT_EVENTS_KEY: &str = "relay:recent_events";
-const MAX_CACHED_EVENTS: i64 = 1000;
+const REDIS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// NIP-66 relay discovery event, republished hourly under the relay key
+const MONITOR_KIND: u64 = 30166;
+const MONITOR_IDENTIFIER: &str = "nrelay";
 
 #[derive(Clone)]
 struct AppState {
-    db: Pool<Postgres>,
+    config: RelayConfig,
+    store: Arc<dyn EventStore>,
     tx: broadcast::Sender<Event>,
+    control: broadcast::Sender<Control>,
     redis: Option<RedisPool>,
+    /// The relay's persistent identity: signs relay-authored events (NIP-66 monitor,
+    /// audit log) and AUTH to peers. `None` for subcommands that sign nothing.
+    keys: Option<Keys>,
+    mirror_stats: Arc<mirror::MirrorStats>,
+    fanout: Arc<fanout::Fanout>,
+    cache: Arc<cache::EventCache>,
+    /// Stands in for Redis while it's absent or failing
+    local: Arc<local_cache::LocalCache>,
+    breaker: Arc<breaker::CircuitBreaker>,
+}
+
+/// Moderation changes that live connections must act on straight away
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "action", content = "target", rename_all = "snake_case")]
+enum Control {
+    /// Sockets authenticated as this pubkey lose it, and close if it was their only one
+    PubkeyBanned(String),
+    /// Sockets from this address close
+    IpBlocked(IpAddr),
 }
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
+    // Logs go to stderr so `export` can write events to stdout
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+
+    let cli = cli::Cli::parse();
 
     let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let pool = PgPoolOptions::new()
-        .max_connections(50)
-        .connect(&database_url)
+    let mut store = store::connect(&database_url)
         .await
         .expect("Failed to connect to database");
+    if cli.command.is_none() {
+        store.spawn_maintenance();
+        // Only the server answers NIP-77, so only it pays for the negentropy index
+        store = store::IndexedStore::wrap(store);
+    }
+
+    let config = RelayConfig::from_env();
 
     // Initialize Redis connection pool
-    let redis_url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://redis:6379".to_string());
-    let redis_pool = match RedisConfig::from_url(&redis_url).create_pool(Some(Runtime::Tokio1)) {
+    // Short timeouts, so a dead Redis fails fast and trips the circuit breaker
+    let mut redis_config = RedisConfig::from_url(&config.redis_url);
+    redis_config.pool = Some(PoolConfig {
+        timeouts: Timeouts {
+            wait: Some(REDIS_TIMEOUT),
+            create: Some(REDIS_TIMEOUT),
+            recycle: Some(REDIS_TIMEOUT),
+        },
+        ..PoolConfig::default()
+    });
+    let redis_pool = match redis_config.create_pool(Some(Runtime::Tokio1)) {
         Ok(pool) => {
-            info!("Redis connection pool initialized: {}", redis_url);
+            info!("Redis connection pool initialized: {}", config.redis_url);
             Some(pool)
         }
         Err(e) => {
@@ -62,97 +129,162 @@ async fn main() {
     };
 
     let (tx, _rx) = broadcast::channel(1000); // Increased buffer size
+    let (control, _) = broadcast::channel(100);
+
+    let keys = match &cli.command {
+        None | Some(cli::Command::Sync { .. }) => Some(identity::load(&config)),
+        // Audit events must come from the relay's real identity, not a new key
+        Some(cli::Command::Admin { .. }) if config.publish_audit => {
+            let keys = identity::existing(&config);
+            if keys.is_none() {
+                warn!("No relay key in RELAY_SECRET_KEY or {}; not publishing audit events", config.key_file);
+            }
+            keys
+        }
+        Some(_) => None,
+    };
+    if let Some(keys) = &keys {
+        info!("Relay pubkey: {}", keys.public_key());
+    }
 
-    let state = Arc::new(AppState { db: pool, tx, redis: redis_pool });
+    let state = Arc::new(AppState {
+        config,
+        store,
+        tx,
+        control,
+        redis: redis_pool,
+        keys,
+        mirror_stats: Arc::default(),
+        fanout: Arc::default(),
+        cache: Arc::default(),
+        local: Arc::default(),
+        breaker: Arc::default(),
+    });
+
+    let result = match cli.command {
+        None => {
+            serve(state).await;
+            Ok(())
+        }
+        Some(command) => cli::run(command, state).await,
+    };
+
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn serve(state: Arc<AppState>) {
+    // Other instances' events and moderation changes, when running replicas
+    // Relay-signed events need their author in `users`
+    identity::register(&state).await;
+
+    fanout::spawn(&state);
+    cache::spawn_warm(&state);
+
+    // NIP-77 client: keep configured peers reconciled
+    sync::spawn_scheduler(&state);
+    // Long-lived REQ subscriptions on upstream relays
+    mirror::spawn(&state);
 
     // NIP-66: Relay Monitor Task
     let monitor_state = state.clone();
     tokio::spawn(async move {
-        let keys = Keys::generate(); // Ephemeral relay keys for now
-        let pubkey = keys.public_key();
-        info!("Relay Pubkey for NIP-66: {}", pubkey);
+        let Some(keys) = monitor_state.keys.clone() else { return };
 
         loop {
             // Build Kind 30166 Event
             let tags = vec![
-                Tag::Identifier("nrelay".to_string()),
-                Tag::parse(vec!["url", "wss://relay.pleb.one"]).unwrap(),
+                Tag::Identifier(MONITOR_IDENTIFIER.to_string()),
+                Tag::parse(vec!["url", &monitor_state.config.relay_urls[0]]).unwrap(),
                 Tag::parse(vec!["software", "relay-rs"]).unwrap(),
                 Tag::parse(vec!["version", "0.1.0"]).unwrap(),
-                Tag::parse(vec!["supported_nips", "1", "9", "11", "15", "17", "20", "23", "33", "40", "42", "51", "56", "62", "65", "66", "77", "86"]).unwrap(),
+                Tag::parse(vec!["supported_nips", "1", "9", "11", "15", "17", "20", "23", "33", "40", "42", "45", "51", "56", "62", "65", "66", "70", "77", "86"]).unwrap(),
             ];
 
             let event_builder = EventBuilder::new(
-                Kind::from(30166),
+                Kind::from(MONITOR_KIND),
                 "",
                 tags,
             );
             
             if let Ok(event) = event_builder.to_event(&keys) {
-                // Save to DB (Upsert)
-                let tags_json = serde_json::to_value(&event.tags).unwrap_or(serde_json::Value::Null);
-                let created_at = chrono::DateTime::from_timestamp(event.created_at.as_u64() as i64, 0)
-                    .unwrap_or_default()
-                    .naive_utc()
-                    .and_utc();
-
-                // We use a simplified insert here. In a real NIP-66, we might want to replace the previous one.
-                // Since it's addressable (30166), we should handle replacement logic, but our DB schema is append-only-ish with "ON CONFLICT DO NOTHING" for ID.
-                // For 30166, we should probably delete old ones from this pubkey+d tag or just let them pile up (not ideal).
-                // For this MVP, we'll just insert it.
-                
-                let _ = sqlx::query(
-                    "INSERT INTO events (id, \"eventId\", pubkey, kind, content, tags, sig, \"createdAt\", \"receivedAt\") 
-                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, NOW())
-                     ON CONFLICT (\"eventId\") DO NOTHING"
-                )
-                .bind(nanoid::nanoid!())
-                .bind(event.id.to_string())
-                .bind(event.pubkey.to_string())
-                .bind(event.kind.as_u64() as i32)
-                .bind(&event.content)
-                .bind(tags_json)
-                .bind(event.sig.to_string())
-                .bind(created_at)
-                .execute(&monitor_state.db)
-                .await;
-
-                // Broadcast
-                let _ = monitor_state.tx.send(event);
+                // Addressable, so each hour's event replaces the last one
+                delete_events(&monitor_state, Deletion::Address {
+                    author: &keys.public_key().to_hex(),
+                    kind: MONITOR_KIND,
+                    identifier: MONITOR_IDENTIFIER,
+                }).await;
+
+                match monitor_state.store.save_event(&event, None).await {
+                    Ok(_) => {
+                        cache::insert(&monitor_state, &event).await;
+                        fanout::broadcast(&monitor_state, event).await;
+                    }
+                    Err(e) => error!("Failed to store NIP-66 monitor event: {}", e),
+                }
             }
 
             tokio::time::sleep(Duration::from_secs(3600)).await; // Publish every hour
         }
     });
 
-    let app = Router::new()
-        .route("/", get(handler))
-        .layer(CompressionLayer::new()) // Enable gzip/br/deflate compression for HTTP responses
-        .with_state(state);
+    let app = router(state);
 
     let port = std::env::var("RELAY_PORT").unwrap_or_else(|_| "3001".to_string());
     let addr: SocketAddr = format!("0.0.0.0:{}", port).parse().unwrap();
 
     info!("Listening on {}", addr);
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+}
+
+/// Websocket, NIP-11 and NIP-86 HTTP endpoints
+fn router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/", get(handler).post(nip86::http_handler))
+        .layer(CompressionLayer::new()) // Enable gzip/br/deflate compression for HTTP responses
+        .with_state(state)
 }
 
 async fn handler(
     ws: Option<WebSocketUpgrade>,
     headers: HeaderMap,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
     State(state): State<Arc<AppState>>,
 ) -> Response {
+    // NIP-86 blockip
+    let ip = client_ip(&state, &headers, peer);
+    match state.store.is_ip_blocked(&ip.to_string()).await {
+        Ok(true) => return (StatusCode::FORBIDDEN, "blocked").into_response(),
+        Ok(false) => {}
+        Err(e) => error!("Failed to check blocked IPs: {}", e),
+    }
+
     if let Some(ws) = ws {
-        return ws.on_upgrade(|socket| handle_socket(socket, state)).into_response();
+        return ws.on_upgrade(move |socket| handle_socket(socket, state, ip)).into_response();
     }
 
     if let Some(accept) = headers.get("accept") {
         if accept.to_str().unwrap_or("").contains("application/nostr+json") {
+            // Name, description and icon can be changed through NIP-86
+            let store = &state.store;
+            let name = store.setting(nip86::SETTING_NAME).await.ok().flatten()
+                .unwrap_or_else(|| "Relay Pleb One".to_string());
+            let description = store.setting(nip86::SETTING_DESCRIPTION).await.ok().flatten()
+                .unwrap_or_else(|| "A Rust-based Nostr Relay".to_string());
+            let icon = store.setting(nip86::SETTING_ICON).await.ok().flatten();
+
             return Json(serde_json::json!({
-                "name": "Relay Pleb One",
-                "description": "A Rust-based Nostr Relay",
-                "supported_nips": [1, 9, 11, 15, 17, 20, 23, 33, 40, 42, 51, 56, 62, 65, 66, 77, 86],
+                "name": name,
+                "description": description,
+                "icon": icon,
+                "pubkey": state.keys.as_ref().map(|keys| keys.public_key().to_hex()),
+                "supported_nips": [1, 9, 11, 15, 17, 20, 23, 33, 40, 42, 45, 51, 56, 62, 65, 66, 70, 77, 86],
+                "limitation": {
+                    "auth_required": state.config.auth_required
+                },
                 "software": "relay-rs",
                 "version": "0.1.0"
             })).into_response();
@@ -162,15 +294,32 @@ async fn handler(
     "Welcome to Relay Pleb One (Rust Edition)".into_response()
 }
 
-async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>) {
+/// The connecting client's address; behind a trusted reverse proxy this is the
+/// first `X-Forwarded-For` entry rather than the proxy itself
+fn client_ip(state: &AppState, headers: &HeaderMap, peer: SocketAddr) -> IpAddr {
+    if state.config.trust_proxy {
+        let forwarded = headers
+            .get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+        if let Some(ip) = forwarded {
+            return ip;
+        }
+    }
+    peer.ip()
+}
+
+async fn handle_socket(socket: WebSocket, state: Arc<AppState>, ip: IpAddr) {
     let (mut sender, mut receiver) = socket.split();
     let mut subscriptions: HashMap<String, Vec<Filter>> = HashMap::new();
     let mut broadcast_rx = state.tx.subscribe();
+    let mut control_rx = state.control.subscribe();
 
     // Spawn a task to handle broadcast messages (events from other clients)
     let (tx_internal, mut rx_internal) = tokio::sync::mpsc::channel::<Message>(100);
     
-    let mut send_task = tokio::spawn(async move {
+    let send_task = tokio::spawn(async move {
         while let Some(msg) = rx_internal.recv().await {
             if let Err(e) = sender.send(msg).await {
                 warn!("Failed to send message: {}", e);
@@ -181,7 +330,7 @@ async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>) {
 
     // Heartbeat task
     let tx_ping = tx_internal.clone();
-    let mut heartbeat_task = tokio::spawn(async move {
+    let heartbeat_task = tokio::spawn(async move {
         loop {
             tokio::time::sleep(std::time::Duration::from_secs(30)).await;
             if tx_ping.send(Message::Ping(vec![])).await.is_err() {
@@ -190,12 +339,14 @@ async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>) {
         }
     });
 
-    // NIP-42: Send AUTH challenge
-    let challenge = nanoid::nanoid!();
+    // NIP-42: Send AUTH challenge. A connection may authenticate several pubkeys,
+    // each with a fresh challenge.
+    let mut challenge = nanoid::nanoid!();
     let _ = tx_internal.send(Message::Text(RelayMessage::auth(challenge.clone()).as_json())).await;
 
-    let mut auth_pubkey: Option<String> = None;
-    let mut negentropy_sessions: HashMap<String, Negentropy> = HashMap::new();
+    let mut auth_pubkeys: HashSet<String> = HashSet::new();
+    let mut negentropy_sessions = nip77::Sessions::default();
+    let mut negentropy_sweep = tokio::time::interval(nip77::IDLE_TIMEOUT / 4);
 
     // Loop to handle incoming messages from client
     loop {
@@ -207,26 +358,26 @@ async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>) {
                         if let Ok(val) = serde_json::from_str::<serde_json::Value>(&text) {
                             if val.is_object() {
                                 // NIP-86 JSON-RPC
-                                handle_nip86(val, &state, &tx_internal, &auth_pubkey).await;
+                                handle_nip86(val, &state, &tx_internal, &auth_pubkeys).await;
                             } else if val.is_array() {
                                 let arr = val.as_array().unwrap();
                                 if !arr.is_empty() {
                                     let msg_type = arr[0].as_str().unwrap_or("");
                                     match msg_type {
                                         "NEG-OPEN" => {
-                                            handle_nip77_open(arr, &state, &tx_internal, &mut negentropy_sessions).await;
+                                            nip77::handle_open(arr, &state, &tx_internal, &mut negentropy_sessions, &auth_pubkeys).await;
                                         }
                                         "NEG-MSG" => {
-                                            handle_nip77_msg(arr, &state, &tx_internal, &mut negentropy_sessions).await;
+                                            nip77::handle_msg(arr, &tx_internal, &mut negentropy_sessions).await;
                                         }
                                         "NEG-CLOSE" => {
-                                            handle_nip77_close(arr, &mut negentropy_sessions);
+                                            nip77::handle_close(arr, &mut negentropy_sessions);
                                         }
                                         _ => {
                                             // Standard Nostr
                                             match ClientMessage::from_json(&text) {
                                                 Ok(msg) => {
-                                                    handle_client_message(msg, &state, &mut subscriptions, &tx_internal, &challenge, &mut auth_pubkey).await;
+                                                    handle_client_message(msg, &state, &mut subscriptions, &tx_internal, &mut challenge, &mut auth_pubkeys).await;
                                                 }
                                                 Err(e) => {
                                                     // Attempt to fix malformed REQ from some clients (nostr-tools v2?)
@@ -243,7 +394,7 @@ async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>) {
                                                             }
                                                             let new_text = serde_json::to_string(&new_arr).unwrap_or_default();
                                                             if let Ok(msg) = ClientMessage::from_json(&new_text) {
-                                                                handle_client_message(msg, &state, &mut subscriptions, &tx_internal, &challenge, &mut auth_pubkey).await;
+                                                                handle_client_message(msg, &state, &mut subscriptions, &tx_internal, &mut challenge, &mut auth_pubkeys).await;
                                                                 handled = true;
                                                             }
                                                         }
@@ -259,7 +410,8 @@ async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>) {
                                                                     filter_obj.clone(),
                                                                     &state,
                                                                     &mut subscriptions,
-                                                                    &tx_internal
+                                                                    &tx_internal,
+                                                                    &auth_pubkeys
                                                                 ).await;
                                                                 handled = true;
                                                             }
@@ -289,6 +441,9 @@ async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>) {
             }
             Ok(event) = broadcast_rx.recv() => {
                 debug!("Broadcast received event {} (kind: {}), checking {} active subscriptions", event.id, event.kind, subscriptions.len());
+                if !can_read_event(&event, &auth_pubkeys) {
+                    continue;
+                }
                 // Check if event matches any subscription
                 let mut sent_to = Vec::new();
                 for (sub_id, filters) in &subscriptions {
@@ -305,11 +460,37 @@ async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>) {
                     info!("Broadcast event {} to {} subscriptions: {:?}", event.id, sent_to.len(), sent_to);
                 }
             }
+            _ = negentropy_sweep.tick() => {
+                negentropy_sessions.expire_idle(&tx_internal).await;
+            }
+            Ok(control) = control_rx.recv() => {
+                let close = match control {
+                    Control::PubkeyBanned(pubkey) => {
+                        if !auth_pubkeys.remove(&pubkey) {
+                            continue;
+                        }
+                        info!("Downgraded connection authenticated as banned pubkey {}", pubkey);
+                        let _ = tx_internal.send(Message::Text(RelayMessage::notice(format!("restricted: {} has been banned", pubkey)).as_json())).await;
+                        auth_pubkeys.is_empty()
+                    }
+                    Control::IpBlocked(blocked) => blocked == ip,
+                };
+                if close {
+                    let _ = tx_internal.send(Message::Close(None)).await;
+                    break;
+                }
+            }
         }
     }
-    
-    send_task.abort();
+
+    // Let queued messages (e.g. a closing NOTICE) go out before dropping the socket
     heartbeat_task.abort();
+    drop(tx_internal);
+    let send_abort = send_task.abort_handle();
+    if tokio::time::timeout(Duration::from_secs(1), send_task).await.is_err() {
+        debug!("Timed out flushing messages to closing socket");
+        send_abort.abort();
+    }
 }
 
 async fn handle_client_message(
@@ -317,15 +498,50 @@ async fn handle_client_message(
     state: &Arc<AppState>,
     subscriptions: &mut HashMap<String, Vec<Filter>>, 
     sender: &tokio::sync::mpsc::Sender<Message>,
-    challenge: &str,
-    auth_pubkey: &mut Option<String>,
+    challenge: &mut String,
+    auth_pubkeys: &mut HashSet<String>,
 ) {
     match msg {
         ClientMessage::Event(event) => {
-            handle_event(*event, state, sender).await;
+            handle_event(*event, state, sender, auth_pubkeys).await;
         }
         ClientMessage::Req { subscription_id, filters } => {
-            handle_req(subscription_id, filters, state, subscriptions, sender).await;
+            handle_req(subscription_id, filters, state, subscriptions, sender, auth_pubkeys).await;
+        }
+        ClientMessage::Count { subscription_id, filters } => {
+            // NIP-45: Counting results
+            if let Some(reason) = read_access_denied(state, auth_pubkeys).await {
+                let _ = sender.send(Message::Text(RelayMessage::closed(subscription_id, reason).as_json())).await;
+                return;
+            }
+
+            // A count over private kinds leaks metadata, so it must be scoped to the reader
+            if filters.iter().any(requests_private_kinds) {
+                let reason = if auth_pubkeys.is_empty() {
+                    Some("auth-required: authenticate to count direct messages")
+                } else if filters.iter().any(|f| requests_private_kinds(f) && !filter_scoped_to(f, auth_pubkeys)) {
+                    Some("restricted: direct message counts must be limited to your own authors or #p")
+                } else {
+                    None
+                };
+                if let Some(reason) = reason {
+                    let _ = sender.send(Message::Text(RelayMessage::closed(subscription_id, reason).as_json())).await;
+                    return;
+                }
+            }
+
+            let mut count = 0;
+            for filter in &filters {
+                match state.store.count(filter).await {
+                    Ok(n) => count += n,
+                    Err(e) => {
+                        error!("Failed to count events: {}", e);
+                        let _ = sender.send(Message::Text(RelayMessage::closed(subscription_id, "error: failed to count events").as_json())).await;
+                        return;
+                    }
+                }
+            }
+            let _ = sender.send(Message::Text(RelayMessage::count(subscription_id, count).as_json())).await;
         }
         ClientMessage::Close(subscription_id) => {
             subscriptions.remove(&subscription_id.to_string());
@@ -338,16 +554,30 @@ async fn handle_client_message(
                 return;
             }
             
-            // Verify challenge tag
-            let mut challenge_valid = false;
-            for tag in &event.tags {
+            // Verify relay tag points at us, so AUTH events for other relays can't be replayed here
+            let relay_valid = event.tags.iter().any(|tag| {
                 let t = tag.as_vec();
-                if t.len() >= 2 && t[0] == "challenge" && t[1] == challenge {
-                    challenge_valid = true;
-                    break;
-                }
+                t.len() >= 2 && t[0] == "relay" && state.config.is_own_url(&t[1])
+            });
+
+            if !relay_valid {
+                let _ = sender.send(Message::Text(RelayMessage::ok(event.id, false, "error: invalid relay url".to_string()).as_json())).await;
+                return;
             }
 
+            // Verify created_at is recent
+            let skew = (chrono::Utc::now().timestamp() - event.created_at.as_i64()).abs();
+            if skew > config::AUTH_MAX_SKEW_SECS {
+                let _ = sender.send(Message::Text(RelayMessage::ok(event.id, false, "error: auth event too old or too far in the future".to_string()).as_json())).await;
+                return;
+            }
+
+            // Verify challenge tag
+            let challenge_valid = event.tags.iter().any(|tag| {
+                let t = tag.as_vec();
+                t.len() >= 2 && t[0] == "challenge" && t[1] == *challenge
+            });
+
             if !challenge_valid {
                 let _ = sender.send(Message::Text(RelayMessage::ok(event.id, false, "error: invalid challenge".to_string()).as_json())).await;
                 return;
@@ -359,8 +589,12 @@ async fn handle_client_message(
                 return;
             }
 
-            *auth_pubkey = Some(event.pubkey.to_string());
+            auth_pubkeys.insert(event.pubkey.to_string());
             let _ = sender.send(Message::Text(RelayMessage::ok(event.id, true, "auth-success".to_string()).as_json())).await;
+
+            // Each challenge is good for one AUTH; issue a new one for further pubkeys
+            *challenge = nanoid::nanoid!();
+            let _ = sender.send(Message::Text(RelayMessage::auth(challenge.clone()).as_json())).await;
         }
         _ => {
             // Other messages
@@ -373,425 +607,522 @@ async fn handle_nip86(
     val: serde_json::Value,
     state: &Arc<AppState>,
     sender: &tokio::sync::mpsc::Sender<Message>,
-    auth_pubkey: &Option<String>,
+    auth_pubkeys: &HashSet<String>,
 ) {
-    let id = val.get("id");
-    let method = val.get("method").and_then(|v| v.as_str());
-    let params = val.get("params").and_then(|v| v.as_array());
-
-    if id.is_none() || method.is_none() {
+    if val.get("id").is_none() || val.get("method").is_none() {
         return;
     }
-    let id = id.unwrap();
-    let method = method.unwrap();
 
-    // Check Auth (Must be Admin)
-    let is_admin = if let Some(pubkey) = auth_pubkey {
-        let row = sqlx::query("SELECT \"isAdmin\" FROM users WHERE pubkey = $1")
-            .bind(pubkey)
-            .fetch_optional(&state.db)
-            .await
-            .unwrap_or(None);
-        row.map(|r| r.try_get::<bool, _>("isAdmin").unwrap_or(false)).unwrap_or(false)
-    } else {
-        false
-    };
+    let admin = nip86::admin_pubkey(state, auth_pubkeys).await;
+    let response = nip86::respond(state, &val, admin.as_deref(), "websocket").await;
 
-    if !is_admin {
-        let err = serde_json::json!({
-            "jsonrpc": "2.0",
-            "id": id,
-            "error": { "code": -32000, "message": "Unauthorized: Admin access required" }
-        });
-        let _ = sender.send(Message::Text(err.to_string())).await;
-        return;
-    }
+    let _ = sender.send(Message::Text(response.to_string())).await;
+}
 
-    let result = match method {
-        "list_allowed_users" => {
-            let rows = sqlx::query("SELECT pubkey, \"whitelistStatus\" FROM users WHERE \"whitelistStatus\" = 'ACTIVE'")
-                .fetch_all(&state.db)
-                .await;
-            match rows {
-                Ok(rows) => {
-                    let users: Vec<String> = rows.iter().map(|r| r.get("pubkey")).collect();
-                    Ok(serde_json::json!(users))
-                }
-                Err(e) => Err(format!("DB Error: {}", e))
-            }
-        }
-        "allow_user" => {
-            if let Some(p) = params.and_then(|p| p.get(0)).and_then(|v| v.as_str()) {
-                let _ = sqlx::query("INSERT INTO users (id, npub, pubkey, \"whitelistStatus\") VALUES ($1, $2, $3, 'ACTIVE') ON CONFLICT (pubkey) DO UPDATE SET \"whitelistStatus\" = 'ACTIVE'")
-                    .bind(nanoid::nanoid!())
-                    .bind(p) // Assuming pubkey passed, not npub for simplicity in this raw API
-                    .bind(p)
-                    .execute(&state.db)
-                    .await;
-                Ok(serde_json::json!(true))
-            } else {
-                Err("Missing pubkey param".to_string())
-            }
-        }
-        "ban_user" => {
-            if let Some(p) = params.and_then(|p| p.get(0)).and_then(|v| v.as_str()) {
-                let _ = sqlx::query("UPDATE users SET \"whitelistStatus\" = 'REVOKED' WHERE pubkey = $1")
-                    .bind(p)
-                    .execute(&state.db)
-                    .await;
-                Ok(serde_json::json!(true))
-            } else {
-                Err("Missing pubkey param".to_string())
-            }
-        }
-        _ => Err("Method not found".to_string())
-    };
+/// A user's public key in both encodings the `users` table stores
+struct UserKey {
+    hex: String,
+    npub: String,
+}
 
-    let response = match result {
-        Ok(res) => serde_json::json!({
-            "jsonrpc": "2.0",
-            "id": id,
-            "result": res
-        }),
-        Err(e) => serde_json::json!({
-            "jsonrpc": "2.0",
-            "id": id,
-            "error": { "code": -32601, "message": e }
-        })
-    };
+/// Accept a public key as either hex or npub
+fn parse_pubkey(input: &str) -> Option<UserKey> {
+    let pk = PublicKey::parse(input.trim()).ok()?;
+    Some(UserKey {
+        hex: pk.to_hex(),
+        npub: pk.to_bech32().ok()?,
+    })
+}
 
-    let _ = sender.send(Message::Text(response.to_string())).await;
+// ============ Redis Cache Helpers ============
+
+/// Where a pubkey stands with the relay, from the `users` table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Standing {
+    Admin,
+    Active,
+    /// Read-only: may still read in private relay mode but not publish
+    Paused,
+    Pending,
+    Revoked,
+    Vanished,
+    PermanentlyBanned,
+    /// No `users` row
+    Unknown,
 }
 
-// NIP-77: Negentropy
-async fn handle_nip77_open(
-    arr: &Vec<serde_json::Value>,
-    state: &Arc<AppState>,
-    sender: &tokio::sync::mpsc::Sender<Message>,
-    sessions: &mut HashMap<String, Negentropy>,
-) {
-    if arr.len() < 5 { return; }
-    let sub_id = arr[1].as_str().unwrap_or("");
-    let filter_val = &arr[2];
-    let _id_len = arr[3].as_u64().unwrap_or(32) as u8; // Not used in this simple impl
-    let initial_msg_hex = arr[4].as_str().unwrap_or("");
-
-    if let Ok(_filter) = serde_json::from_value::<Filter>(filter_val.clone()) {
-        // 1. Fetch ALL event IDs matching filter (Heavy operation!)
-        // In a real impl, this needs optimized DB queries or a separate index
-        let rows = sqlx::query("SELECT \"eventId\" FROM events") // Simplified: Fetch all and filter in memory for now due to complex filter mapping
-            .fetch_all(&state.db)
-            .await;
-        
-        if let Ok(rows) = rows {
-            // Create Negentropy session
-            if let Ok(mut neg) = Negentropy::new(32, Some(100_000)) { // 32 byte ID
-                for row in rows {
-                    let eid: String = row.get("eventId");
-                    // We need to check if this event matches the filter. 
-                    // Since we don't have the full event here, we should ideally query with WHERE clauses.
-                    // For this implementation, we'll assume the DB query handles it (TODO: Map Filter to SQL)
-                    // For now, let's just add them all to test the protocol flow
-                    if let Ok(bytes) = hex::decode(&eid) {
-                         let id_bytes = Bytes::from_slice(&bytes);
-                         let _ = neg.add_item(0, id_bytes);
-                    }
-                }
-                
-                let _ = neg.seal();
-                
-                // Process initial message
-                if let Ok(msg_bytes) = hex::decode(initial_msg_hex) {
-                    let query = Bytes::from_slice(&msg_bytes);
-                    if let Ok(response) = neg.reconcile(&query) {
-                        sessions.insert(sub_id.to_string(), neg);
-                        let _ = sender.send(Message::Text(serde_json::json!(["NEG-MSG", sub_id, response.to_hex()]).to_string())).await;
-                    }
-                }
-            }
+impl Standing {
+    fn from_access(access: Option<UserAccess>) -> Self {
+        let Some(access) = access else {
+            return Standing::Unknown;
+        };
+        if access.permanently_banned {
+            return Standing::PermanentlyBanned;
+        }
+        if access.is_admin {
+            return Standing::Admin;
+        }
+        match access.status {
+            Some(WhitelistStatus::Active) => Standing::Active,
+            Some(WhitelistStatus::Paused) => Standing::Paused,
+            Some(WhitelistStatus::Revoked) => Standing::Revoked,
+            Some(WhitelistStatus::Vanished) => Standing::Vanished,
+            Some(WhitelistStatus::Pending) | None => Standing::Pending,
         }
     }
-}
 
-async fn handle_nip77_msg(
-    arr: &Vec<serde_json::Value>,
-    _state: &Arc<AppState>,
-    sender: &tokio::sync::mpsc::Sender<Message>,
-    sessions: &mut HashMap<String, Negentropy>,
-) {
-    if arr.len() < 3 { return; }
-    let sub_id = arr[1].as_str().unwrap_or("");
-    let msg_hex = arr[2].as_str().unwrap_or("");
-
-    if let Some(neg) = sessions.get_mut(sub_id) {
-        if let Ok(msg_bytes) = hex::decode(msg_hex) {
-            let query = Bytes::from_slice(&msg_bytes);
-            if let Ok(response) = neg.reconcile(&query) {
-                let _ = sender.send(Message::Text(serde_json::json!(["NEG-MSG", sub_id, response.to_hex()]).to_string())).await;
-            }
-        }
+    fn is_admin(self) -> bool {
+        self == Standing::Admin
     }
-}
-async fn handle_nip77_close(
-    arr: &Vec<serde_json::Value>,
-    sessions: &mut HashMap<String, Negentropy>,
-) {
-    if arr.len() < 2 { return; }
-    let sub_id = arr[1].as_str().unwrap_or("");
-    sessions.remove(sub_id);
-}
 
-// ============ Redis Cache Helpers ============
+    fn can_read(self) -> bool {
+        matches!(self, Standing::Admin | Standing::Active | Standing::Paused)
+    }
 
-/// Check if a user is whitelisted (with Redis caching)
-async fn check_whitelist_cached(state: &Arc<AppState>, pubkey: &str) -> (bool, bool) {
-    let cache_key = format!("whitelist:{}", pubkey);
-    
-    // Try Redis cache first
-    if let Some(ref redis_pool) = state.redis {
-        if let Ok(mut conn) = redis_pool.get().await {
-            if let Ok(cached) = conn.get::<_, Option<String>>(&cache_key).await {
-                if let Some(val) = cached {
-                    let parts: Vec<&str> = val.split(':').collect();
-                    if parts.len() == 2 {
-                        let is_admin = parts[0] == "1";
-                        let is_active = parts[1] == "1";
-                        debug!("Whitelist cache HIT for {}: admin={}, active={}", pubkey, is_admin, is_active);
-                        return (is_admin, is_active);
-                    }
-                }
-            }
+    fn can_write(self) -> bool {
+        matches!(self, Standing::Admin | Standing::Active)
+    }
+
+    /// OK/CLOSED reason for a pubkey that may not do what it asked
+    fn denied_reason(self) -> &'static str {
+        match self {
+            Standing::PermanentlyBanned => "blocked: pubkey is permanently banned from this relay",
+            Standing::Revoked => "blocked: whitelist access has been revoked",
+            Standing::Vanished => "blocked: pubkey has asked this relay to forget it",
+            Standing::Paused => "restricted: account is paused and read-only",
+            Standing::Pending => "restricted: whitelist application is pending approval",
+            Standing::Unknown => "blocked: user not whitelisted",
+            Standing::Admin | Standing::Active => "",
         }
     }
-    
-    // Cache miss - query DB
-    let row = sqlx::query(
-        "SELECT \"isAdmin\", \"whitelistStatus\"::text as status FROM users WHERE pubkey = $1"
-    )
-    .bind(pubkey)
-    .fetch_optional(&state.db)
-    .await;
-
-    let (is_admin, is_active) = match row {
-        Ok(Some(row)) => {
-            let admin: bool = row.try_get("isAdmin").unwrap_or(false);
-            let status: Option<String> = row.try_get("status").unwrap_or(None);
-            (admin, status == Some("ACTIVE".to_string()))
-        }
-        _ => (false, false),
-    };
 
-    // Store in Redis cache
-    if let Some(ref redis_pool) = state.redis {
-        if let Ok(mut conn) = redis_pool.get().await {
-            let cache_val = format!("{}:{}", if is_admin { "1" } else { "0" }, if is_active { "1" } else { "0" });
-            let _: Result<(), _> = conn.set_ex(&cache_key, &cache_val, CACHE_TTL_WHITELIST).await;
-            debug!("Whitelist cache SET for {}: {}", pubkey, cache_val);
+    /// Redis cache encoding
+    fn as_str(self) -> &'static str {
+        match self {
+            Standing::Admin => "admin",
+            Standing::Active => "active",
+            Standing::Paused => "paused",
+            Standing::Pending => "pending",
+            Standing::Revoked => "revoked",
+            Standing::Vanished => "vanished",
+            Standing::PermanentlyBanned => "banned",
+            Standing::Unknown => "unknown",
         }
     }
 
-    (is_admin, is_active)
+    fn parse(s: &str) -> Option<Self> {
+        [
+            Standing::Admin,
+            Standing::Active,
+            Standing::Paused,
+            Standing::Pending,
+            Standing::Revoked,
+            Standing::Vanished,
+            Standing::PermanentlyBanned,
+            Standing::Unknown,
+        ]
+        .into_iter()
+        .find(|standing| standing.as_str() == s)
+    }
 }
 
-/// Cache an event in Redis sorted set (by timestamp)
-async fn cache_event(state: &Arc<AppState>, event: &Event) {
-    if let Some(ref redis_pool) = state.redis {
-        if let Ok(mut conn) = redis_pool.get().await {
-            let event_json = event.as_json();
-            let score = event.created_at.as_u64() as f64;
-            
-            // Add to sorted set
-            let _: Result<(), _> = conn.zadd(RECENT_EVENTS_KEY, &event_json, score).await;
-            
-            // Trim to keep only the most recent events (keep last MAX_CACHED_EVENTS)
-            let trim_index: isize = -(MAX_CACHED_EVENTS as isize + 1);
-            let _: Result<(), _> = conn.zremrangebyrank(RECENT_EVENTS_KEY, 0, trim_index).await;
-            
-            // Also cache by event ID for quick lookups
-            let event_key = format!("event:{}", event.id);
-            let _: Result<(), _> = conn.set_ex(&event_key, &event_json, CACHE_TTL_RECENT_EVENTS * 10).await;
+/// A pooled Redis connection, or `None` while Redis is absent, failing, or
+/// switched off by the circuit breaker
+async fn redis_conn(state: &Arc<AppState>) -> Option<deadpool_redis::Connection> {
+    let redis_pool = state.redis.as_ref()?;
+    if !state.breaker.allow() {
+        return None;
+    }
+    let mut conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            state.breaker.failure(&e);
+            return None;
+        }
+    };
+
+    // Catch up on what couldn't be done while Redis was unreachable
+    let pending = state.local.take_pending_invalidations();
+    if !pending.is_empty() {
+        let keys: Vec<String> = pending.iter().map(|pubkey| format!("whitelist:{}", pubkey)).collect();
+        if state.breaker.record(conn.del::<_, ()>(&keys).await).is_err() {
+            pending.iter().for_each(|pubkey| state.local.defer_invalidation(pubkey));
+            return None;
         }
     }
+    if state.cache.is_stale() {
+        cache::spawn_warm(state);
+    }
+    Some(conn)
 }
 
-/// Get recent events from cache matching a filter
-async fn get_cached_events(state: &Arc<AppState>, filter: &Filter, limit: usize) -> Vec<Event> {
-    let mut events = Vec::new();
-    
-    if let Some(ref redis_pool) = state.redis {
-        if let Ok(mut conn) = redis_pool.get().await {
-            // Get events from sorted set (most recent first) using zrevrange
-            let result: Result<Vec<String>, _> = redis::cmd("ZREVRANGE")
-                .arg(RECENT_EVENTS_KEY)
-                .arg(0)
-                .arg(limit as isize - 1)
-                .query_async(&mut conn)
-                .await;
-            
-            if let Ok(cached_events) = result {
-                for event_json in cached_events {
-                    if let Ok(event) = Event::from_json(&event_json) {
-                        if filter.match_event(&event) {
-                            events.push(event);
-                        }
-                    }
+/// Look up a user's standing (with Redis caching, or in-process while Redis is down)
+async fn user_standing(state: &Arc<AppState>, pubkey: &str) -> Standing {
+    let cache_key = format!("whitelist:{}", pubkey);
+
+    let mut conn = redis_conn(state).await;
+    if let Some(ref mut c) = conn {
+        match state.breaker.record(c.get::<_, Option<String>>(&cache_key).await) {
+            Ok(Some(val)) => {
+                if let Some(standing) = Standing::parse(&val) {
+                    debug!("Whitelist cache HIT for {}: {}", pubkey, val);
+                    return standing;
                 }
             }
+            Ok(None) => {}
+            Err(_) => conn = None,
         }
+    } else if let Some(standing) = state.local.standing(pubkey) {
+        debug!("Local whitelist cache HIT for {}: {}", pubkey, standing.as_str());
+        return standing;
     }
-    
-    events
+
+    // Cache miss - query DB. Errors aren't cached.
+    let standing = match state.store.user_access(pubkey).await {
+        Ok(access) => Standing::from_access(access),
+        Err(e) => {
+            error!("Failed to look up user {}: {}", pubkey, e);
+            return Standing::Unknown;
+        }
+    };
+
+    match conn {
+        Some(mut conn) => {
+            let set = conn.set_ex::<_, _, ()>(&cache_key, standing.as_str(), CACHE_TTL_WHITELIST).await;
+            if state.breaker.record(set).is_ok() {
+                debug!("Whitelist cache SET for {}: {}", pubkey, standing.as_str());
+            }
+        }
+        None => state.local.set_standing(pubkey, standing),
+    }
+
+    standing
 }
 
 /// Invalidate whitelist cache for a user
 async fn invalidate_whitelist_cache(state: &Arc<AppState>, pubkey: &str) {
-    if let Some(ref redis_pool) = state.redis {
-        if let Ok(mut conn) = redis_pool.get().await {
+    state.local.forget_standing(pubkey);
+    let cleared = match redis_conn(state).await {
+        Some(mut conn) => {
             let cache_key = format!("whitelist:{}", pubkey);
-            let _: Result<(), _> = conn.del(&cache_key).await;
+            state.breaker.record(conn.del::<_, ()>(&cache_key).await).is_ok()
         }
+        None => state.redis.is_none(),
+    };
+    if !cleared {
+        state.local.defer_invalidation(pubkey);
     }
+    fanout::whitelist_changed(state, pubkey).await;
+}
+
+/// Revoke a user (adding them if the database doesn't know them yet) and stop
+/// cached "active" answers and live sockets from outlasting the ban
+async fn ban_pubkey(state: &Arc<AppState>, user: &UserKey, reason: Option<&str>) -> store::StoreResult<()> {
+    state.store.ban_user(&user.npub, &user.hex, reason).await?;
+    invalidate_whitelist_cache(state, &user.hex).await;
+    fanout::control(state, Control::PubkeyBanned(user.hex.clone())).await;
+    Ok(())
 }
 
 // ============ End Cache Helpers ============
 
-async fn handle_event(event: Event, state: &Arc<AppState>, sender: &tokio::sync::mpsc::Sender<Message>) {
+async fn handle_event(
+    event: Event,
+    state: &Arc<AppState>,
+    sender: &tokio::sync::mpsc::Sender<Message>,
+    auth_pubkeys: &HashSet<String>,
+) {
     info!("Received EVENT from pubkey: {}, kind: {}", event.pubkey, event.kind);
-    
-    // 1. Verify signature
-    if let Err(e) = event.verify() {
-        let _ = sender.send(Message::Text(RelayMessage::ok(event.id, false, format!("Invalid signature: {}", e)).as_json())).await;
+
+    let event_id = event.id;
+    if let Some(reason) = protected_event_denied(&event, auth_pubkeys) {
+        let _ = sender.send(Message::Text(RelayMessage::ok(event_id, false, reason.to_string()).as_json())).await;
         return;
     }
+    if let Some(reason) = write_access_denied(&event, state, auth_pubkeys).await {
+        let _ = sender.send(Message::Text(RelayMessage::ok(event_id, false, reason.to_string()).as_json())).await;
+        return;
+    }
+
+    let (accepted, message) = match process_event(event, state, Origin::Client).await {
+        Ok(Accepted::Stored) => (true, String::new()),
+        Ok(Accepted::Duplicate) => (true, "duplicate: already have this event".to_string()),
+        Err(reason) => (false, reason),
+    };
+    let _ = sender.send(Message::Text(RelayMessage::ok(event_id, accepted, message).as_json())).await;
+}
+
+/// NIP-70: events tagged `["-"]` may only be published by their authenticated author
+fn protected_event_denied(event: &Event, auth_pubkeys: &HashSet<String>) -> Option<&'static str> {
+    let protected = event.tags.iter().any(|tag| {
+        let t = tag.as_vec();
+        t.len() == 1 && t[0] == "-"
+    });
+    if !protected {
+        return None;
+    }
+    if auth_pubkeys.is_empty() {
+        return Some("auth-required: this event may only be published by its author");
+    }
+    if !auth_pubkeys.contains(&event.pubkey.to_string()) {
+        return Some("restricted: this event may only be published by its author");
+    }
+    None
+}
+
+/// Under the write auth policy, why this socket may not publish `event` (`None` if it may).
+/// Without it anyone can republish a member's signed events from any connection.
+async fn write_access_denied(event: &Event, state: &Arc<AppState>, auth_pubkeys: &HashSet<String>) -> Option<&'static str> {
+    if !state.config.write_auth_required {
+        return None;
+    }
+    if auth_pubkeys.is_empty() {
+        return Some("auth-required: authenticate to publish events");
+    }
+    if auth_pubkeys.contains(&event.pubkey.to_string()) {
+        return None;
+    }
+
+    // NIP-26: the delegator may publish events signed by their delegatee
+    let properties = nip26::EventProperties::from_event(event);
+    let delegated = event.tags.iter().any(|tag| {
+        nip26::DelegationTag::try_from(tag.as_vec())
+            .is_ok_and(|d| d.validate(event.pubkey, &properties).is_ok() && auth_pubkeys.contains(&d.delegator_pubkey().to_string()))
+    });
+    if delegated {
+        return None;
+    }
+
+    for pubkey in auth_pubkeys {
+        if user_standing(state, pubkey).await.is_admin() {
+            return None;
+        }
+    }
+    Some("restricted: authenticated pubkey must be the event author")
+}
+
+/// What happened to an event that passed validation
+enum Accepted {
+    Stored,
+    Duplicate,
+}
+
+/// Where an event handed to `process_event` came from
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Origin {
+    /// Published by a client over its socket. Only these count as the author's
+    /// policy violations.
+    Client,
+    /// Fetched from another relay by NIP-77 sync or REQ mirroring, possibly again
+    Upstream,
+    /// Replayed from a backup by `relay-rs import`
+    Import { skip_whitelist: bool },
+}
+
+impl Origin {
+    fn checks_whitelist(self) -> bool {
+        self != Origin::Import { skip_whitelist: true }
+    }
+}
+
+/// Run an event through the relay's acceptance rules, store it and broadcast it.
+/// Shared by client EVENT messages, `relay-rs import`, sync and mirroring; on
+/// rejection returns the reason to put in the OK message.
+async fn process_event(event: Event, state: &Arc<AppState>, origin: Origin) -> Result<Accepted, String> {
+    // 1. Verify signature
+    if event.verify().is_err() {
+        return Err("invalid: bad signature".to_string());
+    }
+
+    // created_at must fit both the bigint column and the web app's timestamp column
+    if store::event_timestamps(&event).is_none() {
+        return Err("invalid: created_at out of range".to_string());
+    }
 
     // NIP-40: Check Expiration
-    let mut expires_at: Option<chrono::NaiveDateTime> = None;
+    let mut expires_at: Option<i64> = None;
     for tag in &event.tags {
         let t = tag.as_vec();
         if t.len() >= 2 && t[0] == "expiration" {
             if let Ok(timestamp) = t[1].parse::<i64>() {
                 let exp = chrono::DateTime::from_timestamp(timestamp, 0).unwrap_or_default();
                 if exp < chrono::Utc::now() {
-                    let _ = sender.send(Message::Text(RelayMessage::ok(event.id, false, "error: event expired".to_string()).as_json())).await;
-                    return;
+                    return Err("error: event expired".to_string());
                 }
-                expires_at = Some(exp.naive_utc());
+                expires_at = Some(timestamp);
             }
         }
     }
 
-    // 2. Check whitelist (with Redis caching)
+    // 2. Check whitelist (with Redis caching). Permanent bans apply even to imports.
     let pubkey_hex = event.pubkey.to_string();
-    let (is_admin, is_active) = check_whitelist_cached(state, &pubkey_hex).await;
-    
-    if !is_admin && !is_active {
-        let _ = sender.send(Message::Text(RelayMessage::ok(event.id, false, "blocked: user not whitelisted".to_string()).as_json())).await;
-        return;
+    let standing = user_standing(state, &pubkey_hex).await;
+    if standing == Standing::PermanentlyBanned || (origin.checks_whitelist() && !standing.can_write()) {
+        return Err(policy_rejection(state, origin, &pubkey_hex, standing, standing.denied_reason().to_string()).await);
     }
 
-    // 3. Handle addressable/replaceable events (NIP-33: kinds 30000-39999)
+    // NIP-86 moderation: banned events and the kind lists. Re-sending a
+    // banned event isn't a new offence, and anyone holding a copy can do it, so it
+    // doesn't count against the author.
+    if state.store.is_event_banned(&event.id.to_hex()).await.unwrap_or(false) {
+        return Err("blocked: event has been banned by the relay".to_string());
+    }
     let kind_num = event.kind.as_u64();
-    if kind_num >= 30000 && kind_num < 40000 {
+    let disallowed = state.store.disallowed_kinds().await.unwrap_or_default().contains(&kind_num) || {
+        let allowed_kinds = state.store.allowed_kinds().await.unwrap_or_default();
+        !allowed_kinds.is_empty() && !allowed_kinds.contains(&kind_num)
+    };
+    if disallowed {
+        let reason = format!("blocked: kind {} is not accepted by this relay", kind_num);
+        return Err(policy_rejection(state, origin, &pubkey_hex, standing, reason).await);
+    }
+
+    // 3. Handle addressable/replaceable events (NIP-33: kinds 30000-39999)
+    if (30000..40000).contains(&kind_num) {
         // Extract d-tag for addressable events
         let d_tag = event.tags.iter()
             .find(|t| {
                 let v = t.as_vec();
-                v.len() >= 1 && v[0] == "d"
+                !v.is_empty() && v[0] == "d"
             })
             .map(|t| t.as_vec().get(1).cloned().unwrap_or_default())
             .unwrap_or_default();
         
         // Delete older events with same pubkey + kind + d-tag
-        let _ = sqlx::query(
-            "DELETE FROM events WHERE pubkey = $1 AND kind = $2 AND 
-             EXISTS (SELECT 1 FROM jsonb_array_elements(tags) AS t 
-                     WHERE t->>0 = 'd' AND (t->>1 = $3 OR ($3 = '' AND (t->>1 IS NULL OR t->>1 = ''))))"
-        )
-        .bind(event.pubkey.to_string())
-        .bind(kind_num as i32)
-        .bind(&d_tag)
-        .execute(&state.db)
-        .await;
+        delete_events(state, Deletion::Address {
+            author: &pubkey_hex,
+            kind: kind_num,
+            identifier: &d_tag,
+        }).await;
         
         info!("Addressable event kind {} with d-tag '{}' - replaced previous version", kind_num, d_tag);
     }
 
     // 4. Save to DB
-    let tags_json = serde_json::to_value(&event.tags).unwrap_or(serde_json::Value::Null);
-    let created_at = chrono::DateTime::from_timestamp(event.created_at.as_u64() as i64, 0)
-        .unwrap_or_default()
-        .naive_utc()
-        .and_utc();
-
-    let insert_result = sqlx::query(
-        "INSERT INTO events (id, \"eventId\", pubkey, kind, content, tags, sig, \"createdAt\", \"receivedAt\", \"expiresAt\") 
-         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, NOW(), $9)
-         ON CONFLICT (\"eventId\") DO NOTHING"
-    )
-    .bind(nanoid::nanoid!())
-    .bind(event.id.to_string())
-    .bind(event.pubkey.to_string())
-    .bind(event.kind.as_u64() as i32)
-    .bind(&event.content)
-    .bind(tags_json)
-    .bind(event.sig.to_string())
-    .bind(created_at)
-    .bind(expires_at)
-    .execute(&state.db)
-    .await;
+    let insert_result = state.store.save_event(&event, expires_at).await;
 
     match insert_result {
-        Ok(_) => {
-            let _ = sender.send(Message::Text(RelayMessage::ok(event.id, true, "".to_string()).as_json())).await;
-            
+        Ok(false) => Ok(Accepted::Duplicate),
+        Ok(true) => {
             // Handle NIP-09: Event Deletion
             if event.kind.as_u64() == 5 {
-                let pubkey = event.pubkey.to_string();
                 for tag in &event.tags {
                     let t = tag.as_vec();
                     if t.len() >= 2 && t[0] == "e" {
                         let target_id = &t[1];
                         // Delete the event if it belongs to the same pubkey
-                        let _ = sqlx::query("DELETE FROM events WHERE \"eventId\" = $1 AND pubkey = $2")
-                            .bind(target_id)
-                            .bind(&pubkey)
-                            .execute(&state.db)
-                            .await;
+                        delete_events(state, Deletion::Event { id: target_id, author: &pubkey_hex }).await;
                     }
                 }
             }
 
             // Handle NIP-62: Request to Vanish
             if event.kind.as_u64() == 62 {
-                let pubkey = event.pubkey.to_string();
-                
                 // 1. Delete all events from this pubkey
-                let _ = sqlx::query("DELETE FROM events WHERE pubkey = $1")
-                    .bind(&pubkey)
-                    .execute(&state.db)
-                    .await;
+                delete_events(state, Deletion::Author(&pubkey_hex)).await;
                 
                 // 2. Update user status to VANISHED
-                let _ = sqlx::query("UPDATE users SET \"whitelistStatus\" = 'VANISHED' WHERE pubkey = $1")
-                    .bind(&pubkey)
-                    .execute(&state.db)
-                    .await;
+                let _ = state.store.set_whitelist_status(&pubkey_hex, WhitelistStatus::Vanished).await;
                 
                 // Invalidate whitelist cache for vanished user
-                invalidate_whitelist_cache(state, &pubkey).await;
+                invalidate_whitelist_cache(state, &pubkey_hex).await;
+            }
+
+            // NIP-56: file reports for the admins
+            if event.kind.as_u64() == moderation::REPORT_KIND {
+                moderation::handle_report(state, &event).await;
             }
 
             // Cache the event in Redis
-            cache_event(state, &event).await;
+            cache::insert(state, &event).await;
 
-            // Broadcast
-            let _ = state.tx.send(event);
+            // Broadcast, here and on any other instances
+            fanout::broadcast(state, event).await;
+            Ok(Accepted::Stored)
         }
+        Err(sqlx::Error::Database(e)) if e.is_foreign_key_violation() => Err(MISSING_AUTHOR.to_string()),
         Err(e) => {
             error!("Failed to save event: {}", e);
-            let _ = sender.send(Message::Text(RelayMessage::ok(event.id, false, "error: internal error".to_string()).as_json())).await;
+            Err("error: internal error".to_string())
+        }
+    }
+}
+
+/// Delete events from the store and the recent-events cache
+async fn delete_events(state: &Arc<AppState>, target: Deletion<'_>) {
+    match state.store.delete(target).await {
+        Ok(removed) => cache::remove_keys(state, &removed).await,
+        Err(e) => error!("Failed to delete events: {}", e),
+    }
+}
+
+/// Count a policy rejection against the author, if they're a user and published
+/// it themselves, and pass the reason on
+async fn policy_rejection(state: &Arc<AppState>, origin: Origin, pubkey: &str, standing: Standing, reason: String) -> String {
+    if origin == Origin::Client && standing != Standing::Unknown {
+        if let Err(e) = state.store.record_violation(pubkey).await {
+            error!("Failed to record violation for {}: {}", pubkey, e);
         }
     }
+    reason
+}
+
+// NIP-04 DMs and NIP-17/59 gift wraps are only served to their author or a p-tagged recipient
+const PRIVATE_KINDS: [u64; 2] = [4, 1059];
+
+fn is_private_kind(kind: Kind) -> bool {
+    PRIVATE_KINDS.contains(&kind.as_u64())
+}
+
+/// Whether a filter explicitly asks for private kinds
+fn requests_private_kinds(filter: &Filter) -> bool {
+    filter.kinds.as_ref().is_some_and(|kinds| kinds.iter().any(|k| is_private_kind(*k)))
+}
+
+/// Whether a filter can only match events authored by or addressed to `pubkeys`
+fn filter_scoped_to(filter: &Filter, pubkeys: &HashSet<String>) -> bool {
+    let only_authors = filter.authors.as_ref()
+        .is_some_and(|a| !a.is_empty() && a.iter().all(|a| pubkeys.contains(&a.to_string())));
+    let only_recipient = filter.generic_tags.iter().any(|(tag, values)| {
+        tag.to_string() == "p" && !values.is_empty() && values.iter().all(|v| pubkeys.contains(&v.to_string()))
+    });
+    only_authors || only_recipient
+}
+
+/// Whether an event may be sent to a socket authenticated as `auth_pubkeys`
+fn can_read_event(event: &Event, auth_pubkeys: &HashSet<String>) -> bool {
+    if !is_private_kind(event.kind) {
+        return true;
+    }
+    if auth_pubkeys.contains(&event.pubkey.to_string()) {
+        return true;
+    }
+    event.tags.iter().any(|tag| {
+        let t = tag.as_vec();
+        t.len() >= 2 && t[0] == "p" && auth_pubkeys.contains(&t[1])
+    })
+}
+
+/// In auth-required mode, why this socket may not read yet (`None` if it may).
+/// Any authenticated pubkey that is an admin or an active or paused member grants access.
+async fn read_access_denied(state: &Arc<AppState>, auth_pubkeys: &HashSet<String>) -> Option<&'static str> {
+    if !state.config.auth_required {
+        return None;
+    }
+    if auth_pubkeys.is_empty() {
+        return Some("auth-required: this relay is only readable by its members");
+    }
+    let mut reason = "restricted: authenticated pubkey is not a whitelisted member";
+    for pubkey in auth_pubkeys {
+        let standing = user_standing(state, pubkey).await;
+        if standing.can_read() {
+            return None;
+        }
+        if standing != Standing::Unknown {
+            reason = standing.denied_reason();
+        }
+    }
+    Some(reason)
 }
 
 async fn handle_req(
@@ -800,113 +1131,61 @@ async fn handle_req(
     state: &Arc<AppState>,
     subscriptions: &mut HashMap<String, Vec<Filter>>,
     sender: &tokio::sync::mpsc::Sender<Message>,
+    auth_pubkeys: &HashSet<String>,
 ) {
     info!("Received REQ sub_id: {}, filters: {:?}", sub_id, filters);
-    subscriptions.insert(sub_id.to_string(), filters.clone());
 
-    // Build SQL query based on filters
-    let mut sql = String::from("SELECT \"eventId\", pubkey, kind, content, tags, sig, \"createdAt\" FROM events WHERE (\"expiresAt\" IS NULL OR \"expiresAt\" > NOW())");
-    let mut params: Vec<String> = Vec::new();
-    let mut param_count = 1;
-    
-    // Take the first filter (most clients send one filter per REQ)
-    if let Some(filter) = filters.first() {
-        // Filter by kinds
-        if let Some(kinds) = &filter.kinds {
-            let kind_list: Vec<String> = kinds.iter().map(|k| k.as_u64().to_string()).collect();
-            if !kind_list.is_empty() {
-                sql.push_str(&format!(" AND kind IN ({})", kind_list.join(",")));
-            }
-        }
-        
-        // Filter by authors
-        if let Some(authors) = &filter.authors {
-            if !authors.is_empty() {
-                let author_list: Vec<String> = authors.iter().map(|a| format!("'{}'", a)).collect();
-                sql.push_str(&format!(" AND pubkey IN ({})", author_list.join(",")));
-            }
-        }
-        
-        // Filter by since
-        if let Some(since) = filter.since {
-            sql.push_str(&format!(" AND EXTRACT(EPOCH FROM \"createdAt\") >= {}", since.as_u64()));
-        }
-        
-        // Filter by until
-        if let Some(until) = filter.until {
-            sql.push_str(&format!(" AND EXTRACT(EPOCH FROM \"createdAt\") <= {}", until.as_u64()));
-        }
+    if let Some(reason) = read_access_denied(state, auth_pubkeys).await {
+        subscriptions.remove(&sub_id.to_string());
+        let _ = sender.send(Message::Text(RelayMessage::closed(sub_id, reason).as_json())).await;
+        return;
     }
-    
-    // Order and limit
-    sql.push_str(" ORDER BY \"createdAt\" DESC");
-    if let Some(filter) = filters.first() {
-        if let Some(limit) = filter.limit {
-            sql.push_str(&format!(" LIMIT {}", limit.min(500))); // Cap at 500
-        } else {
-            sql.push_str(" LIMIT 100");
-        }
-    } else {
-        sql.push_str(" LIMIT 100");
+
+    if auth_pubkeys.is_empty() && filters.iter().any(requests_private_kinds) {
+        subscriptions.remove(&sub_id.to_string());
+        let _ = sender.send(Message::Text(RelayMessage::closed(sub_id, "auth-required: authenticate to read direct messages").as_json())).await;
+        return;
     }
-    
-    debug!("Executing query: {}", sql);
-    
-    let rows = sqlx::query(&sql)
-    .fetch_all(&state.db)
-    .await;
 
-    match rows {
-        Ok(rows) => {
-            info!("handle_req: Found {} events in DB for sub_id: {}", rows.len(), sub_id);
-            let mut sent_count = 0;
-            for row in rows {
-                let event_id: String = row.get("eventId");
-                let pubkey: String = row.get("pubkey");
-                let kind: i32 = row.get("kind");
-                let content: String = row.get("content");
-                let tags_val: serde_json::Value = row.get("tags");
-                let sig: String = row.get("sig");
-                let created_at: chrono::NaiveDateTime = row.get("createdAt");
-                let created_at_utc = chrono::DateTime::<chrono::Utc>::from_naive_utc_and_offset(created_at, chrono::Utc);
-
-                let tags: Vec<Tag> = serde_json::from_value(tags_val).unwrap_or_default();
-                
-                let event_json = serde_json::json!({
-                    "id": event_id,
-                    "pubkey": pubkey,
-                    "created_at": created_at_utc.timestamp(),
-                    "kind": kind,
-                    "tags": tags,
-                    "content": content,
-                    "sig": sig
-                });
-                
-                if let Ok(event) = Event::from_json(&event_json.to_string()) {
-                    // Check if it matches filters
-                    let mut matched = false;
-                    for filter in &filters {
-                        if filter.match_event(&event) {
-                            matched = true;
-                            break;
-                        }
-                    }
-                    
-                    if matched {
-                        sent_count += 1;
-                        info!("handle_req: Sending event {} (kind: {}) to sub_id: {}", event_id, kind, sub_id);
-                        let _ = sender.send(Message::Text(RelayMessage::event(sub_id.clone(), event).as_json())).await;
+    subscriptions.insert(sub_id.to_string(), filters.clone());
+
+    let mut events: Vec<Event> = Vec::new();
+    let mut seen: HashSet<nostr::EventId> = HashSet::new();
+
+    for filter in &filters {
+        let limit = filter.limit.map(|l| l.min(500)).unwrap_or(100); // Cap at 500
+        let found = match cache::query(state, filter, limit).await {
+            Some(events) => Ok(events),
+            None => state.store.query(filter, limit).await.inspect(|found| cache::remember(state, found)),
+        };
+        match found {
+            Ok(found) => {
+                for event in found {
+                    if seen.insert(event.id) {
+                        events.push(event);
                     }
                 }
             }
-            info!("handle_req: Sent {} matching events for sub_id: {}, sending EOSE", sent_count, sub_id);
-            let _ = sender.send(Message::Text(RelayMessage::eose(sub_id).as_json())).await;
-        }
-        Err(e) => {
-            error!("Failed to query events: {}", e);
-            let _ = sender.send(Message::Text(RelayMessage::notice(format!("Failed to query events: {}", e)).as_json())).await;
+            Err(e) => {
+                error!("Failed to query events: {}", e);
+                let _ = sender.send(Message::Text(RelayMessage::notice(format!("Failed to query events: {}", e)).as_json())).await;
+                return;
+            }
         }
     }
+
+    // Newest first across all filters, minus DMs meant for someone else
+    events.retain(|e| can_read_event(e, auth_pubkeys));
+    events.sort_by_key(|e| std::cmp::Reverse(e.created_at));
+
+    info!("handle_req: Found {} events in DB for sub_id: {}", events.len(), sub_id);
+    let sent_count = events.len();
+    for event in events {
+        debug!("handle_req: Sending event {} (kind: {}) to sub_id: {}", event.id, event.kind, sub_id);
+        let _ = sender.send(Message::Text(RelayMessage::event(sub_id.clone(), event).as_json())).await;
+    }
+    info!("handle_req: Sent {} matching events for sub_id: {}, sending EOSE", sent_count, sub_id);
+    let _ = sender.send(Message::Text(RelayMessage::eose(sub_id).as_json())).await;
 }
 
 // Handle REQ with prefix searches (short author pubkeys)
@@ -914,10 +1193,16 @@ async fn handle_prefix_search_req(
     sub_id: SubscriptionId,
     filter: serde_json::Map<String, serde_json::Value>,
     state: &Arc<AppState>,
-    subscriptions: &mut HashMap<String, Vec<Filter>>,
+    _subscriptions: &mut HashMap<String, Vec<Filter>>,
     sender: &tokio::sync::mpsc::Sender<Message>,
+    auth_pubkeys: &HashSet<String>,
 ) {
     info!("Received REQ with potential prefix search, sub_id: {}", sub_id);
+
+    if let Some(reason) = read_access_denied(state, auth_pubkeys).await {
+        let _ = sender.send(Message::Text(RelayMessage::closed(sub_id, reason).as_json())).await;
+        return;
+    }
     
     // Extract filter components
     let kinds: Vec<i32> = filter.get("kinds")
@@ -934,79 +1219,22 @@ async fn handle_prefix_search_req(
     let until = filter.get("until").and_then(|v| v.as_i64());
     let limit = filter.get("limit").and_then(|v| v.as_i64()).unwrap_or(100);
     
-    // Build SQL query with prefix support
-    let mut query = String::from(
-        "SELECT \"eventId\", pubkey, kind, content, tags, sig, \"createdAt\" FROM events WHERE (\"expiresAt\" IS NULL OR \"expiresAt\" > NOW())"
-    );
-    
-    // Add kinds filter
-    if !kinds.is_empty() {
-        let kinds_str = kinds.iter().map(|k| k.to_string()).collect::<Vec<_>>().join(",");
-        query.push_str(&format!(" AND kind IN ({})", kinds_str));
-    }
-    
-    // Add authors filter with prefix support
-    if !authors.is_empty() {
-        query.push_str(" AND (");
-        let author_conditions: Vec<String> = authors.iter().map(|author| {
-            if author.len() == 64 {
-                // Full pubkey - exact match
-                format!("pubkey = '{}'", author)
-            } else {
-                // Prefix - use LIKE
-                format!("pubkey LIKE '{}%'", author)
-            }
-        }).collect();
-        query.push_str(&author_conditions.join(" OR "));
-        query.push_str(")");
-    }
-    
-    // Add time filters
-    if let Some(since_ts) = since {
-        query.push_str(&format!(" AND EXTRACT(EPOCH FROM \"createdAt\") >= {}", since_ts));
-    }
-    if let Some(until_ts) = until {
-        query.push_str(&format!(" AND EXTRACT(EPOCH FROM \"createdAt\") <= {}", until_ts));
+    if auth_pubkeys.is_empty() && kinds.iter().any(|k| PRIVATE_KINDS.contains(&(*k as u64))) {
+        let _ = sender.send(Message::Text(RelayMessage::closed(sub_id, "auth-required: authenticate to read direct messages").as_json())).await;
+        return;
     }
-    
-    query.push_str(&format!(" ORDER BY \"createdAt\" DESC LIMIT {}", limit));
-    
-    debug!("Prefix search query: {}", query);
-    
-    // Execute query
-    let rows = sqlx::query(&query).fetch_all(&state.db).await;
+
+    let query = PrefixQuery { kinds, authors, since, until, limit };
+    let rows = state.store.query_prefix(&query).await;
     
     match rows {
-        Ok(rows) => {
-            info!("Found {} events for prefix search sub_id: {}", rows.len(), sub_id);
-            let mut sent_count = 0;
+        Ok(mut events) => {
+            events.retain(|e| can_read_event(e, auth_pubkeys));
+            info!("Found {} events for prefix search sub_id: {}", events.len(), sub_id);
+            let sent_count = events.len();
             
-            for row in rows {
-                let event_id: String = row.get("eventId");
-                let pubkey: String = row.get("pubkey");
-                let kind: i32 = row.get("kind");
-                let content: String = row.get("content");
-                let tags_val: serde_json::Value = row.get("tags");
-                let sig: String = row.get("sig");
-                let created_at: chrono::NaiveDateTime = row.get("createdAt");
-                let created_at_utc = chrono::DateTime::<chrono::Utc>::from_naive_utc_and_offset(created_at, chrono::Utc);
-
-                let tags: Vec<Tag> = serde_json::from_value(tags_val).unwrap_or_default();
-                
-                let event_json = serde_json::json!({
-                    "id": event_id,
-                    "pubkey": pubkey,
-                    "created_at": created_at_utc.timestamp(),
-                    "kind": kind,
-                    "tags": tags,
-                    "content": content,
-                    "sig": sig
-                });
-                
-                if let Ok(event) = Event::from_json(&event_json.to_string()) {
-                    sent_count += 1;
-                    let _ = sender.send(Message::Text(RelayMessage::event(sub_id.clone(), event).as_json())).await;
-                }
+            for event in events {
+                let _ = sender.send(Message::Text(RelayMessage::event(sub_id.clone(), event).as_json())).await;
             }
             
             info!("Sent {} events for prefix search sub_id: {}, sending EOSE", sent_count, sub_id);
@@ -1019,3 +1247,431 @@ async fn handle_prefix_search_req(
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::{Timestamp, Url};
+    use serde_json::json;
+
+    pub const RELAY_URL: &str = "wss://relay.test";
+
+    /// Settings for a relay at RELAY_URL with every optional policy off
+    pub fn config() -> RelayConfig {
+        let mut config = RelayConfig::from_env();
+        config.relay_urls = vec![RELAY_URL.to_string()];
+        config.auth_required = false;
+        config.write_auth_required = false;
+        config.publish_audit = false;
+        config.sync_peers.clear();
+        config.mirror_sources.clear();
+        config.redis_fanout = false;
+        config
+    }
+
+    /// A relay on an in-memory SQLite store, without Redis
+    pub async fn state_with(config: RelayConfig) -> Arc<AppState> {
+        state_on(config, store::SqliteStore::connect("sqlite::memory:").await.unwrap())
+    }
+
+    /// A relay on `store`, without Redis
+    pub fn state_on(config: RelayConfig, store: impl EventStore + 'static) -> Arc<AppState> {
+        Arc::new(AppState {
+            config,
+            store: Arc::new(store),
+            tx: broadcast::channel(100).0,
+            control: broadcast::channel(100).0,
+            redis: None,
+            keys: Some(Keys::generate()),
+            mirror_stats: Arc::default(),
+            fanout: Arc::default(),
+            cache: Arc::default(),
+            local: Arc::default(),
+            breaker: Arc::default(),
+        })
+    }
+
+    pub async fn state() -> Arc<AppState> {
+        state_with(config()).await
+    }
+
+    /// Messages the relay has queued for a socket so far, as JSON arrays
+    fn sent(rx: &mut tokio::sync::mpsc::Receiver<Message>) -> Vec<serde_json::Value> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .filter_map(|msg| match msg {
+                Message::Text(text) => serde_json::from_str(&text).ok(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    struct AuthSocket {
+        state: Arc<AppState>,
+        challenge: String,
+        auth_pubkeys: HashSet<String>,
+        tx: tokio::sync::mpsc::Sender<Message>,
+        rx: tokio::sync::mpsc::Receiver<Message>,
+    }
+
+    impl AuthSocket {
+        async fn new() -> Self {
+            let (tx, rx) = tokio::sync::mpsc::channel(100);
+            Self { state: state().await, challenge: "challenge-1".to_string(), auth_pubkeys: HashSet::new(), tx, rx }
+        }
+
+        fn auth_event(&self, keys: &Keys, challenge: &str, relay: &str, created_at: Timestamp) -> Event {
+            EventBuilder::auth(challenge, Url::parse(relay).unwrap())
+                .custom_created_at(created_at)
+                .to_event(keys)
+                .unwrap()
+        }
+
+        /// Send AUTH and return the OK's accepted flag and message, plus any new challenge
+        async fn send(&mut self, event: Event) -> (bool, String, Option<String>) {
+            let msg = ClientMessage::auth(event);
+            handle_client_message(msg, &self.state, &mut HashMap::new(), &self.tx, &mut self.challenge, &mut self.auth_pubkeys).await;
+            let replies = sent(&mut self.rx);
+            let ok = &replies[0];
+            assert_eq!(ok[0], "OK");
+            let challenge = replies.get(1).map(|auth| {
+                assert_eq!(auth[0], "AUTH");
+                auth[1].as_str().unwrap().to_string()
+            });
+            (ok[2].as_bool().unwrap(), ok[3].as_str().unwrap().to_string(), challenge)
+        }
+    }
+
+    #[tokio::test]
+    async fn auth_accepts_a_fresh_event_for_this_relay_and_rotates_the_challenge() {
+        let mut socket = AuthSocket::new().await;
+        let keys = Keys::generate();
+        let event = socket.auth_event(&keys, "challenge-1", &format!("{}/", RELAY_URL), Timestamp::now());
+
+        let (accepted, _, next) = socket.send(event).await;
+        assert!(accepted);
+        assert!(socket.auth_pubkeys.contains(&keys.public_key().to_string()));
+        let next = next.expect("a new challenge after a successful AUTH");
+        assert_ne!(next, "challenge-1");
+        assert_eq!(socket.challenge, next);
+    }
+
+    #[tokio::test]
+    async fn auth_rejects_another_relay() {
+        let mut socket = AuthSocket::new().await;
+        let event = socket.auth_event(&Keys::generate(), "challenge-1", "wss://other.relay", Timestamp::now());
+
+        let (accepted, message, next) = socket.send(event).await;
+        assert!(!accepted);
+        assert!(message.contains("relay"), "{}", message);
+        assert!(next.is_none());
+        assert!(socket.auth_pubkeys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn auth_rejects_stale_and_future_events() {
+        let mut socket = AuthSocket::new().await;
+        let now = Timestamp::now().as_u64();
+        let skew = config::AUTH_MAX_SKEW_SECS as u64 + 60;
+        for created_at in [now - skew, now + skew] {
+            let event = socket.auth_event(&Keys::generate(), "challenge-1", RELAY_URL, Timestamp::from(created_at));
+            let (accepted, message, _) = socket.send(event).await;
+            assert!(!accepted);
+            assert!(message.contains("too old"), "{}", message);
+        }
+        assert!(socket.auth_pubkeys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn auth_rejects_a_used_or_wrong_challenge() {
+        let mut socket = AuthSocket::new().await;
+        let (first, second) = (Keys::generate(), Keys::generate());
+        let (accepted, _, _) = socket.send(socket.auth_event(&first, "challenge-1", RELAY_URL, Timestamp::now())).await;
+        assert!(accepted);
+
+        // The challenge was rotated, so replaying it for another key fails
+        let (accepted, message, _) = socket.send(socket.auth_event(&second, "challenge-1", RELAY_URL, Timestamp::now())).await;
+        assert!(!accepted);
+        assert!(message.contains("challenge"), "{}", message);
+
+        let (accepted, _, _) = socket.send(socket.auth_event(&second, "made-up", RELAY_URL, Timestamp::now())).await;
+        assert!(!accepted);
+        assert_eq!(socket.auth_pubkeys, pubkeys(&[&first]));
+    }
+
+    fn pubkeys(keys: &[&Keys]) -> HashSet<String> {
+        keys.iter().map(|k| k.public_key().to_string()).collect()
+    }
+
+    fn event(keys: &Keys, kind: u64, tags: Vec<Vec<String>>) -> Event {
+        let tags: Vec<Tag> = tags.into_iter().map(|t| Tag::parse(t).unwrap()).collect();
+        EventBuilder::new(Kind::from(kind), "", tags).to_event(keys).unwrap()
+    }
+
+    fn p_tag(keys: &Keys) -> Vec<String> {
+        vec!["p".to_string(), keys.public_key().to_hex()]
+    }
+
+    /// Give `keys` a `users` row with `status`
+    async fn member(state: &Arc<AppState>, keys: &Keys, status: WhitelistStatus) {
+        let (hex, npub) = (keys.public_key().to_hex(), keys.public_key().to_bech32().unwrap());
+        state.store.ensure_user(&npub, &hex, status, "test").await.unwrap();
+    }
+
+    /// Handle one message on a socket authenticated as `auth`, returning what it sent back
+    async fn reply(state: &Arc<AppState>, msg: ClientMessage, auth: &HashSet<String>) -> Vec<serde_json::Value> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+        let mut auth = auth.clone();
+        handle_client_message(msg, state, &mut HashMap::new(), &tx, &mut "challenge".to_string(), &mut auth).await;
+        sent(&mut rx)
+    }
+
+    #[test]
+    fn private_events_are_readable_by_author_and_recipients_only() {
+        let (author, recipient, outsider) = (Keys::generate(), Keys::generate(), Keys::generate());
+        let dm = event(&author, 4, vec![p_tag(&recipient)]);
+        let gift_wrap = event(&author, 1059, vec![p_tag(&recipient)]);
+        let note = event(&author, 1, vec![p_tag(&recipient)]);
+
+        let cases = [
+            (&dm, pubkeys(&[&author]), true),
+            (&dm, pubkeys(&[&recipient]), true),
+            (&dm, pubkeys(&[&outsider]), false),
+            (&dm, pubkeys(&[&outsider, &recipient]), true),
+            (&dm, pubkeys(&[]), false),
+            (&gift_wrap, pubkeys(&[&recipient]), true),
+            (&gift_wrap, pubkeys(&[&outsider]), false),
+            (&note, pubkeys(&[]), true),
+        ];
+        for (i, (event, auth, readable)) in cases.iter().enumerate() {
+            assert_eq!(can_read_event(event, auth), *readable, "case {}", i);
+        }
+    }
+
+    #[test]
+    fn protected_events_need_their_authenticated_author() {
+        let (author, other) = (Keys::generate(), Keys::generate());
+        let protected = event(&author, 1, vec![vec!["-".to_string()]]);
+        let unprotected = event(&author, 1, vec![]);
+
+        assert!(protected_event_denied(&protected, &pubkeys(&[])).is_some_and(|r| r.starts_with("auth-required:")));
+        assert!(protected_event_denied(&protected, &pubkeys(&[&other])).is_some_and(|r| r.starts_with("restricted:")));
+        assert_eq!(protected_event_denied(&protected, &pubkeys(&[&author])), None);
+        assert_eq!(protected_event_denied(&protected, &pubkeys(&[&other, &author])), None);
+        assert_eq!(protected_event_denied(&unprotected, &pubkeys(&[])), None);
+    }
+
+    #[test]
+    fn filters_must_be_scoped_to_the_reader() {
+        let (me, outsider) = (Keys::generate(), Keys::generate());
+        let auth = pubkeys(&[&me]);
+        let dms = || Filter::new().kind(Kind::EncryptedDirectMessage);
+
+        let cases = [
+            (dms().author(me.public_key()), true),
+            (dms().pubkey(me.public_key()), true),
+            (dms(), false),
+            (dms().author(outsider.public_key()), false),
+            (dms().pubkey(outsider.public_key()), false),
+            (dms().authors([me.public_key(), outsider.public_key()]), false),
+            (dms().pubkeys([me.public_key(), outsider.public_key()]), false),
+            (dms().authors(Vec::<PublicKey>::new()), false),
+            (dms().pubkeys(Vec::<PublicKey>::new()), false),
+            // Either scope is enough on its own
+            (dms().author(outsider.public_key()).pubkey(me.public_key()), true),
+        ];
+        for (i, (filter, scoped)) in cases.iter().enumerate() {
+            assert_eq!(filter_scoped_to(filter, &auth), *scoped, "case {}", i);
+        }
+        assert!(!filter_scoped_to(&dms().author(me.public_key()), &HashSet::new()));
+    }
+
+    #[tokio::test]
+    async fn negentropy_over_private_kinds_must_be_scoped_to_the_reader() {
+        let state = state().await;
+        let (me, outsider) = (Keys::generate(), Keys::generate());
+        let dm = event(&outsider, 4, vec![p_tag(&outsider)]);
+        state.store.save_event(&dm, None).await.unwrap();
+
+        let neg_open = |filter: serde_json::Value, auth: HashSet<String>| {
+            let state = state.clone();
+            async move {
+                let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+                let mut sessions = nip77::Sessions::default();
+                let initial = {
+                    let mut neg = negentropy::Negentropy::new(32, None).unwrap();
+                    neg.seal().unwrap();
+                    neg.initiate().unwrap().to_hex()
+                };
+                let arr = [json!("NEG-OPEN"), json!("neg"), filter, json!(initial)];
+                nip77::handle_open(&arr, &state, &tx, &mut sessions, &auth).await;
+                let reply = sent(&mut rx).remove(0);
+                (reply[0].as_str().unwrap().to_string(), reply[2].as_str().unwrap_or_default().to_string())
+            }
+        };
+        let me_hex = me.public_key().to_hex();
+
+        let (kind, reason) = neg_open(json!({"kinds": [4]}), pubkeys(&[])).await;
+        assert_eq!(kind, "NEG-ERR");
+        assert!(reason.starts_with("auth-required:"), "{}", reason);
+        let (kind, reason) = neg_open(json!({"kinds": [1059]}), pubkeys(&[&me])).await;
+        assert_eq!(kind, "NEG-ERR");
+        assert!(reason.starts_with("restricted:"), "{}", reason);
+        let (kind, _) = neg_open(json!({"kinds": [4], "#p": [me_hex]}), pubkeys(&[&me])).await;
+        assert_eq!(kind, "NEG-MSG");
+    }
+
+    #[tokio::test]
+    async fn cli_ban_downgrades_live_sessions_and_clears_cached_standing() {
+        let state = state().await;
+        let known = Keys::generate();
+        let known_hex = known.public_key().to_hex();
+        let known_npub = known.public_key().to_bech32().unwrap();
+        state.store.allow_user(&known_npub, &known_hex, None).await.unwrap();
+        assert_eq!(user_standing(&state, &known_hex).await, Standing::Active);
+        assert_eq!(state.local.standing(&known_hex), Some(Standing::Active));
+
+        let mut control_rx = state.control.subscribe();
+        let ban = |user: String| cli::Command::Admin { action: cli::AdminAction::Ban { user } };
+        cli::run(ban(known_npub), state.clone()).await.unwrap();
+
+        match control_rx.try_recv().unwrap() {
+            Control::PubkeyBanned(pubkey) => assert_eq!(pubkey, known_hex),
+            other => panic!("unexpected control message {:?}", other),
+        }
+        assert_eq!(state.local.standing(&known_hex), None);
+        assert_eq!(user_standing(&state, &known_hex).await, Standing::Revoked);
+
+        // Like banpubkey, banning someone the database doesn't know yet adds them
+        let unknown_hex = Keys::generate().public_key().to_hex();
+        cli::run(ban(unknown_hex.clone()), state.clone()).await.unwrap();
+        assert_eq!(user_standing(&state, &unknown_hex).await, Standing::Revoked);
+    }
+
+    #[tokio::test]
+    async fn auth_required_mode_only_lets_members_read() {
+        let mut config = config();
+        config.auth_required = true;
+        let state = state_with(config).await;
+        let (active, paused, pending, revoked, stranger) =
+            (Keys::generate(), Keys::generate(), Keys::generate(), Keys::generate(), Keys::generate());
+        member(&state, &active, WhitelistStatus::Active).await;
+        member(&state, &paused, WhitelistStatus::Paused).await;
+        member(&state, &pending, WhitelistStatus::Pending).await;
+        member(&state, &revoked, WhitelistStatus::Revoked).await;
+
+        let outcome = |auth: HashSet<String>| {
+            let state = state.clone();
+            async move {
+                let req = ClientMessage::req(SubscriptionId::new("sub"), vec![Filter::new()]);
+                let count = ClientMessage::count(SubscriptionId::new("count"), vec![Filter::new()]);
+                let (req, count) = (reply(&state, req, &auth).await, reply(&state, count, &auth).await);
+                let [req, count] = [&req[0], &count[0]].map(|reply| match reply[0].as_str().unwrap() {
+                    "CLOSED" => reply[2].as_str().unwrap().to_string(),
+                    _ => "allowed".to_string(),
+                });
+                assert_eq!(req, count, "REQ and COUNT disagree");
+                req
+            }
+        };
+
+        assert!(outcome(pubkeys(&[])).await.starts_with("auth-required:"));
+        assert!(outcome(pubkeys(&[&stranger])).await.starts_with("restricted:"));
+        assert!(outcome(pubkeys(&[&pending])).await.starts_with("restricted:"));
+        assert_eq!(outcome(pubkeys(&[&revoked])).await, Standing::Revoked.denied_reason());
+        assert_eq!(outcome(pubkeys(&[&active])).await, "allowed");
+        // Paused members keep read access
+        assert_eq!(outcome(pubkeys(&[&paused])).await, "allowed");
+        // One readable pubkey among several is enough
+        assert_eq!(outcome(pubkeys(&[&stranger, &active])).await, "allowed");
+    }
+
+    #[tokio::test]
+    async fn write_auth_requires_the_author_a_delegator_or_an_admin() {
+        let mut config = config();
+        config.write_auth_required = true;
+        let state = state_with(config).await;
+        let (author, other, admin, delegator) = (Keys::generate(), Keys::generate(), Keys::generate(), Keys::generate());
+        member(&state, &author, WhitelistStatus::Active).await;
+        member(&state, &admin, WhitelistStatus::Active).await;
+        state.store.set_admin(&admin.public_key().to_hex(), true).await.unwrap();
+
+        let note = event(&author, 1, vec![]);
+        let denied = |auth: HashSet<String>| {
+            let (note, state) = (note.clone(), state.clone());
+            async move { write_access_denied(&note, &state, &auth).await }
+        };
+        assert!(denied(pubkeys(&[])).await.is_some_and(|r| r.starts_with("auth-required:")));
+        assert!(denied(pubkeys(&[&other])).await.is_some_and(|r| r.starts_with("restricted:")));
+        assert_eq!(denied(pubkeys(&[&author])).await, None);
+        assert_eq!(denied(pubkeys(&[&other, &author])).await, None);
+        assert_eq!(denied(pubkeys(&[&admin])).await, None);
+
+        // NIP-26: the delegator may publish what they delegated, within its conditions
+        let conditions = "kind=1".parse().unwrap();
+        let delegation = nip26::DelegationTag::new(&delegator, author.public_key(), conditions).unwrap();
+        let tag: Vec<String> = serde_json::from_str(&delegation.as_json()).unwrap();
+        let delegated = event(&author, 1, vec![tag.clone()]);
+        let outside_conditions = event(&author, 7, vec![tag]);
+        assert_eq!(write_access_denied(&delegated, &state, &pubkeys(&[&delegator])).await, None);
+        assert!(write_access_denied(&outside_conditions, &state, &pubkeys(&[&delegator])).await.is_some());
+
+        // The whole EVENT path answers with the same reason
+        let ok = reply(&state, ClientMessage::event(note.clone()), &pubkeys(&[])).await;
+        assert_eq!(ok[0][2], false);
+        assert!(ok[0][3].as_str().unwrap().starts_with("auth-required:"));
+        let ok = reply(&state, ClientMessage::event(note), &pubkeys(&[&author])).await;
+        assert_eq!(ok[0][2], true, "{}", ok[0][3]);
+    }
+
+    #[tokio::test]
+    async fn paused_and_permanently_banned_authors_are_refused() {
+        // A second connection to the same file sets what only the web app writes
+        let path = std::env::temp_dir().join(format!("relay-rs-enforcement-{}.db", nanoid::nanoid!()));
+        let url = format!("sqlite://{}", path.display());
+        let state = state_on(config(), store::SqliteStore::connect(&url).await.unwrap());
+        let db = sqlx::SqlitePool::connect(&url).await.unwrap();
+        let violations = |keys: &Keys| {
+            let (db, hex) = (db.clone(), keys.public_key().to_hex());
+            async move {
+                sqlx::query_scalar::<_, i64>("SELECT blacklist_violations FROM users WHERE pubkey = ?")
+                    .bind(hex)
+                    .fetch_one(&db)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let (paused, banned) = (Keys::generate(), Keys::generate());
+        member(&state, &paused, WhitelistStatus::Paused).await;
+        member(&state, &banned, WhitelistStatus::Active).await;
+        state.store.set_admin(&banned.public_key().to_hex(), true).await.unwrap();
+        sqlx::query("UPDATE users SET permanently_banned = 1 WHERE pubkey = ?")
+            .bind(banned.public_key().to_hex())
+            .execute(&db)
+            .await
+            .unwrap();
+
+        // Paused members can't publish, and each attempt counts against them
+        let ok = reply(&state, ClientMessage::event(event(&paused, 1, vec![])), &pubkeys(&[&paused])).await;
+        assert_eq!(ok[0][2], false);
+        assert_eq!(ok[0][3], Standing::Paused.denied_reason());
+        assert_eq!(violations(&paused).await, 1);
+        // Copies arriving from elsewhere are refused without counting
+        let upstream = process_event(event(&paused, 1, vec![]), &state, Origin::Upstream).await;
+        assert_eq!(upstream.err().as_deref(), Some(Standing::Paused.denied_reason()));
+        assert_eq!(violations(&paused).await, 1);
+        // An import that skips the whitelist still takes them
+        let import = process_event(event(&paused, 1, vec![]), &state, Origin::Import { skip_whitelist: true }).await;
+        assert!(import.is_ok());
+
+        // A permanent ban outranks admin rights and applies to imports too
+        let ok = reply(&state, ClientMessage::event(event(&banned, 1, vec![])), &pubkeys(&[&banned])).await;
+        assert_eq!(ok[0][3], Standing::PermanentlyBanned.denied_reason());
+        assert_eq!(violations(&banned).await, 1);
+        let import = process_event(event(&banned, 1, vec![]), &state, Origin::Import { skip_whitelist: true }).await;
+        assert_eq!(import.err().as_deref(), Some(Standing::PermanentlyBanned.denied_reason()));
+        assert_eq!(violations(&banned).await, 1);
+
+        db.close().await;
+        let _ = std::fs::remove_file(&path);
+    }
+}
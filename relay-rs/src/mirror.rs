@@ -0,0 +1,411 @@
+// REQ mirroring: long-lived subscriptions on upstream relays
+//
+// Each source gets a task that keeps a REQ open and runs every event it sends
+// through `process_event`. Dropped connections are retried with exponential
+// backoff. The newest `created_at` ingested is saved as the source's cursor, so
+// a reconnect or restart only asks for what it missed.
+
+use crate::{
+    config::MirrorSource, parse_pubkey, peer::PeerConnection, process_event, store::WhitelistStatus, Accepted,
+    AppState, Origin,
+};
+use nostr::{Event, Filter, PublicKey, Timestamp};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use tracing::{error, info, warn};
+
+const SUB_PREFIX: &str = "relay-rs-mirror";
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// A connection that stayed up this long resets the backoff
+const HEALTHY_AFTER: Duration = Duration::from_secs(60);
+
+/// A silent upstream is pinged after this long, and dropped after twice this
+const PING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// While events stream in, the cursor is saved at most this often (and at each
+/// EOSE, lull and disconnect)
+const CURSOR_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Upstreams can receive events after newer ones; resume this far before the cursor
+const CURSOR_OVERLAP_SECS: i64 = 600;
+
+/// How often `members` sources re-read the member list
+const MEMBER_REFRESH: Duration = Duration::from_secs(600);
+
+/// Authors per REQ filter, to stay under upstream limits
+const AUTHORS_PER_FILTER: usize = 500;
+
+/// Counters for one upstream, served by the `mirrorstats` NIP-86 method
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct UpstreamStats {
+    pub connected: bool,
+    pub connections: u64,
+    /// Connections that failed or dropped
+    pub failures: u64,
+    pub last_error: Option<String>,
+    pub last_connected_at: Option<i64>,
+    pub received: u64,
+    pub stored: u64,
+    pub duplicates: u64,
+    pub rejected: u64,
+    pub cursor: Option<i64>,
+}
+
+/// Per-upstream counters, keyed by source URL
+#[derive(Default)]
+pub struct MirrorStats {
+    upstreams: Mutex<BTreeMap<String, UpstreamStats>>,
+}
+
+impl MirrorStats {
+    fn update(&self, url: &str, f: impl FnOnce(&mut UpstreamStats)) {
+        let mut upstreams = self.upstreams.lock().unwrap_or_else(PoisonError::into_inner);
+        f(upstreams.entry(url.to_string()).or_default());
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!(*self.upstreams.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+/// Start mirroring every configured source
+pub fn spawn(state: &Arc<AppState>) {
+    for source in state.config.mirror_sources.clone() {
+        state.mirror_stats.update(&source.url, |_| {});
+        let state = state.clone();
+        tokio::spawn(async move { run(state, source).await });
+    }
+    if !state.config.mirror_sources.is_empty() {
+        info!("Mirroring {} upstream relay(s)", state.config.mirror_sources.len());
+    }
+}
+
+/// Where a source's stream has got to
+struct Cursor {
+    value: Option<i64>,
+    saved: Option<i64>,
+    saved_at: Instant,
+}
+
+impl Cursor {
+    async fn load(state: &Arc<AppState>, url: &str) -> Self {
+        let value = state.store.mirror_cursor(url).await.unwrap_or_else(|e| {
+            error!("Failed to load mirror cursor for {}: {}", url, e);
+            None
+        });
+        state.mirror_stats.update(url, |stats| stats.cursor = value);
+        Self { value, saved: value, saved_at: Instant::now() }
+    }
+
+    /// Never past now, so one event dated in the future can't skip the stream ahead
+    fn advance(&mut self, created_at: i64) {
+        let created_at = created_at.min(Timestamp::now().as_u64() as i64);
+        if self.value.is_none_or(|value| created_at > value) {
+            self.value = Some(created_at);
+        }
+    }
+
+    async fn save(&mut self, state: &Arc<AppState>, url: &str) {
+        let Some(value) = self.value else { return };
+        if self.saved == Some(value) {
+            return;
+        }
+        match state.store.set_mirror_cursor(url, value).await {
+            Ok(()) => {
+                self.saved = Some(value);
+                self.saved_at = Instant::now();
+                state.mirror_stats.update(url, |stats| stats.cursor = Some(value));
+            }
+            Err(e) => error!("Failed to save mirror cursor for {}: {}", url, e),
+        }
+    }
+
+    /// Where a new subscription should start
+    fn since(&self) -> Option<Timestamp> {
+        self.value.map(|value| Timestamp::from((value - CURSOR_OVERLAP_SECS).max(0) as u64))
+    }
+}
+
+/// Mirror one source for as long as the relay runs
+async fn run(state: Arc<AppState>, source: MirrorSource) {
+    let mut cursor = Cursor::load(&state, &source.url).await;
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        let started = Instant::now();
+        let Err(reason) = mirror(&state, &source, &mut cursor).await;
+        cursor.save(&state, &source.url).await;
+
+        if started.elapsed() >= HEALTHY_AFTER {
+            backoff = MIN_BACKOFF;
+        }
+        state.mirror_stats.update(&source.url, |stats| {
+            stats.connected = false;
+            stats.failures += 1;
+            stats.last_error = Some(reason.clone());
+        });
+        warn!("Mirror of {} stopped: {}; retrying in {}s", source.url, reason, backoff.as_secs());
+
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// One connection's worth of mirroring; only returns when it ends
+async fn mirror(state: &Arc<AppState>, source: &MirrorSource, cursor: &mut Cursor) -> Result<Infallible, String> {
+    let mut conn = PeerConnection::connect(state, &source.url).await?;
+    state.mirror_stats.update(&source.url, |stats| {
+        stats.connected = true;
+        stats.connections += 1;
+        stats.last_connected_at = Some(Timestamp::now().as_u64() as i64);
+    });
+
+    let mut members = member_authors(state, source).await?;
+    let mut subscriptions = subscribe(&mut conn, source, members.as_deref(), cursor.since(), 0).await?;
+    let mut refreshed_at = Instant::now();
+    let mut pinged = false;
+
+    loop {
+        if source.members && refreshed_at.elapsed() >= MEMBER_REFRESH {
+            let current = member_authors(state, source).await?;
+            if current != members {
+                subscriptions = subscribe(&mut conn, source, current.as_deref(), cursor.since(), subscriptions).await?;
+                members = current;
+            }
+            refreshed_at = Instant::now();
+        }
+
+        let Some(message) = conn.recv_within(PING_INTERVAL).await? else {
+            cursor.save(state, &source.url).await;
+            if pinged {
+                return Err("upstream stopped responding".to_string());
+            }
+            conn.ping().await?;
+            pinged = true;
+            continue;
+        };
+        pinged = false;
+
+        if !message.get(1).and_then(|v| v.as_str()).is_some_and(|sub| sub.starts_with(SUB_PREFIX)) {
+            continue;
+        }
+        match message[0].as_str() {
+            Some("EVENT") => {
+                ingest(state, &source.url, message.get(2), cursor).await;
+                if cursor.saved_at.elapsed() >= CURSOR_SAVE_INTERVAL {
+                    cursor.save(state, &source.url).await;
+                }
+            }
+            Some("EOSE") => cursor.save(state, &source.url).await,
+            Some("CLOSED") => {
+                return Err(format!("upstream closed the subscription: {}", message.get(2).and_then(|v| v.as_str()).unwrap_or("")));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Store one upstream event under the rules a client's would face
+async fn ingest(state: &Arc<AppState>, url: &str, event: Option<&Value>, cursor: &mut Cursor) {
+    let outcome = match event.and_then(|v| serde_json::from_value::<Event>(v.clone()).ok()) {
+        Some(event) => {
+            let created_at = event.created_at.as_u64() as i64;
+            let outcome = process_event(event, state, Origin::Upstream).await;
+            if outcome.is_ok() {
+                cursor.advance(created_at);
+            }
+            outcome
+        }
+        None => Err("invalid: malformed event JSON".to_string()),
+    };
+
+    state.mirror_stats.update(url, |stats| {
+        stats.received += 1;
+        match outcome {
+            Ok(Accepted::Stored) => stats.stored += 1,
+            Ok(Accepted::Duplicate) => stats.duplicates += 1,
+            Err(_) => stats.rejected += 1,
+        }
+    });
+}
+
+/// The active members, sorted, for sources limited to them
+async fn member_authors(state: &Arc<AppState>, source: &MirrorSource) -> Result<Option<Vec<PublicKey>>, String> {
+    if !source.members {
+        return Ok(None);
+    }
+    let entries = state.store
+        .list_users(WhitelistStatus::Active)
+        .await
+        .map_err(|e| format!("could not list members: {}", e))?;
+    let mut authors: Vec<PublicKey> = entries
+        .iter()
+        .filter_map(|entry| parse_pubkey(&entry.value))
+        .filter_map(|user| PublicKey::from_hex(&user.hex).ok())
+        .collect();
+    authors.sort();
+    authors.dedup();
+    Ok(Some(authors))
+}
+
+/// Open (or replace) the source's subscriptions, closing any left over from a
+/// longer member list. Returns how many are open.
+async fn subscribe(
+    conn: &mut PeerConnection,
+    source: &MirrorSource,
+    members: Option<&[PublicKey]>,
+    since: Option<Timestamp>,
+    previous: usize,
+) -> Result<usize, String> {
+    let filters = filters(source, members, since);
+    for message in subscription_messages(&filters, previous) {
+        conn.send(message).await?;
+    }
+    Ok(filters.len())
+}
+
+/// The source's filter resumed from `since`, split into chunks of members if it
+/// is limited to them
+fn filters(source: &MirrorSource, members: Option<&[PublicKey]>, since: Option<Timestamp>) -> Vec<Filter> {
+    let mut base = source.filter.clone();
+    if let Some(since) = since {
+        if base.since.is_none_or(|configured| configured < since) {
+            base = base.since(since);
+        }
+    }
+
+    match members {
+        Some(members) => {
+            // Members within the configured authors, if the filter names any
+            let configured = base.authors.take().filter(|authors| !authors.is_empty());
+            let authors: Vec<PublicKey> = members
+                .iter()
+                .filter(|pubkey| configured.as_ref().is_none_or(|configured| configured.contains(pubkey)))
+                .copied()
+                .collect();
+            authors.chunks(AUTHORS_PER_FILTER).map(|chunk| base.clone().authors(chunk.iter().copied())).collect()
+        }
+        None => vec![base],
+    }
+}
+
+/// A REQ for each filter, and a CLOSE for each of the `previous` subscriptions
+/// they no longer cover
+fn subscription_messages(filters: &[Filter], previous: usize) -> Vec<Value> {
+    let reqs = filters
+        .iter()
+        .enumerate()
+        .map(|(i, filter)| json!(["REQ", format!("{}-{}", SUB_PREFIX, i), filter]));
+    let closes = (filters.len()..previous).map(|i| json!(["CLOSE", format!("{}-{}", SUB_PREFIX, i)]));
+    reqs.chain(closes).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::{Keys, Kind};
+
+    fn source(filter: Filter, members: bool) -> MirrorSource {
+        MirrorSource { url: "wss://upstream.test".to_string(), filter, members }
+    }
+
+    fn cursor_at(value: Option<i64>) -> Cursor {
+        Cursor { value, saved: value, saved_at: Instant::now() }
+    }
+
+    fn pubkeys(count: usize) -> Vec<PublicKey> {
+        let mut pubkeys: Vec<PublicKey> = (0..count).map(|_| Keys::generate().public_key()).collect();
+        pubkeys.sort();
+        pubkeys
+    }
+
+    #[test]
+    fn cursor_only_moves_forward_and_never_past_now() {
+        let now = Timestamp::now().as_u64() as i64;
+        let mut cursor = cursor_at(None);
+        assert_eq!(cursor.since(), None);
+
+        cursor.advance(now - 100);
+        assert_eq!(cursor.value, Some(now - 100));
+        cursor.advance(now - 200);
+        assert_eq!(cursor.value, Some(now - 100));
+
+        cursor.advance(now + 86_400);
+        let value = cursor.value.unwrap();
+        assert!(value >= now && value <= now + 5, "cursor jumped to {}", value);
+
+        // Subscriptions resume an overlap before the cursor, but not before the epoch
+        assert_eq!(cursor.since(), Some(Timestamp::from((value - CURSOR_OVERLAP_SECS) as u64)));
+        assert_eq!(cursor_at(Some(60)).since(), Some(Timestamp::from(0)));
+    }
+
+    #[test]
+    fn since_only_overrides_an_older_configured_since() {
+        let configured = source(Filter::new().kind(Kind::TextNote).since(Timestamp::from(1_000)), false);
+        let older = filters(&configured, None, Some(Timestamp::from(500)));
+        assert_eq!(older.len(), 1);
+        assert_eq!(older[0].since, Some(Timestamp::from(1_000)));
+
+        let newer = filters(&configured, None, Some(Timestamp::from(2_000)));
+        assert_eq!(newer[0].since, Some(Timestamp::from(2_000)));
+        assert!(newer[0].kinds.as_ref().unwrap().contains(&Kind::TextNote));
+    }
+
+    #[test]
+    fn member_sources_split_authors_into_chunks() {
+        let members = pubkeys(AUTHORS_PER_FILTER * 2 + 1);
+        let filters = filters(&source(Filter::new(), true), Some(&members), None);
+        let sizes: Vec<usize> = filters.iter().map(|filter| filter.authors.as_ref().unwrap().len()).collect();
+        assert_eq!(sizes, vec![AUTHORS_PER_FILTER, AUTHORS_PER_FILTER, 1]);
+        let covered: usize = filters
+            .iter()
+            .map(|filter| members.iter().filter(|pubkey| filter.authors.as_ref().unwrap().contains(pubkey)).count())
+            .sum();
+        assert_eq!(covered, members.len());
+    }
+
+    #[test]
+    fn member_sources_stay_within_configured_authors() {
+        let members = pubkeys(3);
+        let outsider = Keys::generate().public_key();
+        let configured = source(Filter::new().authors([members[0], members[2], outsider]), true);
+
+        let chunks = filters(&configured, Some(&members), None);
+        assert_eq!(chunks.len(), 1);
+        let authors = chunks[0].authors.as_ref().unwrap();
+        assert_eq!(authors.len(), 2);
+        assert!(authors.contains(&members[0]) && authors.contains(&members[2]));
+
+        // No members means no subscriptions rather than an unrestricted one
+        assert!(filters(&configured, Some(&[]), None).is_empty());
+    }
+
+    #[test]
+    fn a_shorter_member_list_closes_the_leftover_subscriptions() {
+        let filters = filters(&source(Filter::new(), true), Some(&pubkeys(2)), None);
+        let messages = subscription_messages(&filters, 3);
+        let sent: Vec<(&str, &str)> = messages
+            .iter()
+            .map(|message| (message[0].as_str().unwrap(), message[1].as_str().unwrap()))
+            .collect();
+        assert_eq!(sent, vec![
+            ("REQ", "relay-rs-mirror-0"),
+            ("CLOSE", "relay-rs-mirror-1"),
+            ("CLOSE", "relay-rs-mirror-2"),
+        ]);
+
+        // Growing the list only opens subscriptions
+        let messages = subscription_messages(&filters, 0);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0][0], "REQ");
+    }
+}
@@ -0,0 +1,182 @@
+// NIP-56 reports and event moderation
+//
+// Kind 1984 reports are stored like any other event and also filed on the web
+// app's admin message board. Once enough distinct pubkeys have reported the same
+// event it is hidden pending review; NIP-86 `banevent`/`allowevent` settle it,
+// taking the event out of (or putting it back into) the database results and
+// the Redis cache alike.
+
+use crate::{cache, store::{Report, StoreResult}, AppState};
+use nostr::{Event, EventId, Filter, PublicKey};
+use std::sync::Arc;
+use tracing::{error, info};
+
+pub const REPORT_KIND: u64 = 1984;
+
+/// File a stored kind 1984 event as reports, hiding events that cross the threshold
+pub async fn handle_report(state: &Arc<AppState>, report: &Event) {
+    // NIP-56: `p` names the reported pubkey, `e` the reported events, and the
+    // report type is the third value of either
+    let mut target: Option<(String, Option<String>)> = None;
+    let mut reported_events: Vec<(String, Option<String>)> = Vec::new();
+    for tag in &report.tags {
+        let t = tag.as_vec();
+        if t.len() < 2 {
+            continue;
+        }
+        match t[0].as_str() {
+            "p" if target.is_none() && PublicKey::from_hex(&t[1]).is_ok() => {
+                target = Some((t[1].clone(), t.get(2).cloned()));
+            }
+            "e" if EventId::from_hex(&t[1]).is_ok() => {
+                reported_events.push((t[1].clone(), t.get(2).cloned()));
+            }
+            _ => {}
+        }
+    }
+    let Some((target_pubkey, pubkey_report_type)) = target else {
+        return;
+    };
+
+    let report_id = report.id.to_hex();
+    let reporter = report.pubkey.to_string();
+    let base = Report {
+        id: &report_id,
+        reporter: &reporter,
+        target_pubkey: &target_pubkey,
+        event_id: None,
+        report_type: pubkey_report_type.as_deref(),
+        content: &report.content,
+    };
+
+    if reported_events.is_empty() {
+        if let Err(e) = state.store.record_report(&base).await {
+            error!("Failed to record report {}: {}", report_id, e);
+        }
+        return;
+    }
+
+    for (event_id, report_type) in &reported_events {
+        let report = Report {
+            event_id: Some(event_id),
+            report_type: report_type.as_deref().or(base.report_type),
+            ..base
+        };
+        match state.store.record_report(&report).await {
+            Ok(reporters) => {
+                let threshold = state.config.report_hide_threshold;
+                if threshold > 0 && reporters >= threshold {
+                    hide_reported(state, event_id, reporters).await;
+                }
+            }
+            Err(e) => error!("Failed to record report {}: {}", report_id, e),
+        }
+    }
+}
+
+async fn hide_reported(state: &Arc<AppState>, id: &str, reporters: u64) {
+    let event = find_event(state, id).await;
+    let reason = format!("hidden pending review after reports from {} pubkeys", reporters);
+    match state.store.hide_event(id, &reason).await {
+        Ok(true) => {
+            info!("Event {} {}", id, reason);
+            if let Some(event) = event {
+                cache::remove(state, &event).await;
+            }
+        }
+        Ok(false) => {}
+        Err(e) => error!("Failed to hide reported event {}: {}", id, e),
+    }
+}
+
+/// NIP-86 `banevent`: hide the event for good and close its reports as upheld
+pub async fn ban_event(state: &Arc<AppState>, id: &str, reason: Option<&str>) -> StoreResult<()> {
+    // Look it up while it's still visible, so it can be found in the cache
+    let event = find_event(state, id).await;
+    state.store.ban_event(id, reason).await?;
+    state.store.resolve_reports(id, true).await?;
+    if let Some(event) = event {
+        cache::remove(state, &event).await;
+    }
+    Ok(())
+}
+
+/// NIP-86 `allowevent`: lift a ban or pending-review hide and close its reports
+pub async fn allow_event(state: &Arc<AppState>, id: &str) -> StoreResult<()> {
+    state.store.allow_event(id).await?;
+    state.store.resolve_reports(id, false).await?;
+    if let Some(event) = find_event(state, id).await {
+        cache::insert(state, &event).await;
+    }
+    Ok(())
+}
+
+/// A stored, visible event by id
+async fn find_event(state: &Arc<AppState>, id: &str) -> Option<Event> {
+    let id = EventId::from_hex(id).ok()?;
+    state.store.query(&Filter::new().id(id), 1).await.ok()?.into_iter().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        process_event,
+        tests::{config, state_with},
+        Origin,
+    };
+    use nostr::{EventBuilder, Keys, Kind, Tag};
+
+    async fn publish(state: &Arc<AppState>, event: Event) {
+        process_event(event, state, Origin::Import { skip_whitelist: true }).await.unwrap();
+    }
+
+    fn report(reporter: &Keys, target: &Event) -> Event {
+        let tags = vec![
+            Tag::parse(vec!["e".to_string(), target.id.to_hex(), "spam".to_string()]).unwrap(),
+            Tag::parse(vec!["p".to_string(), target.pubkey.to_hex()]).unwrap(),
+        ];
+        EventBuilder::new(Kind::from(REPORT_KIND), "", tags).to_event(reporter).unwrap()
+    }
+
+    async fn visible(state: &Arc<AppState>, event: &Event) -> bool {
+        find_event(state, &event.id.to_hex()).await.is_some()
+    }
+
+    #[tokio::test]
+    async fn reports_from_enough_distinct_pubkeys_hide_an_event_until_allowed() {
+        let mut config = config();
+        config.report_hide_threshold = 2;
+        let state = state_with(config).await;
+        let note = EventBuilder::text_note("spam", []).to_event(&Keys::generate()).unwrap();
+        publish(&state, note.clone()).await;
+
+        let (first, second) = (Keys::generate(), Keys::generate());
+        publish(&state, report(&first, &note)).await;
+        // The same reporter again doesn't count twice
+        publish(&state, report(&first, &note)).await;
+        assert!(visible(&state, &note).await);
+        assert_eq!(state.store.events_needing_moderation().await.unwrap().len(), 1);
+
+        publish(&state, report(&second, &note)).await;
+        assert!(!visible(&state, &note).await);
+
+        allow_event(&state, &note.id.to_hex()).await.unwrap();
+        assert!(visible(&state, &note).await);
+        assert!(state.store.events_needing_moderation().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_zero_threshold_never_hides() {
+        let mut config = config();
+        config.report_hide_threshold = 0;
+        let state = state_with(config).await;
+        let note = EventBuilder::text_note("disputed", []).to_event(&Keys::generate()).unwrap();
+        publish(&state, note.clone()).await;
+
+        for _ in 0..5 {
+            publish(&state, report(&Keys::generate(), &note)).await;
+        }
+        assert!(visible(&state, &note).await);
+    }
+}
@@ -0,0 +1,338 @@
+// NIP-77: negentropy set reconciliation
+//
+// Each connection keeps its own open sessions, keyed by subscription id. Every
+// problem a client can cause is answered with `NEG-ERR` so it never waits on a
+// reply that isn't coming.
+
+use crate::{filter_scoped_to, read_access_denied, requests_private_kinds, AppState};
+use axum::extract::ws::Message;
+use negentropy::{Bytes, Negentropy};
+use nostr::Filter;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+use tracing::error;
+
+/// Open sessions allowed per connection
+const MAX_SESSIONS: usize = 8;
+
+/// Sessions with no NEG-MSG for this long are closed
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Negentropy frames the relay will produce
+pub const FRAME_SIZE_LIMIT: u64 = 100_000;
+
+struct Session {
+    neg: Negentropy,
+    last_active: Instant,
+}
+
+/// One connection's negentropy sessions
+#[derive(Default)]
+pub struct Sessions {
+    open: HashMap<String, Session>,
+}
+
+impl Sessions {
+    /// Close sessions idle for longer than `IDLE_TIMEOUT`, telling the client
+    pub async fn expire_idle(&mut self, sender: &mpsc::Sender<Message>) {
+        let now = Instant::now();
+        let expired: Vec<String> = self.open.iter()
+            .filter(|(_, session)| now.duration_since(session.last_active) >= IDLE_TIMEOUT)
+            .map(|(sub_id, _)| sub_id.clone())
+            .collect();
+        for sub_id in expired {
+            self.open.remove(&sub_id);
+            send_err(sender, &sub_id, "closed: negentropy session timed out").await;
+        }
+    }
+}
+
+/// `["NEG-OPEN", sub, filter, msg]`
+pub async fn handle_open(
+    arr: &[serde_json::Value],
+    state: &Arc<AppState>,
+    sender: &mpsc::Sender<Message>,
+    sessions: &mut Sessions,
+    auth_pubkeys: &HashSet<String>,
+) {
+    let Some(sub_id) = arr.get(1).and_then(|v| v.as_str()) else {
+        send_notice(sender, "invalid: NEG-OPEN needs a subscription id").await;
+        return;
+    };
+
+    // Reusing a subscription id closes the session already open under it
+    sessions.open.remove(sub_id);
+
+    if arr.len() < 4 {
+        send_err(sender, sub_id, "invalid: NEG-OPEN needs a filter and an initial message").await;
+        return;
+    }
+    if let Some(reason) = read_access_denied(state, auth_pubkeys).await {
+        send_err(sender, sub_id, reason).await;
+        return;
+    }
+    if sessions.open.len() >= MAX_SESSIONS {
+        send_err(sender, sub_id, &format!("blocked: at most {} negentropy sessions per connection", MAX_SESSIONS)).await;
+        return;
+    }
+
+    let filter = match serde_json::from_value::<Filter>(arr[2].clone()) {
+        Ok(filter) => filter,
+        Err(_) => {
+            send_err(sender, sub_id, "invalid: bad filter").await;
+            return;
+        }
+    };
+    // Ids and timestamps of private kinds are as revealing as a REQ for them
+    if requests_private_kinds(&filter) {
+        let reason = if auth_pubkeys.is_empty() {
+            Some("auth-required: authenticate to sync direct messages")
+        } else if !filter_scoped_to(&filter, auth_pubkeys) {
+            Some("restricted: direct message syncs must be limited to your own authors or #p")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            send_err(sender, sub_id, reason).await;
+            return;
+        }
+    }
+    // Early drafts put an id size before the message
+    let Some(query) = arr[arr.len().min(5) - 1].as_str().and_then(decode_message) else {
+        send_err(sender, sub_id, "invalid: initial message is not hex").await;
+        return;
+    };
+
+    // Ask for one more than the cap to tell "exactly at the cap" from "over it"
+    let max_records = state.config.negentropy_max_records;
+    let items = match state.store.negentropy_items(&filter, max_records + 1).await {
+        Ok(items) => items,
+        Err(e) => {
+            error!("Failed to load negentropy items: {}", e);
+            send_err(sender, sub_id, "error: could not load events").await;
+            return;
+        }
+    };
+    if items.len() > max_records {
+        send_err(sender, sub_id, &format!("blocked: this query matches more than {} events", max_records)).await;
+        return;
+    }
+
+    let mut neg = match Negentropy::new(32, Some(FRAME_SIZE_LIMIT)) {
+        Ok(neg) => neg,
+        Err(e) => {
+            error!("Failed to create negentropy session: {}", e);
+            send_err(sender, sub_id, "error: could not start negentropy session").await;
+            return;
+        }
+    };
+    for (created_at, id) in items {
+        let _ = neg.add_item(created_at, Bytes::from_slice(id.as_bytes()));
+    }
+    if let Err(e) = neg.seal() {
+        error!("Failed to seal negentropy session: {}", e);
+        send_err(sender, sub_id, "error: could not start negentropy session").await;
+        return;
+    }
+
+    let mut session = Session { neg, last_active: Instant::now() };
+    if reconcile(&mut session, sub_id, &query, sender).await {
+        sessions.open.insert(sub_id.to_string(), session);
+    }
+}
+
+/// `["NEG-MSG", sub, msg]`
+pub async fn handle_msg(arr: &[serde_json::Value], sender: &mpsc::Sender<Message>, sessions: &mut Sessions) {
+    let Some(sub_id) = arr.get(1).and_then(|v| v.as_str()) else {
+        send_notice(sender, "invalid: NEG-MSG needs a subscription id").await;
+        return;
+    };
+    let Some(mut session) = sessions.open.remove(sub_id) else {
+        send_err(sender, sub_id, "closed: no open negentropy session with this id").await;
+        return;
+    };
+    let Some(query) = arr.get(2).and_then(|v| v.as_str()).and_then(decode_message) else {
+        send_err(sender, sub_id, "invalid: message is not hex").await;
+        return;
+    };
+
+    session.last_active = Instant::now();
+    if reconcile(&mut session, sub_id, &query, sender).await {
+        sessions.open.insert(sub_id.to_string(), session);
+    }
+}
+
+/// `["NEG-CLOSE", sub]`
+pub fn handle_close(arr: &[serde_json::Value], sessions: &mut Sessions) {
+    if let Some(sub_id) = arr.get(1).and_then(|v| v.as_str()) {
+        sessions.open.remove(sub_id);
+    }
+}
+
+/// Answer a client message. Returns `false` (after a NEG-ERR) if the session is unusable.
+async fn reconcile(session: &mut Session, sub_id: &str, query: &Bytes, sender: &mpsc::Sender<Message>) -> bool {
+    match session.neg.reconcile(query) {
+        Ok(response) => {
+            let reply = serde_json::json!(["NEG-MSG", sub_id, response.to_hex()]);
+            let _ = sender.send(Message::Text(reply.to_string())).await;
+            true
+        }
+        Err(e) => {
+            send_err(sender, sub_id, &format!("invalid: {}", e)).await;
+            false
+        }
+    }
+}
+
+fn decode_message(hex_msg: &str) -> Option<Bytes> {
+    hex::decode(hex_msg).ok().map(|bytes| Bytes::from_slice(&bytes))
+}
+
+async fn send_err(sender: &mpsc::Sender<Message>, sub_id: &str, reason: &str) {
+    let _ = sender.send(Message::Text(serde_json::json!(["NEG-ERR", sub_id, reason]).to_string())).await;
+}
+
+async fn send_notice(sender: &mpsc::Sender<Message>, message: &str) {
+    let _ = sender.send(Message::Text(serde_json::json!(["NOTICE", message]).to_string())).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{config, state_with};
+    use nostr::{EventBuilder, Keys, Kind};
+    use serde_json::{json, Value};
+
+    /// A client's first message, from an empty set
+    fn initial() -> String {
+        let mut neg = Negentropy::new(32, None).unwrap();
+        neg.seal().unwrap();
+        neg.initiate().unwrap().to_hex()
+    }
+
+    fn replies(rx: &mut mpsc::Receiver<Message>) -> Vec<Value> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .filter_map(|msg| match msg {
+                Message::Text(text) => serde_json::from_str(&text).ok(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    async fn open(state: &Arc<AppState>, sessions: &mut Sessions, sub_id: &str, filter: Value) -> Value {
+        send(state, sessions, json!(["NEG-OPEN", sub_id, filter, initial()])).await
+    }
+
+    #[tokio::test]
+    async fn queries_matching_more_than_the_record_cap_are_refused() {
+        let mut config = config();
+        config.negentropy_max_records = 2;
+        let state = state_with(config).await;
+        let keys = Keys::generate();
+        for kind in [1, 1, 7] {
+            let event = EventBuilder::new(Kind::from(kind), nanoid::nanoid!(), []).to_event(&keys).unwrap();
+            state.store.save_event(&event, None).await.unwrap();
+        }
+        let mut sessions = Sessions::default();
+
+        let reply = open(&state, &mut sessions, "all", json!({})).await;
+        assert_eq!(reply[0], "NEG-ERR");
+        assert!(reply[2].as_str().unwrap().starts_with("blocked:"), "{}", reply[2]);
+        assert!(sessions.open.is_empty());
+
+        // The filter is applied before counting, and exactly at the cap is fine
+        let reply = open(&state, &mut sessions, "notes", json!({"kinds": [1]})).await;
+        assert_eq!(reply[0], "NEG-MSG");
+        assert!(sessions.open.contains_key("notes"));
+    }
+
+    /// Send one message on a connection, returning the single reply
+    async fn send(state: &Arc<AppState>, sessions: &mut Sessions, arr: Value) -> Value {
+        let (tx, mut rx) = mpsc::channel(10);
+        let arr = arr.as_array().unwrap();
+        match arr[0].as_str().unwrap() {
+            "NEG-OPEN" => handle_open(arr, state, &tx, sessions, &HashSet::new()).await,
+            "NEG-MSG" => handle_msg(arr, &tx, sessions).await,
+            _ => handle_close(arr, sessions),
+        }
+        let mut replies = replies(&mut rx);
+        assert!(replies.len() <= 1, "{:?}", replies);
+        replies.pop().unwrap_or(Value::Null)
+    }
+
+    fn reason(reply: &Value) -> &str {
+        assert_eq!(reply[0], "NEG-ERR", "{}", reply);
+        reply[2].as_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn client_mistakes_are_answered_with_neg_err() {
+        let state = state_with(config()).await;
+        let mut sessions = Sessions::default();
+
+        let reply = send(&state, &mut sessions, json!(["NEG-OPEN", "short", {}])).await;
+        assert!(reason(&reply).starts_with("invalid:"));
+        let reply = send(&state, &mut sessions, json!(["NEG-OPEN", "filter", "not a filter", initial()])).await;
+        assert_eq!(reason(&reply), "invalid: bad filter");
+        let reply = send(&state, &mut sessions, json!(["NEG-OPEN", "hex", {}, "zz"])).await;
+        assert!(reason(&reply).starts_with("invalid:"));
+        let reply = send(&state, &mut sessions, json!(["NEG-MSG", "unknown", initial()])).await;
+        assert!(reason(&reply).starts_with("closed:"));
+        // Without a subscription id there is nothing to address a NEG-ERR to
+        let reply = send(&state, &mut sessions, json!(["NEG-MSG"])).await;
+        assert_eq!(reply[0], "NOTICE");
+        assert!(sessions.open.is_empty());
+
+        // A bad message ends the session it was sent on
+        assert_eq!(open(&state, &mut sessions, "sub", json!({})).await[0], "NEG-MSG");
+        let reply = send(&state, &mut sessions, json!(["NEG-MSG", "sub", "zz"])).await;
+        assert!(reason(&reply).starts_with("invalid:"));
+        assert!(sessions.open.is_empty());
+
+        // In auth-required mode strangers get the read gate's reason
+        let mut config = config();
+        config.auth_required = true;
+        let private = state_with(config).await;
+        let reply = open(&private, &mut sessions, "sub", json!({})).await;
+        assert!(reason(&reply).starts_with("auth-required:"));
+    }
+
+    #[tokio::test]
+    async fn sessions_are_capped_per_connection_and_expire_when_idle() {
+        let state = state_with(config()).await;
+        let mut sessions = Sessions::default();
+        for i in 0..MAX_SESSIONS {
+            assert_eq!(open(&state, &mut sessions, &format!("sub-{}", i), json!({})).await[0], "NEG-MSG");
+        }
+        let reply = open(&state, &mut sessions, "one-more", json!({})).await;
+        assert!(reason(&reply).starts_with("blocked:"));
+
+        // Reusing an id replaces its session rather than taking another slot
+        assert_eq!(open(&state, &mut sessions, "sub-0", json!({})).await[0], "NEG-MSG");
+        assert_eq!(sessions.open.len(), MAX_SESSIONS);
+        // NEG-CLOSE frees one, silently
+        assert_eq!(send(&state, &mut sessions, json!(["NEG-CLOSE", "sub-1"])).await, Value::Null);
+        assert_eq!(open(&state, &mut sessions, "one-more", json!({})).await[0], "NEG-MSG");
+
+        // Only the sessions idle past the timeout are closed, each with a NEG-ERR
+        let stale = Instant::now() - IDLE_TIMEOUT;
+        sessions.open.get_mut("sub-2").unwrap().last_active = stale;
+        sessions.open.get_mut("sub-3").unwrap().last_active = stale;
+        let (tx, mut rx) = mpsc::channel(10);
+        sessions.expire_idle(&tx).await;
+        let mut expired: Vec<String> = replies(&mut rx)
+            .iter()
+            .map(|reply| {
+                assert!(reason(reply).starts_with("closed:"));
+                reply[1].as_str().unwrap().to_string()
+            })
+            .collect();
+        expired.sort();
+        assert_eq!(expired, ["sub-2", "sub-3"]);
+        assert_eq!(sessions.open.len(), MAX_SESSIONS - 2);
+    }
+}
@@ -0,0 +1,722 @@
+// NIP-86 relay management API
+//
+// Requests arrive either as JSON objects on an admin's authenticated websocket or
+// as HTTP POSTs authorized with NIP-98; both end up in `respond`.
+
+use crate::{
+    audit, ban_pubkey, cache, config, fanout, invalidate_whitelist_cache, moderation, parse_pubkey, redis_conn,
+    store::{AuditEntry, ListEntry, WhitelistStatus},
+    AppState, Control, Standing, UserKey,
+};
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::Engine;
+use nostr::{hashes::{sha256::Hash as Sha256Hash, Hash}, Event, EventId, JsonUtil};
+use serde_json::{json, Value};
+use std::{net::IpAddr, sync::Arc};
+use tracing::error;
+
+pub const CONTENT_TYPE: &str = "application/nostr+json+rpc";
+
+/// NIP-98 HTTP auth event kind
+const HTTP_AUTH_KIND: u64 = 27235;
+
+/// Why a method call failed, mapped onto JSON-RPC error codes
+enum RpcError {
+    MethodNotFound(String),
+    InvalidParams(&'static str),
+    Internal(String),
+}
+
+impl RpcError {
+    fn code(&self) -> i64 {
+        match self {
+            RpcError::MethodNotFound(_) => -32601,
+            RpcError::InvalidParams(_) => -32602,
+            RpcError::Internal(_) => -32603,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            RpcError::MethodNotFound(method) => format!("method not found: {}", method),
+            RpcError::InvalidParams(message) => message.to_string(),
+            RpcError::Internal(message) => message.clone(),
+        }
+    }
+}
+
+pub const SUPPORTED_METHODS: [&str; 22] = [
+    "supportedmethods",
+    "banpubkey",
+    "listbannedpubkeys",
+    "allowpubkey",
+    "listallowedpubkeys",
+    "listeventsneedingmoderation",
+    "allowevent",
+    "banevent",
+    "listbannedevents",
+    "changerelayname",
+    "changerelaydescription",
+    "changerelayicon",
+    "allowkind",
+    "disallowkind",
+    "listallowedkinds",
+    // Not in the NIP: [] -> kinds refused by disallowkind
+    "listdisallowedkinds",
+    "blockip",
+    "unblockip",
+    "listblockedips",
+    // Not in the NIP: [limit?, before_id?] -> newest audit log entries
+    "listauditlog",
+    // Not in the NIP: [] -> connection and ingest counters for each mirrored upstream
+    "mirrorstats",
+    // Not in the NIP: [] -> REQ hit rate of the Redis recent-events cache
+    "cachestats",
+];
+
+/// `relay_settings` keys overriding the relay information document
+pub const SETTING_NAME: &str = "name";
+pub const SETTING_DESCRIPTION: &str = "description";
+pub const SETTING_ICON: &str = "icon";
+
+/// The first of `pubkeys` that belongs to a relay admin
+pub async fn admin_pubkey<'a>(state: &Arc<AppState>, pubkeys: impl IntoIterator<Item = &'a String>) -> Option<String> {
+    for pubkey in pubkeys {
+        // Straight from the database: admin rights shouldn't wait for a cache to expire
+        if let Ok(access) = state.store.user_access(pubkey).await {
+            if Standing::from_access(access).is_admin() {
+                return Some(pubkey.clone());
+            }
+        }
+    }
+    None
+}
+
+/// Whether a method only reads, and so is left out of the audit log. Logging
+/// their results would copy lists (and earlier audit entries) into every row.
+pub fn is_read_only(method: &str) -> bool {
+    method == "supportedmethods" || method.starts_with("list") || method.ends_with("stats")
+}
+
+/// Answer a JSON-RPC request object from `admin` (`None` if the caller isn't one),
+/// recording changes in the audit log
+pub async fn respond(state: &Arc<AppState>, request: &Value, admin: Option<&str>, source: &str) -> Value {
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+
+    let Some(admin) = admin else {
+        return json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": -32000, "message": "Unauthorized: Admin access required" }
+        });
+    };
+
+    let Some(method) = request.get("method").and_then(|v| v.as_str()) else {
+        return json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": -32600, "message": "missing method" }
+        });
+    };
+    let params = request.get("params").and_then(|v| v.as_array()).map(Vec::as_slice).unwrap_or_default();
+
+    let outcome = call(state, method, params).await;
+    if !is_read_only(method) {
+        audit::record(state, AuditEntry {
+            admin_pubkey: Some(admin.to_string()),
+            source: source.to_string(),
+            method: method.to_string(),
+            params: Value::Array(params.to_vec()),
+            result: match &outcome {
+                Ok(res) => res.clone(),
+                Err(e) => json!(e.message()),
+            },
+            success: outcome.is_ok(),
+        })
+        .await;
+    }
+
+    match outcome {
+        Ok(res) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "result": res
+        }),
+        Err(e) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": e.code(), "message": e.message() }
+        }),
+    }
+}
+
+/// `POST /` with `Content-Type: application/nostr+json+rpc` and a NIP-98 `Authorization` header
+pub async fn http_handler(State(state): State<Arc<AppState>>, headers: HeaderMap, body: Bytes) -> Response {
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("");
+    if !content_type.starts_with(CONTENT_TYPE) {
+        return (StatusCode::UNSUPPORTED_MEDIA_TYPE, format!("expected {}", CONTENT_TYPE)).into_response();
+    }
+
+    let auth = match verify_http_auth(&state, &headers, &body) {
+        Ok(auth) => auth,
+        Err(reason) => return (StatusCode::UNAUTHORIZED, reason).into_response(),
+    };
+    let Some(admin) = admin_pubkey(&state, [&auth.pubkey.to_string()]).await else {
+        return (StatusCode::UNAUTHORIZED, "admin access required").into_response();
+    };
+    if !claim_http_auth(&state, &auth).await {
+        return (StatusCode::UNAUTHORIZED, "auth event already used").into_response();
+    }
+
+    let request: Value = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(_) => return (StatusCode::BAD_REQUEST, "invalid JSON-RPC request").into_response(),
+    };
+
+    let response = respond(&state, &request, Some(&admin), "http").await;
+    ([(header::CONTENT_TYPE, CONTENT_TYPE)], response.to_string()).into_response()
+}
+
+/// Check a NIP-98 `Authorization: Nostr <base64 event>` header against this request.
+/// Returns the auth event.
+fn verify_http_auth(state: &AppState, headers: &HeaderMap, body: &[u8]) -> Result<Event, &'static str> {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Nostr "))
+        .ok_or("missing NIP-98 authorization")?;
+    let json = base64::engine::general_purpose::STANDARD
+        .decode(token.trim())
+        .map_err(|_| "authorization is not base64")?;
+    let event = Event::from_json(json).map_err(|_| "authorization is not a nostr event")?;
+
+    if event.kind.as_u64() != HTTP_AUTH_KIND {
+        return Err("invalid kind for HTTP auth");
+    }
+
+    let tag = |name: &str| {
+        event.tags.iter().map(|t| t.as_vec()).find(|t| t.len() >= 2 && t[0] == name).map(|t| t[1].clone())
+    };
+    if !tag("u").is_some_and(|u| state.config.is_own_http_url(&u)) {
+        return Err("auth event url does not match this relay");
+    }
+    if !tag("method").is_some_and(|m| m.eq_ignore_ascii_case("POST")) {
+        return Err("auth event method does not match");
+    }
+    let payload = Sha256Hash::hash(body).to_string();
+    if !tag("payload").is_some_and(|p| p.eq_ignore_ascii_case(&payload)) {
+        return Err("auth event payload hash does not match the body");
+    }
+
+    let skew = (chrono::Utc::now().timestamp() - event.created_at.as_i64()).abs();
+    if skew > config::HTTP_AUTH_MAX_SKEW_SECS {
+        return Err("auth event too old or too far in the future");
+    }
+
+    event.verify().map_err(|_| "invalid auth event signature")?;
+    Ok(event)
+}
+
+/// Mark an accepted auth event used, so it can't be replayed while it's still
+/// fresh enough to pass. False if it was already used here or, through Redis,
+/// on another instance.
+async fn claim_http_auth(state: &Arc<AppState>, event: &Event) -> bool {
+    let expires_at = event.created_at.as_i64() + config::HTTP_AUTH_MAX_SKEW_SECS;
+    if !state.local.claim_auth_event(event.id, expires_at) {
+        return false;
+    }
+    let Some(mut conn) = redis_conn(state).await else {
+        return true;
+    };
+    let ttl = (expires_at - chrono::Utc::now().timestamp()).max(1);
+    let set: redis::RedisResult<Option<String>> = redis::cmd("SET")
+        .arg(format!("nip98:{}", event.id))
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(ttl)
+        .query_async(&mut conn)
+        .await;
+    !matches!(state.breaker.record(set), Ok(None))
+}
+
+async fn call(state: &Arc<AppState>, method: &str, params: &[Value]) -> Result<Value, RpcError> {
+    let store = &state.store;
+    match method {
+        "supportedmethods" => Ok(json!(SUPPORTED_METHODS)),
+        "banpubkey" => {
+            let user = pubkey_param(params)?;
+            ban_pubkey(state, &user, reason_param(params)).await.map_err(db_error)?;
+            Ok(json!(true))
+        }
+        "allowpubkey" => {
+            let user = pubkey_param(params)?;
+            store.allow_user(&user.npub, &user.hex, reason_param(params)).await.map_err(db_error)?;
+            invalidate_whitelist_cache(state, &user.hex).await;
+            Ok(json!(true))
+        }
+        "listbannedpubkeys" => {
+            let users = store.banned_users().await.map_err(db_error)?;
+            Ok(entries_json(users, "pubkey"))
+        }
+        "listallowedpubkeys" => {
+            let users = store.list_users(WhitelistStatus::Active).await.map_err(db_error)?;
+            Ok(entries_json(users, "pubkey"))
+        }
+        "banevent" => {
+            let id = event_id_param(params)?;
+            moderation::ban_event(state, &id, reason_param(params)).await.map_err(db_error)?;
+            Ok(json!(true))
+        }
+        "allowevent" => {
+            let id = event_id_param(params)?;
+            moderation::allow_event(state, &id).await.map_err(db_error)?;
+            Ok(json!(true))
+        }
+        "listbannedevents" => {
+            let events = store.banned_events().await.map_err(db_error)?;
+            Ok(entries_json(events, "id"))
+        }
+        "listeventsneedingmoderation" => {
+            let events = store.events_needing_moderation().await.map_err(db_error)?;
+            Ok(entries_json(events, "id"))
+        }
+        "changerelayname" => set_setting(state, SETTING_NAME, params).await,
+        "changerelaydescription" => set_setting(state, SETTING_DESCRIPTION, params).await,
+        "changerelayicon" => set_setting(state, SETTING_ICON, params).await,
+        "allowkind" => {
+            store.allow_kind(kind_param(params)?).await.map_err(db_error)?;
+            Ok(json!(true))
+        }
+        "disallowkind" => {
+            store.disallow_kind(kind_param(params)?).await.map_err(db_error)?;
+            Ok(json!(true))
+        }
+        "listallowedkinds" => Ok(json!(store.allowed_kinds().await.map_err(db_error)?)),
+        "listdisallowedkinds" => Ok(json!(store.disallowed_kinds().await.map_err(db_error)?)),
+        "blockip" => {
+            let ip = ip_param(params)?;
+            store.block_ip(&ip.to_string(), reason_param(params)).await.map_err(db_error)?;
+            fanout::control(state, Control::IpBlocked(ip)).await;
+            Ok(json!(true))
+        }
+        "unblockip" => {
+            store.unblock_ip(&ip_param(params)?.to_string()).await.map_err(db_error)?;
+            Ok(json!(true))
+        }
+        "listblockedips" => {
+            let ips = store.blocked_ips().await.map_err(db_error)?;
+            Ok(entries_json(ips, "ip"))
+        }
+        "listauditlog" => {
+            let limit = params.first().and_then(|v| v.as_i64()).unwrap_or(100).clamp(1, 1000);
+            let before_id = params.get(1).and_then(|v| v.as_i64());
+            let records = store.audit_log(limit, before_id).await.map_err(db_error)?;
+            Ok(Value::Array(records.iter().map(audit::to_json).collect()))
+        }
+        "mirrorstats" => Ok(state.mirror_stats.to_json()),
+        "cachestats" => Ok(cache::stats(state).await),
+        _ => Err(RpcError::MethodNotFound(method.to_string())),
+    }
+}
+
+async fn set_setting(state: &Arc<AppState>, key: &str, params: &[Value]) -> Result<Value, RpcError> {
+    let value = params.first().and_then(|v| v.as_str()).ok_or(RpcError::InvalidParams("missing string param"))?;
+    state.store.set_setting(key, value.trim()).await.map_err(db_error)?;
+    Ok(json!(true))
+}
+
+/// `[{"<key>": value, "reason": reason?}, ...]` as the NIP's list methods return
+fn entries_json(entries: Vec<ListEntry>, key: &str) -> Value {
+    Value::Array(
+        entries
+            .into_iter()
+            .map(|entry| {
+                let mut item = serde_json::Map::new();
+                item.insert(key.to_string(), json!(entry.value));
+                if let Some(reason) = entry.reason {
+                    item.insert("reason".to_string(), json!(reason));
+                }
+                Value::Object(item)
+            })
+            .collect(),
+    )
+}
+
+fn pubkey_param(params: &[Value]) -> Result<UserKey, RpcError> {
+    params
+        .first()
+        .and_then(|v| v.as_str())
+        .and_then(parse_pubkey)
+        .ok_or(RpcError::InvalidParams("missing or invalid pubkey param"))
+}
+
+fn event_id_param(params: &[Value]) -> Result<String, RpcError> {
+    params
+        .first()
+        .and_then(|v| v.as_str())
+        .and_then(|id| EventId::from_hex(id).ok())
+        .map(|id| id.to_hex())
+        .ok_or(RpcError::InvalidParams("missing or invalid event id param"))
+}
+
+fn kind_param(params: &[Value]) -> Result<u64, RpcError> {
+    params
+        .first()
+        .and_then(|v| v.as_u64())
+        .filter(|k| *k <= 65535)
+        .ok_or(RpcError::InvalidParams("missing or invalid kind param"))
+}
+
+/// IPs are stored in canonical form so lookups match however they were written
+fn ip_param(params: &[Value]) -> Result<IpAddr, RpcError> {
+    params
+        .first()
+        .and_then(|v| v.as_str())
+        .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+        .ok_or(RpcError::InvalidParams("missing or invalid ip param"))
+}
+
+/// Optional free-text reason, always the second param
+fn reason_param(params: &[Value]) -> Option<&str> {
+    params.get(1).and_then(|v| v.as_str()).map(str::trim).filter(|r| !r.is_empty())
+}
+
+fn db_error(e: sqlx::Error) -> RpcError {
+    error!("NIP-86 database error: {}", e);
+    RpcError::Internal("database error".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        process_event,
+        store::{Report, SqliteStore},
+        tests::{config, state, state_on, state_with, RELAY_URL},
+        Origin,
+    };
+    use nostr::{EventBuilder, Filter, Keys, Kind, Tag, Timestamp, ToBech32};
+
+    const BODY: &[u8] = br#"{"method":"supportedmethods","params":[]}"#;
+
+    struct AuthEvent<'a> {
+        kind: u64,
+        url: &'a str,
+        method: &'a str,
+        payload: Option<String>,
+        created_at: Timestamp,
+    }
+
+    impl Default for AuthEvent<'_> {
+        fn default() -> Self {
+            Self {
+                kind: HTTP_AUTH_KIND,
+                url: "https://relay.test",
+                method: "POST",
+                payload: Some(Sha256Hash::hash(BODY).to_string()),
+                created_at: Timestamp::now(),
+            }
+        }
+    }
+
+    impl AuthEvent<'_> {
+        fn sign(&self, keys: &Keys) -> Event {
+            let mut tags = vec![vec!["u", self.url], vec!["method", self.method]];
+            if let Some(payload) = &self.payload {
+                tags.push(vec!["payload", payload]);
+            }
+            let tags: Vec<Tag> = tags.into_iter().map(|t| Tag::parse(t).unwrap()).collect();
+            EventBuilder::new(Kind::from(self.kind), "", tags)
+                .custom_created_at(self.created_at)
+                .to_event(keys)
+                .unwrap()
+        }
+    }
+
+    fn headers(event: &Event) -> HeaderMap {
+        let token = base64::engine::general_purpose::STANDARD.encode(event.as_json());
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, format!("Nostr {}", token).parse().unwrap());
+        headers.insert(header::CONTENT_TYPE, CONTENT_TYPE.parse().unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn accepts_a_matching_auth_event() {
+        let state = state().await;
+        let keys = Keys::generate();
+        let event = AuthEvent::default().sign(&keys);
+        assert_eq!(verify_http_auth(&state, &headers(&event), BODY), Ok(event));
+    }
+
+    #[tokio::test]
+    async fn rejects_auth_events_that_do_not_match_the_request() {
+        let state = state().await;
+        let keys = Keys::generate();
+        let now = Timestamp::now().as_u64();
+        let skew = config::HTTP_AUTH_MAX_SKEW_SECS as u64 + 10;
+
+        let cases = [
+            (AuthEvent { kind: 22242, ..Default::default() }, "invalid kind for HTTP auth"),
+            (AuthEvent { url: "https://other.relay", ..Default::default() }, "auth event url does not match this relay"),
+            (AuthEvent { url: RELAY_URL, ..Default::default() }, "auth event url does not match this relay"),
+            (AuthEvent { method: "GET", ..Default::default() }, "auth event method does not match"),
+            (AuthEvent { payload: None, ..Default::default() }, "auth event payload hash does not match the body"),
+            (
+                AuthEvent { payload: Some(Sha256Hash::hash(b"{}").to_string()), ..Default::default() },
+                "auth event payload hash does not match the body",
+            ),
+            (AuthEvent { created_at: Timestamp::from(now - skew), ..Default::default() }, "auth event too old or too far in the future"),
+            (AuthEvent { created_at: Timestamp::from(now + skew), ..Default::default() }, "auth event too old or too far in the future"),
+        ];
+        for (case, reason) in &cases {
+            let event = case.sign(&keys);
+            assert_eq!(verify_http_auth(&state, &headers(&event), BODY), Err(*reason));
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_a_bad_signature() {
+        let state = state().await;
+        let event = AuthEvent::default().sign(&Keys::generate());
+        let other = AuthEvent::default().sign(&Keys::generate());
+
+        let mut forged: Value = serde_json::from_str(&event.as_json()).unwrap();
+        forged["sig"] = json!(other.sig.to_string());
+        let forged = Event::from_value(forged).unwrap();
+        assert_eq!(verify_http_auth(&state, &headers(&forged), BODY), Err("invalid auth event signature"));
+    }
+
+    #[tokio::test]
+    async fn only_admins_may_call_methods() {
+        let state = state().await;
+        let (admin, member) = (Keys::generate(), Keys::generate());
+        for keys in [&admin, &member] {
+            let pubkey = keys.public_key();
+            state.store.allow_user(&pubkey.to_bech32().unwrap(), &pubkey.to_hex(), None).await.unwrap();
+        }
+        state.store.set_admin(&admin.public_key().to_hex(), true).await.unwrap();
+
+        let call = |keys: &Keys| {
+            let headers = headers(&AuthEvent::default().sign(keys));
+            http_handler(State(state.clone()), headers, Bytes::from_static(BODY))
+        };
+        assert_eq!(call(&member).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call(&admin).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn auth_events_are_single_use() {
+        let state = state().await;
+        let admin = Keys::generate();
+        let pubkey = admin.public_key();
+        state.store.allow_user(&pubkey.to_bech32().unwrap(), &pubkey.to_hex(), None).await.unwrap();
+        state.store.set_admin(&pubkey.to_hex(), true).await.unwrap();
+
+        let call = |event: &Event| http_handler(State(state.clone()), headers(event), Bytes::from_static(BODY));
+        let event = AuthEvent::default().sign(&admin);
+        assert_eq!(call(&event).await.status(), StatusCode::OK);
+        let replay = call(&event).await;
+        assert_eq!(replay.status(), StatusCode::UNAUTHORIZED);
+        let body = axum::body::to_bytes(replay.into_body(), 1024).await.unwrap();
+        assert_eq!(&body[..], b"auth event already used");
+
+        // A new auth event for the same request is fine
+        let next = AuthEvent { created_at: Timestamp::from(Timestamp::now().as_u64() - 1), ..Default::default() };
+        assert_eq!(call(&next.sign(&admin)).await.status(), StatusCode::OK);
+    }
+
+    const ADMIN: &str = "0000000000000000000000000000000000000000000000000000000000000001";
+
+    async fn rpc(state: &Arc<AppState>, method: &str, params: Value) -> Value {
+        let request = json!({ "id": 1, "method": method, "params": params });
+        respond(state, &request, Some(ADMIN), "websocket").await
+    }
+
+    #[tokio::test]
+    async fn listauditlog_does_not_nest_earlier_results() {
+        let state = state().await;
+        let pubkey = Keys::generate().public_key().to_hex();
+        rpc(&state, "banpubkey", json!([pubkey, "spam"])).await;
+        rpc(&state, "listauditlog", json!([])).await;
+        rpc(&state, "listbannedpubkeys", json!([])).await;
+
+        let log = rpc(&state, "listauditlog", json!([])).await;
+        let entries = log["result"].as_array().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["method"], "banpubkey");
+        assert_eq!(entries[0]["result"], true);
+    }
+
+    #[tokio::test]
+    async fn published_audit_events_leave_out_ips_reasons_and_results() {
+        let mut config = config();
+        config.publish_audit = true;
+        let state = state_with(config).await;
+        let pubkey = Keys::generate().public_key().to_hex();
+        rpc(&state, "blockip", json!(["203.0.113.7", "scraper"])).await;
+        rpc(&state, "banpubkey", json!([pubkey, "spam"])).await;
+
+        let filter = Filter::new().kind(Kind::from(30078));
+        let events = state.store.query(&filter, 10).await.unwrap();
+        assert_eq!(events.len(), 2);
+        for event in &events {
+            assert!(!event.content.contains("203.0.113.7"));
+            assert!(!event.content.contains("scraper") && !event.content.contains("spam"));
+            assert!(!event.content.contains("result"));
+        }
+        let ban: Value = events.iter().map(|e| serde_json::from_str(&e.content).unwrap()).find(|c: &Value| c["method"] == "banpubkey").unwrap();
+        assert_eq!(ban["target"], json!(pubkey));
+        assert_eq!(ban["success"], true);
+    }
+
+    fn error_code(response: &Value) -> Option<i64> {
+        response["error"]["code"].as_i64()
+    }
+
+    /// Publish an event of `kind` as if imported, so only relay policy applies
+    async fn publish(state: &Arc<AppState>, kind: u64) -> Result<(), String> {
+        let event = EventBuilder::new(Kind::from(kind), format!("kind {}", kind), []).to_event(&Keys::generate()).unwrap();
+        process_event(event, state, Origin::Import { skip_whitelist: true }).await.map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn every_supported_method_is_handled() {
+        let state = state().await;
+        for method in SUPPORTED_METHODS {
+            let response = rpc(&state, method, json!([])).await;
+            assert_ne!(error_code(&response), Some(-32601), "{}", method);
+        }
+        assert_eq!(error_code(&rpc(&state, "nosuchmethod", json!([])).await), Some(-32601));
+    }
+
+    #[tokio::test]
+    async fn banpubkey_and_allowpubkey_move_users_between_lists() {
+        let state = state().await;
+        let pubkey = Keys::generate().public_key().to_hex();
+
+        assert_eq!(rpc(&state, "banpubkey", json!([pubkey, "spam"])).await["result"], true);
+        assert_eq!(rpc(&state, "listbannedpubkeys", json!([])).await["result"], json!([{ "pubkey": pubkey, "reason": "spam" }]));
+        assert_eq!(rpc(&state, "listallowedpubkeys", json!([])).await["result"], json!([]));
+        assert_eq!(state.store.user_access(&pubkey).await.unwrap().unwrap().status, Some(WhitelistStatus::Revoked));
+
+        assert_eq!(rpc(&state, "allowpubkey", json!([pubkey, "appealed"])).await["result"], true);
+        assert_eq!(rpc(&state, "listallowedpubkeys", json!([])).await["result"], json!([{ "pubkey": pubkey, "reason": "appealed" }]));
+        assert_eq!(rpc(&state, "listbannedpubkeys", json!([])).await["result"], json!([]));
+
+        assert_eq!(error_code(&rpc(&state, "banpubkey", json!(["not a pubkey"])).await), Some(-32602));
+    }
+
+    #[tokio::test]
+    async fn banevent_hides_an_event_until_allowevent() {
+        let state = state().await;
+        let event = EventBuilder::text_note("hello", []).to_event(&Keys::generate()).unwrap();
+        state.store.save_event(&event, None).await.unwrap();
+        let id = event.id.to_hex();
+        let filter = Filter::new().id(event.id);
+        let visible = |state: Arc<AppState>| {
+            let filter = filter.clone();
+            async move { state.store.query(&filter, 10).await.unwrap().len() }
+        };
+
+        rpc(&state, "banevent", json!([id, "illegal"])).await;
+        assert_eq!(rpc(&state, "listbannedevents", json!([])).await["result"], json!([{ "id": id, "reason": "illegal" }]));
+        assert_eq!(visible(state.clone()).await, 0);
+
+        rpc(&state, "allowevent", json!([id])).await;
+        assert_eq!(rpc(&state, "listbannedevents", json!([])).await["result"], json!([]));
+        assert_eq!(visible(state.clone()).await, 1);
+
+        // A reported event waits for moderation; allowevent closes its reports
+        let author = event.pubkey.to_hex();
+        let report = Report { id: "report", reporter: "reporter", target_pubkey: &author, event_id: Some(&id), report_type: Some("spam"), content: "" };
+        state.store.record_report(&report).await.unwrap();
+        let needing = rpc(&state, "listeventsneedingmoderation", json!([])).await;
+        assert_eq!(needing["result"][0]["id"], json!(id));
+        assert_eq!(rpc(&state, "listbannedevents", json!([])).await["result"], json!([]));
+        rpc(&state, "allowevent", json!([id])).await;
+        assert_eq!(rpc(&state, "listeventsneedingmoderation", json!([])).await["result"], json!([]));
+        assert_eq!(visible(state.clone()).await, 1);
+    }
+
+    #[tokio::test]
+    async fn relay_settings_are_saved_trimmed() {
+        let state = state().await;
+        for (method, key) in [("changerelayname", SETTING_NAME), ("changerelaydescription", SETTING_DESCRIPTION), ("changerelayicon", SETTING_ICON)] {
+            assert_eq!(rpc(&state, method, json!(["  value  "])).await["result"], true);
+            assert_eq!(state.store.setting(key).await.unwrap().as_deref(), Some("value"));
+            assert_eq!(error_code(&rpc(&state, method, json!([])).await), Some(-32602));
+        }
+    }
+
+    #[tokio::test]
+    async fn kind_lists_decide_which_kinds_are_accepted() {
+        let state = state().await;
+
+        // With no allow-list, disallowing a kind still refuses it
+        rpc(&state, "disallowkind", json!([4])).await;
+        assert_eq!(rpc(&state, "listdisallowedkinds", json!([])).await["result"], json!([4]));
+        assert!(publish(&state, 4).await.is_err_and(|reason| reason.contains("kind 4")));
+        assert!(publish(&state, 1).await.is_ok());
+
+        // Allowing it again lifts that without starting an allow-list
+        rpc(&state, "allowkind", json!([4])).await;
+        assert_eq!(rpc(&state, "listdisallowedkinds", json!([])).await["result"], json!([]));
+        assert_eq!(rpc(&state, "listallowedkinds", json!([])).await["result"], json!([]));
+        assert!(publish(&state, 4).await.is_ok());
+
+        rpc(&state, "allowkind", json!([30023])).await;
+        assert_eq!(rpc(&state, "listallowedkinds", json!([])).await["result"], json!([30023]));
+        assert!(publish(&state, 1).await.is_err());
+
+        assert_eq!(error_code(&rpc(&state, "allowkind", json!([70000])).await), Some(-32602));
+    }
+
+    #[tokio::test]
+    async fn blocked_ips_are_stored_in_canonical_form() {
+        let state = state().await;
+        rpc(&state, "blockip", json!(["2001:DB8::1", "abuse"])).await;
+        assert_eq!(rpc(&state, "listblockedips", json!([])).await["result"], json!([{ "ip": "2001:db8::1", "reason": "abuse" }]));
+
+        rpc(&state, "unblockip", json!(["2001:db8:0::1"])).await;
+        assert_eq!(rpc(&state, "listblockedips", json!([])).await["result"], json!([]));
+        assert_eq!(error_code(&rpc(&state, "blockip", json!(["not an ip"])).await), Some(-32602));
+    }
+
+    #[tokio::test]
+    async fn management_lists_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("relay-rs-nip86-{}.db", nanoid::nanoid!()));
+        let url = format!("sqlite://{}", path.display());
+        let pubkey = Keys::generate().public_key().to_hex();
+        let event_id = EventBuilder::text_note("x", []).to_event(&Keys::generate()).unwrap().id.to_hex();
+
+        let state = state_on(config(), SqliteStore::connect(&url).await.unwrap());
+        for (method, params) in [
+            ("banpubkey", json!([pubkey])),
+            ("banevent", json!([event_id])),
+            ("allowkind", json!([1])),
+            ("disallowkind", json!([4])),
+            ("blockip", json!(["10.0.0.1"])),
+            ("changerelayname", json!(["Restarted"])),
+        ] {
+            assert_eq!(rpc(&state, method, params).await["result"], true, "{}", method);
+        }
+        drop(state);
+
+        let state = state_on(config(), SqliteStore::connect(&url).await.unwrap());
+        assert_eq!(rpc(&state, "listbannedpubkeys", json!([])).await["result"], json!([{ "pubkey": pubkey }]));
+        assert_eq!(rpc(&state, "listbannedevents", json!([])).await["result"], json!([{ "id": event_id }]));
+        assert_eq!(rpc(&state, "listallowedkinds", json!([])).await["result"], json!([1]));
+        assert_eq!(rpc(&state, "listdisallowedkinds", json!([])).await["result"], json!([4]));
+        assert_eq!(rpc(&state, "listblockedips", json!([])).await["result"], json!([{ "ip": "10.0.0.1" }]));
+        assert_eq!(state.store.setting(SETTING_NAME).await.unwrap().as_deref(), Some("Restarted"));
+        drop(state);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,126 @@
+// Websocket client connection to another relay, shared by NIP-77 sync and
+// REQ mirroring. Peers that ask for NIP-42 AUTH get it from the relay key.
+
+use crate::AppState;
+use futures::{sink::SinkExt, stream::StreamExt};
+use nostr::{EventBuilder, Keys, Url};
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Duration};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use tracing::{debug, warn};
+
+/// Longest wait for any single reply from the peer
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a peer has to send its AUTH challenge after connecting
+const CHALLENGE_WAIT: Duration = Duration::from_secs(2);
+
+pub struct PeerConnection {
+    pub url: String,
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    keys: Keys,
+    /// Id of the last AUTH event sent, whose OK is ours to consume
+    auth_id: Option<String>,
+}
+
+impl PeerConnection {
+    pub async fn connect(state: &Arc<AppState>, url: &str) -> Result<Self, String> {
+        let keys = state.keys.clone().ok_or("the relay key isn't loaded")?;
+        let (ws, _) = timeout(REPLY_TIMEOUT, connect_async(url))
+            .await
+            .map_err(|_| "timed out connecting".to_string())?
+            .map_err(|e| format!("could not connect: {}", e))?;
+        let mut conn = Self { url: url.to_string(), ws, keys, auth_id: None };
+
+        // Relays that want AUTH challenge straight away; answer before asking for anything
+        if let Ok(message) = timeout(CHALLENGE_WAIT, conn.next_message()).await {
+            if let Some(challenge) = message?.as_deref().and_then(auth_challenge) {
+                conn.authenticate(&challenge).await?;
+            }
+        }
+        Ok(conn)
+    }
+
+    pub async fn send(&mut self, message: Value) -> Result<(), String> {
+        self.ws
+            .send(Message::Text(message.to_string()))
+            .await
+            .map_err(|e| format!("send failed: {}", e))
+    }
+
+    /// Websocket ping, to keep a quiet connection open and prove it's alive
+    pub async fn ping(&mut self) -> Result<(), String> {
+        self.ws
+            .send(Message::Ping(Vec::new()))
+            .await
+            .map_err(|e| format!("send failed: {}", e))
+    }
+
+    pub async fn close(mut self) {
+        let _ = self.ws.close(None).await;
+    }
+
+    /// Wait for the next message that needs a caller's attention, handling
+    /// NOTICEs and late AUTH challenges along the way
+    pub async fn recv(&mut self) -> Result<Vec<Value>, String> {
+        self.recv_within(REPLY_TIMEOUT)
+            .await?
+            .ok_or_else(|| "timed out waiting for the peer".to_string())
+    }
+
+    /// Like `recv`, but `None` once the peer has sent nothing at all for `wait`
+    pub async fn recv_within(&mut self, wait: Duration) -> Result<Option<Vec<Value>>, String> {
+        loop {
+            let Ok(message) = timeout(wait, self.next_message()).await else {
+                return Ok(None);
+            };
+            let Some(message) = message? else { continue };
+            if let Some(challenge) = auth_challenge(&message) {
+                self.authenticate(&challenge).await?;
+                continue;
+            }
+            let kind = message.first().and_then(|v| v.as_str());
+            if kind == Some("OK") && message.get(1).and_then(|v| v.as_str()) == self.auth_id.as_deref() {
+                if message.get(2).and_then(|v| v.as_bool()) != Some(true) {
+                    warn!("{} refused our AUTH: {}", self.url, message.get(3).and_then(|v| v.as_str()).unwrap_or(""));
+                }
+                continue;
+            }
+            match kind {
+                Some("NOTICE") => debug!("NOTICE from {}: {}", self.url, message.get(1).and_then(|v| v.as_str()).unwrap_or("")),
+                Some(_) => return Ok(Some(message)),
+                None => {}
+            }
+        }
+    }
+
+    /// The next relay message as a JSON array, `None` for anything else
+    async fn next_message(&mut self) -> Result<Option<Vec<Value>>, String> {
+        match self.ws.next().await {
+            Some(Ok(Message::Text(text))) => Ok(serde_json::from_str::<Vec<Value>>(&text).ok()),
+            Some(Ok(Message::Close(_))) | None => Err("peer closed the connection".to_string()),
+            Some(Ok(_)) => Ok(None),
+            Some(Err(e)) => Err(format!("receive failed: {}", e)),
+        }
+    }
+
+    /// NIP-42: sign the challenge with the relay key. The peer handles messages
+    /// in order, so there's no need to wait for its OK before carrying on.
+    async fn authenticate(&mut self, challenge: &str) -> Result<(), String> {
+        let relay = Url::parse(&self.url).map_err(|e| format!("invalid peer URL: {}", e))?;
+        let event = EventBuilder::auth(challenge, relay)
+            .to_event(&self.keys)
+            .map_err(|e| format!("could not sign AUTH: {}", e))?;
+        self.auth_id = Some(event.id.to_hex());
+        self.send(json!(["AUTH", event])).await
+    }
+}
+
+/// The challenge in an `["AUTH", challenge]` message
+fn auth_challenge(message: &[Value]) -> Option<String> {
+    match message {
+        [kind, challenge] if kind.as_str() == Some("AUTH") => challenge.as_str().map(str::to_string),
+        _ => None,
+    }
+}
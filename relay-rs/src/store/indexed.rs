@@ -0,0 +1,544 @@
+// In-memory negentropy index in front of a database backend
+//
+// Mirrors run NIP-77 syncs over the whole relay, and scanning millions of rows
+// for every NEG-OPEN is too slow. This wrapper keeps every visible event's
+// (created_at, id) in a sorted set, maintained on each write that goes through
+// the store, and answers NEG-OPEN filters that only restrict the time range from
+// it. Filters naming ids, authors, kinds or tags are selective enough to go to SQL.
+//
+// negentropy 0.3 (the version nostr 0.29 pins) has no storage-backed tree, so a
+// session still copies its range out of the index; it just no longer has to
+// wait on the database for it.
+
+use super::{
+    AuditEntry, AuditRecord, Deletion, EventStore, IndexChange, IndexKey, ListEntry, PrefixQuery, Report, StoreResult,
+    UserAccess, WhitelistStatus,
+};
+use async_trait::async_trait;
+use nostr::{Event, EventId, Filter, Timestamp};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    ops::Bound,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{error, info};
+
+/// Rows handed from the initial load to the index per lock
+const LOAD_BATCH: usize = 10_000;
+
+/// Index changes buffered for other relay instances
+const CHANGES_BUFFER: usize = 10_000;
+
+#[derive(Default)]
+struct Index {
+    items: BTreeSet<IndexKey>,
+    /// NIP-40: entries to drop once their expiration passes
+    expiring: BTreeMap<i64, Vec<IndexKey>>,
+    /// Removed before the initial load finished, so the load mustn't add them back
+    removed_while_loading: HashSet<IndexKey>,
+}
+
+impl Index {
+    fn insert(&mut self, key: IndexKey, expires_at: Option<i64>) {
+        self.items.insert(key);
+        if let Some(expires_at) = expires_at {
+            self.expiring.entry(expires_at).or_default().push(key);
+        }
+    }
+
+    fn remove(&mut self, key: &IndexKey, loading: bool) {
+        self.items.remove(key);
+        if loading {
+            self.removed_while_loading.insert(*key);
+        }
+    }
+
+    fn has_expired(&self, now: i64) -> bool {
+        self.expiring.first_key_value().is_some_and(|(expires_at, _)| *expires_at <= now)
+    }
+
+    /// Matches the database's visibility rule: expired once `expires_at <= now`
+    fn drop_expired(&mut self, now: i64) {
+        while self.has_expired(now) {
+            if let Some((_, keys)) = self.expiring.pop_first() {
+                for key in keys {
+                    self.items.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+pub struct IndexedStore {
+    inner: Arc<dyn EventStore>,
+    index: RwLock<Index>,
+    /// Until a load finishes, NEG-OPEN is answered from the database. Only
+    /// changed while holding the `index` lock.
+    ready: AtomicBool,
+    /// Held for the whole of a load, so reloads don't interleave
+    loading: tokio::sync::Mutex<()>,
+    /// Every change made through this store, for other instances sharing the database
+    changes: broadcast::Sender<IndexChange>,
+}
+
+impl IndexedStore {
+    /// Wrap `inner` and start loading the index in the background
+    pub fn wrap(inner: Arc<dyn EventStore>) -> Arc<Self> {
+        let store = Self::new(inner);
+        let loader = store.clone();
+        tokio::spawn(async move { loader.load().await });
+        store
+    }
+
+    fn new(inner: Arc<dyn EventStore>) -> Arc<Self> {
+        Arc::new(Self {
+            inner,
+            index: RwLock::new(Index::default()),
+            ready: AtomicBool::new(false),
+            loading: tokio::sync::Mutex::new(()),
+            changes: broadcast::channel(CHANGES_BUFFER).0,
+        })
+    }
+
+    async fn load(&self) {
+        let _loading = self.loading.lock().await;
+        self.fill().await;
+    }
+
+    /// Fill the empty index from the database. Writes made meanwhile go to the
+    /// index too, and removals are remembered so the rows being read don't undo them.
+    async fn fill(&self) {
+        let (tx, mut rx) = mpsc::channel(LOAD_BATCH);
+        let inner = self.inner.clone();
+        let query = tokio::spawn(async move { inner.stream_index(tx).await });
+
+        let mut batch = Vec::with_capacity(LOAD_BATCH);
+        while rx.recv_many(&mut batch, LOAD_BATCH).await > 0 {
+            let mut index = self.index.write().unwrap_or_else(PoisonError::into_inner);
+            for (key, expires_at) in batch.drain(..) {
+                if !index.removed_while_loading.contains(&key) {
+                    index.insert(key, expires_at);
+                }
+            }
+        }
+
+        match query.await {
+            Ok(Ok(loaded)) => {
+                let mut index = self.index.write().unwrap_or_else(PoisonError::into_inner);
+                index.removed_while_loading = HashSet::new();
+                self.ready.store(true, Ordering::Release);
+                info!("Negentropy index loaded: {} events", loaded);
+            }
+            Ok(Err(e)) => error!("Failed to load negentropy index, NIP-77 will query the database: {}", e),
+            Err(e) => error!("Negentropy index load panicked: {}", e),
+        }
+    }
+
+    fn insert(&self, key: IndexKey, expires_at: Option<i64>) {
+        self.apply_insert(key, expires_at);
+        let _ = self.changes.send(IndexChange::Added { key, expires_at });
+    }
+
+    fn remove(&self, keys: &[IndexKey]) {
+        if keys.is_empty() {
+            return;
+        }
+        self.apply_remove(keys);
+        let _ = self.changes.send(IndexChange::Removed { keys: keys.to_vec() });
+    }
+
+    fn apply_insert(&self, key: IndexKey, expires_at: Option<i64>) {
+        self.index.write().unwrap_or_else(PoisonError::into_inner).insert(key, expires_at);
+    }
+
+    fn apply_remove(&self, keys: &[IndexKey]) {
+        let mut index = self.index.write().unwrap_or_else(PoisonError::into_inner);
+        let loading = !self.ready.load(Ordering::Acquire);
+        for key in keys {
+            index.remove(key, loading);
+        }
+    }
+
+    /// The index entry for a visible event
+    async fn lookup(&self, id: &str) -> Option<(IndexKey, Option<i64>)> {
+        let id = EventId::from_hex(id).ok()?;
+        let event = self.inner.query(&Filter::new().id(id), 1).await.ok()?.into_iter().next()?;
+        let expires_at = event.expiration().map(|ts| ts.as_u64() as i64);
+        Some(((event.created_at.as_u64(), event.id), expires_at))
+    }
+
+    /// Up to `limit` indexed events created within [since, until], or None
+    /// while the index is loading
+    fn range(&self, since: u64, until: u64, limit: usize) -> Option<Vec<IndexKey>> {
+        let now = Timestamp::now().as_u64() as i64;
+        if self.index.read().unwrap_or_else(PoisonError::into_inner).has_expired(now) {
+            self.index.write().unwrap_or_else(PoisonError::into_inner).drop_expired(now);
+        }
+
+        let index = self.index.read().unwrap_or_else(PoisonError::into_inner);
+        if !self.ready.load(Ordering::Acquire) {
+            return None;
+        }
+        if since > until {
+            return Some(Vec::new());
+        }
+        let lowest = EventId::all_zeros();
+        let highest = EventId::from_slice(&[0xff; 32]).unwrap_or(lowest);
+        Some(index.items
+            .range((Bound::Included((since, lowest)), Bound::Included((until, highest))))
+            .take(limit)
+            .copied()
+            .collect())
+    }
+}
+
+/// Whether a filter only narrows the time range, so the index can answer it
+fn time_range_only(filter: &Filter) -> bool {
+    filter.ids.as_ref().is_none_or(|ids| ids.is_empty())
+        && filter.authors.as_ref().is_none_or(|authors| authors.is_empty())
+        && filter.kinds.as_ref().is_none_or(|kinds| kinds.is_empty())
+        && filter.generic_tags.is_empty()
+        && filter.search.is_none()
+}
+
+#[async_trait]
+impl EventStore for IndexedStore {
+    async fn save_event(&self, event: &Event, expires_at: Option<i64>) -> StoreResult<bool> {
+        let stored = self.inner.save_event(event, expires_at).await?;
+        if stored {
+            self.insert((event.created_at.as_u64(), event.id), expires_at);
+        }
+        Ok(stored)
+    }
+
+    async fn query(&self, filter: &Filter, limit: usize) -> StoreResult<Vec<Event>> {
+        self.inner.query(filter, limit).await
+    }
+
+    async fn query_prefix(&self, query: &PrefixQuery) -> StoreResult<Vec<Event>> {
+        self.inner.query_prefix(query).await
+    }
+
+    async fn count(&self, filter: &Filter) -> StoreResult<usize> {
+        self.inner.count(filter).await
+    }
+
+    async fn stream(&self, filter: &Filter, tx: mpsc::Sender<Event>) -> StoreResult<u64> {
+        self.inner.stream(filter, tx).await
+    }
+
+    async fn delete(&self, target: Deletion<'_>) -> StoreResult<Vec<IndexKey>> {
+        let removed = self.inner.delete(target).await?;
+        self.remove(&removed);
+        Ok(removed)
+    }
+
+    async fn negentropy_items(&self, filter: &Filter, limit: usize) -> StoreResult<Vec<IndexKey>> {
+        if time_range_only(filter) {
+            let since = filter.since.map_or(0, |ts| ts.as_u64());
+            let until = filter.until.map_or(u64::MAX, |ts| ts.as_u64());
+            if let Some(items) = self.range(since, until, limit) {
+                return Ok(items);
+            }
+        }
+        self.inner.negentropy_items(filter, limit).await
+    }
+
+    async fn stream_index(&self, tx: mpsc::Sender<(IndexKey, Option<i64>)>) -> StoreResult<u64> {
+        self.inner.stream_index(tx).await
+    }
+
+    fn index_changes(&self) -> Option<broadcast::Receiver<IndexChange>> {
+        Some(self.changes.subscribe())
+    }
+
+    fn spawn_maintenance(&self) {
+        self.inner.spawn_maintenance();
+    }
+
+    fn apply_index_change(&self, change: &IndexChange) {
+        match change {
+            IndexChange::Added { key, expires_at } => self.apply_insert(*key, *expires_at),
+            IndexChange::Removed { keys } => self.apply_remove(keys),
+        }
+    }
+
+    async fn reload_index(&self) {
+        let _loading = self.loading.lock().await;
+        {
+            let mut index = self.index.write().unwrap_or_else(PoisonError::into_inner);
+            self.ready.store(false, Ordering::Release);
+            *index = Index::default();
+        }
+        info!("Reloading negentropy index");
+        self.fill().await;
+    }
+
+    async fn user_access(&self, pubkey: &str) -> StoreResult<Option<UserAccess>> {
+        self.inner.user_access(pubkey).await
+    }
+
+    async fn list_users(&self, status: WhitelistStatus) -> StoreResult<Vec<ListEntry>> {
+        self.inner.list_users(status).await
+    }
+
+    async fn ensure_user(&self, npub: &str, pubkey: &str, status: WhitelistStatus, notes: &str) -> StoreResult<bool> {
+        self.inner.ensure_user(npub, pubkey, status, notes).await
+    }
+
+    async fn register_relay_user(&self, npub: &str, pubkey: &str) -> StoreResult<bool> {
+        self.inner.register_relay_user(npub, pubkey).await
+    }
+
+    async fn allow_user(&self, npub: &str, pubkey: &str, reason: Option<&str>) -> StoreResult<()> {
+        self.inner.allow_user(npub, pubkey, reason).await
+    }
+
+    async fn banned_users(&self) -> StoreResult<Vec<ListEntry>> {
+        self.inner.banned_users().await
+    }
+
+    async fn ban_user(&self, npub: &str, pubkey: &str, reason: Option<&str>) -> StoreResult<()> {
+        self.inner.ban_user(npub, pubkey, reason).await
+    }
+
+    async fn set_whitelist_status(&self, pubkey: &str, status: WhitelistStatus) -> StoreResult<u64> {
+        self.inner.set_whitelist_status(pubkey, status).await
+    }
+
+    async fn record_violation(&self, pubkey: &str) -> StoreResult<u64> {
+        self.inner.record_violation(pubkey).await
+    }
+
+    async fn set_admin(&self, pubkey: &str, is_admin: bool) -> StoreResult<u64> {
+        self.inner.set_admin(pubkey, is_admin).await
+    }
+
+    async fn ban_event(&self, id: &str, reason: Option<&str>) -> StoreResult<()> {
+        let entry = self.lookup(id).await;
+        self.inner.ban_event(id, reason).await?;
+        if let Some((key, _)) = entry {
+            self.remove(&[key]);
+        }
+        Ok(())
+    }
+
+    async fn hide_event(&self, id: &str, reason: &str) -> StoreResult<bool> {
+        let entry = self.lookup(id).await;
+        let hidden = self.inner.hide_event(id, reason).await?;
+        if let Some((key, _)) = entry {
+            self.remove(&[key]);
+        }
+        Ok(hidden)
+    }
+
+    async fn allow_event(&self, id: &str) -> StoreResult<u64> {
+        let lifted = self.inner.allow_event(id).await?;
+        if let Some((key, expires_at)) = self.lookup(id).await {
+            self.insert(key, expires_at);
+        }
+        Ok(lifted)
+    }
+
+    async fn banned_events(&self) -> StoreResult<Vec<ListEntry>> {
+        self.inner.banned_events().await
+    }
+
+    async fn is_event_banned(&self, id: &str) -> StoreResult<bool> {
+        self.inner.is_event_banned(id).await
+    }
+
+    async fn events_needing_moderation(&self) -> StoreResult<Vec<ListEntry>> {
+        self.inner.events_needing_moderation().await
+    }
+
+    async fn record_report(&self, report: &Report<'_>) -> StoreResult<u64> {
+        self.inner.record_report(report).await
+    }
+
+    async fn resolve_reports(&self, event_id: &str, upheld: bool) -> StoreResult<u64> {
+        self.inner.resolve_reports(event_id, upheld).await
+    }
+
+    async fn allow_kind(&self, kind: u64) -> StoreResult<()> {
+        self.inner.allow_kind(kind).await
+    }
+
+    async fn disallow_kind(&self, kind: u64) -> StoreResult<()> {
+        self.inner.disallow_kind(kind).await
+    }
+
+    async fn allowed_kinds(&self) -> StoreResult<Vec<u64>> {
+        self.inner.allowed_kinds().await
+    }
+
+    async fn disallowed_kinds(&self) -> StoreResult<Vec<u64>> {
+        self.inner.disallowed_kinds().await
+    }
+
+    async fn block_ip(&self, ip: &str, reason: Option<&str>) -> StoreResult<()> {
+        self.inner.block_ip(ip, reason).await
+    }
+
+    async fn unblock_ip(&self, ip: &str) -> StoreResult<u64> {
+        self.inner.unblock_ip(ip).await
+    }
+
+    async fn blocked_ips(&self) -> StoreResult<Vec<ListEntry>> {
+        self.inner.blocked_ips().await
+    }
+
+    async fn is_ip_blocked(&self, ip: &str) -> StoreResult<bool> {
+        self.inner.is_ip_blocked(ip).await
+    }
+
+    async fn setting(&self, key: &str) -> StoreResult<Option<String>> {
+        self.inner.setting(key).await
+    }
+
+    async fn set_setting(&self, key: &str, value: &str) -> StoreResult<()> {
+        self.inner.set_setting(key, value).await
+    }
+
+    async fn mirror_cursor(&self, source: &str) -> StoreResult<Option<i64>> {
+        self.inner.mirror_cursor(source).await
+    }
+
+    async fn set_mirror_cursor(&self, source: &str, created_at: i64) -> StoreResult<()> {
+        self.inner.set_mirror_cursor(source, created_at).await
+    }
+
+    async fn append_audit(&self, entry: &AuditEntry) -> StoreResult<AuditRecord> {
+        self.inner.append_audit(entry).await
+    }
+
+    async fn audit_log(&self, limit: i64, before_id: Option<i64>) -> StoreResult<Vec<AuditRecord>> {
+        self.inner.audit_log(limit, before_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::SqliteStore;
+    use nostr::{EventBuilder, Keys, Kind};
+
+    /// A loaded index over `events`, plus the database behind it
+    async fn indexed(events: &[&Event]) -> (Arc<dyn EventStore>, Arc<IndexedStore>) {
+        let (inner, store) = unloaded(events).await;
+        store.load().await;
+        (inner, store)
+    }
+
+    async fn unloaded(events: &[&Event]) -> (Arc<dyn EventStore>, Arc<IndexedStore>) {
+        let inner: Arc<dyn EventStore> = Arc::new(SqliteStore::connect("sqlite::memory:").await.unwrap());
+        for event in events {
+            assert!(inner.save_event(event, None).await.unwrap());
+        }
+        (inner.clone(), IndexedStore::new(inner))
+    }
+
+    fn event(kind: u64, created_at: u64) -> Event {
+        EventBuilder::new(Kind::from(kind), "", [])
+            .custom_created_at(Timestamp::from(created_at))
+            .to_event(&Keys::generate())
+            .unwrap()
+    }
+
+    fn key(event: &Event) -> IndexKey {
+        (event.created_at.as_u64(), event.id)
+    }
+
+    async fn items(store: &IndexedStore, filter: Filter) -> Vec<IndexKey> {
+        let mut items = store.negentropy_items(&filter, 100).await.unwrap();
+        items.sort();
+        items
+    }
+
+    #[tokio::test]
+    async fn time_range_filters_are_answered_from_the_index() {
+        let (old, new, unindexed) = (event(1, 100), event(1, 200), event(1, 300));
+        let (inner, store) = indexed(&[&old]).await;
+        store.save_event(&new, None).await.unwrap();
+        // Written behind the index's back, so only SQL knows about it
+        inner.save_event(&unindexed, None).await.unwrap();
+
+        assert_eq!(items(&store, Filter::new()).await, vec![key(&old), key(&new)]);
+        assert_eq!(items(&store, Filter::new().since(Timestamp::from(150))).await, vec![key(&new)]);
+        assert_eq!(items(&store, Filter::new().until(Timestamp::from(150))).await, vec![key(&old)]);
+        assert!(items(&store, Filter::new().since(Timestamp::from(250)).until(Timestamp::from(150))).await.is_empty());
+        assert_eq!(store.negentropy_items(&Filter::new(), 1).await.unwrap(), vec![key(&old)]);
+
+        // Anything more selective than a time range goes to the database
+        let selective = items(&store, Filter::new().kind(Kind::TextNote)).await;
+        assert_eq!(selective, vec![key(&old), key(&new), key(&unindexed)]);
+    }
+
+    #[tokio::test]
+    async fn expired_entries_drop_out_of_answers() {
+        let (expired, expiring, lasting) = (event(1, 100), event(1, 200), event(1, 300));
+        let (_, store) = indexed(&[]).await;
+        let now = Timestamp::now().as_u64() as i64;
+        store.apply_index_change(&IndexChange::Added { key: key(&expired), expires_at: Some(now) });
+        store.apply_index_change(&IndexChange::Added { key: key(&expiring), expires_at: Some(now + 3_600) });
+        store.apply_index_change(&IndexChange::Added { key: key(&lasting), expires_at: None });
+
+        assert_eq!(items(&store, Filter::new()).await, vec![key(&expiring), key(&lasting)]);
+        let index = store.index.read().unwrap();
+        assert_eq!(index.expiring.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn removals_while_loading_are_not_undone_by_the_load() {
+        let (removed, kept) = (event(1, 100), event(1, 200));
+        let (_, store) = unloaded(&[&removed, &kept]).await;
+
+        // Another instance deleted it after our load's snapshot was taken
+        store.apply_index_change(&IndexChange::Removed { keys: vec![key(&removed)] });
+        // Not ready yet, so the database answers
+        assert_eq!(items(&store, Filter::new()).await, vec![key(&removed), key(&kept)]);
+
+        store.load().await;
+        assert_eq!(items(&store, Filter::new()).await, vec![key(&kept)]);
+        assert!(store.index.read().unwrap().removed_while_loading.is_empty());
+
+        // Once loaded, removals aren't remembered
+        store.apply_index_change(&IndexChange::Removed { keys: vec![key(&kept)] });
+        assert!(items(&store, Filter::new()).await.is_empty());
+        assert!(store.index.read().unwrap().removed_while_loading.is_empty());
+    }
+
+    #[tokio::test]
+    async fn changes_from_other_instances_are_applied_but_not_passed_on() {
+        let (theirs, ours) = (event(1, 100), event(1, 200));
+        let (_, store) = indexed(&[]).await;
+        let mut changes = store.index_changes().unwrap();
+
+        store.apply_index_change(&IndexChange::Added { key: key(&theirs), expires_at: None });
+        assert_eq!(items(&store, Filter::new()).await, vec![key(&theirs)]);
+        store.apply_index_change(&IndexChange::Removed { keys: vec![key(&theirs)] });
+        assert!(items(&store, Filter::new()).await.is_empty());
+        assert!(changes.try_recv().is_err());
+
+        store.save_event(&ours, None).await.unwrap();
+        match changes.try_recv().unwrap() {
+            IndexChange::Added { key: added, expires_at: None } => assert_eq!(added, key(&ours)),
+            _ => panic!("expected the saved event to be shared"),
+        }
+    }
+
+    #[tokio::test]
+    async fn reloading_drops_entries_the_database_does_not_have() {
+        let (stored, stale) = (event(1, 100), event(1, 200));
+        let (_, store) = indexed(&[&stored]).await;
+        store.apply_index_change(&IndexChange::Added { key: key(&stale), expires_at: None });
+        assert_eq!(items(&store, Filter::new()).await, vec![key(&stored), key(&stale)]);
+
+        store.reload_index().await;
+        assert_eq!(items(&store, Filter::new()).await, vec![key(&stored)]);
+    }
+}
@@ -0,0 +1,323 @@
+// Storage backends
+//
+// Everything the relay persists goes through `EventStore`, so the socket handlers
+// don't care whether they're talking to the Prisma-managed Postgres database or an
+// embedded SQLite file (handy for small deployments and local testing).
+
+mod indexed;
+mod postgres;
+mod sqlite;
+
+use async_trait::async_trait;
+use nostr::{Event, EventId, Filter, JsonUtil, Tag};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc};
+
+pub use indexed::IndexedStore;
+pub use postgres::PostgresStore;
+pub use sqlite::SqliteStore;
+
+pub type StoreResult<T> = Result<T, sqlx::Error>;
+
+/// An event's place in a negentropy set: (created_at, id)
+pub type IndexKey = (u64, EventId);
+
+/// A change one relay instance made to its negentropy index, for the others to
+/// repeat (they share the database but not the index)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum IndexChange {
+    Added { key: IndexKey, expires_at: Option<i64> },
+    Removed { keys: Vec<IndexKey> },
+}
+
+/// Mirrors the `WhitelistStatus` enum in prisma/schema.prisma
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhitelistStatus {
+    Pending,
+    Active,
+    Paused,
+    Revoked,
+    Vanished,
+}
+
+impl WhitelistStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WhitelistStatus::Pending => "PENDING",
+            WhitelistStatus::Active => "ACTIVE",
+            WhitelistStatus::Paused => "PAUSED",
+            WhitelistStatus::Revoked => "REVOKED",
+            WhitelistStatus::Vanished => "VANISHED",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "PENDING" => Some(WhitelistStatus::Pending),
+            "ACTIVE" => Some(WhitelistStatus::Active),
+            "PAUSED" => Some(WhitelistStatus::Paused),
+            "REVOKED" => Some(WhitelistStatus::Revoked),
+            "VANISHED" => Some(WhitelistStatus::Vanished),
+            _ => None,
+        }
+    }
+}
+
+/// What the relay needs to know about a user to decide on access
+#[derive(Debug, Clone)]
+pub struct UserAccess {
+    pub is_admin: bool,
+    pub status: Option<WhitelistStatus>,
+    /// Set by the web app after repeated blacklist violations; overrides everything else
+    pub permanently_banned: bool,
+}
+
+/// An entry in one of the NIP-86 management lists (a pubkey, event id or IP)
+#[derive(Debug, Clone)]
+pub struct ListEntry {
+    pub value: String,
+    pub reason: Option<String>,
+}
+
+/// A management action as written to the audit log
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    /// `None` for actions run from the CLI
+    pub admin_pubkey: Option<String>,
+    /// "websocket", "http" or "cli"
+    pub source: String,
+    pub method: String,
+    pub params: serde_json::Value,
+    /// The method's result, or its error message
+    pub result: serde_json::Value,
+    pub success: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    pub id: i64,
+    pub created_at: i64,
+    pub entry: AuditEntry,
+}
+
+/// A NIP-56 report against a pubkey, and optionally one of their events
+pub struct Report<'a> {
+    /// Id of the kind 1984 event
+    pub id: &'a str,
+    pub reporter: &'a str,
+    pub target_pubkey: &'a str,
+    pub event_id: Option<&'a str>,
+    /// "spam", "illegal", "impersonation", ...
+    pub report_type: Option<&'a str>,
+    pub content: &'a str,
+}
+
+/// Which events a delete should remove
+pub enum Deletion<'a> {
+    /// NIP-09: a single event, only if `author` published it
+    Event { id: &'a str, author: &'a str },
+    /// NIP-33: every stored version of an addressable event
+    Address { author: &'a str, kind: u64, identifier: &'a str },
+    /// NIP-62: everything an author has published
+    Author(&'a str),
+}
+
+/// Loose REQ filter used for clients that send author prefixes instead of full pubkeys
+pub struct PrefixQuery {
+    pub kinds: Vec<i32>,
+    pub authors: Vec<String>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub limit: i64,
+}
+
+#[async_trait]
+pub trait EventStore: Send + Sync {
+    /// Store an event. Returns `false` if it was already stored.
+    async fn save_event(&self, event: &Event, expires_at: Option<i64>) -> StoreResult<bool>;
+
+    /// Newest-first events matching `filter` (unexpired only)
+    async fn query(&self, filter: &Filter, limit: usize) -> StoreResult<Vec<Event>>;
+
+    async fn query_prefix(&self, query: &PrefixQuery) -> StoreResult<Vec<Event>>;
+
+    async fn count(&self, filter: &Filter) -> StoreResult<usize>;
+
+    /// Send every event matching `filter` to `tx`, oldest first, without
+    /// loading them all into memory. Returns the number of events sent.
+    async fn stream(&self, filter: &Filter, tx: mpsc::Sender<Event>) -> StoreResult<u64>;
+
+    /// Returns the events removed
+    async fn delete(&self, target: Deletion<'_>) -> StoreResult<Vec<IndexKey>>;
+
+    /// Visible events matching `filter`, for building a negentropy session.
+    /// Returns at most `limit` of them; the filter's own `limit` is ignored
+    /// since sync needs the whole set.
+    async fn negentropy_items(&self, filter: &Filter, limit: usize) -> StoreResult<Vec<IndexKey>>;
+
+    /// Send every visible event to `tx` with its NIP-40 expiration, in no
+    /// particular order. Returns the number sent.
+    async fn stream_index(&self, tx: mpsc::Sender<(IndexKey, Option<i64>)>) -> StoreResult<u64>;
+
+    /// Changes this instance makes to an in-memory negentropy index, if it keeps one
+    fn index_changes(&self) -> Option<broadcast::Receiver<IndexChange>> {
+        None
+    }
+
+    /// Repeat a change another instance made to its negentropy index
+    fn apply_index_change(&self, _change: &IndexChange) {}
+
+    /// Rebuild an in-memory negentropy index from the database, after changes
+    /// other instances made to theirs may have been missed
+    async fn reload_index(&self) {}
+
+    /// Start schema upkeep too slow to hold up startup. Only the server calls
+    /// this, so short-lived CLI commands never leave it half done.
+    fn spawn_maintenance(&self) {}
+
+    async fn user_access(&self, pubkey: &str) -> StoreResult<Option<UserAccess>>;
+
+    /// Users with a whitelist status, with the notes recorded for them. The relay's
+    /// own pubkey is never listed.
+    async fn list_users(&self, status: WhitelistStatus) -> StoreResult<Vec<ListEntry>>;
+
+    /// REVOKED and permanently banned users, with the notes recorded for them
+    async fn banned_users(&self) -> StoreResult<Vec<ListEntry>>;
+
+    /// Create the user with `status` and `notes` unless they already exist, leaving
+    /// an existing user untouched. Returns whether a user was created.
+    async fn ensure_user(&self, npub: &str, pubkey: &str, status: WhitelistStatus, notes: &str) -> StoreResult<bool>;
+
+    /// Mark the user as the relay's own pubkey, creating them ACTIVE if needed. An
+    /// existing user keeps their status. Returns whether a user was created.
+    async fn register_relay_user(&self, npub: &str, pubkey: &str) -> StoreResult<bool>;
+
+    /// Create the user if needed and mark them ACTIVE. A `reason` replaces their notes.
+    async fn allow_user(&self, npub: &str, pubkey: &str, reason: Option<&str>) -> StoreResult<()>;
+
+    /// Create the user if needed and mark them REVOKED. A `reason` replaces their notes.
+    async fn ban_user(&self, npub: &str, pubkey: &str, reason: Option<&str>) -> StoreResult<()>;
+
+    /// Returns the number of users updated
+    async fn set_whitelist_status(&self, pubkey: &str, status: WhitelistStatus) -> StoreResult<u64>;
+
+    /// Count an event from this user that relay policy rejected.
+    /// Returns the number of users updated (0 if the pubkey isn't a user).
+    async fn record_violation(&self, pubkey: &str) -> StoreResult<u64>;
+
+    /// Returns the number of users updated
+    async fn set_admin(&self, pubkey: &str, is_admin: bool) -> StoreResult<u64>;
+
+    /// Banned events are rejected on publish and hidden from queries
+    async fn ban_event(&self, id: &str, reason: Option<&str>) -> StoreResult<()>;
+
+    /// Hide an event pending review, like a ban that isn't listed as one.
+    /// Returns `false` if it was already hidden or banned.
+    async fn hide_event(&self, id: &str, reason: &str) -> StoreResult<bool>;
+
+    /// Lifts a ban or a pending-review hide. Returns the number lifted.
+    async fn allow_event(&self, id: &str) -> StoreResult<u64>;
+
+    /// Bans decided by an admin (not events hidden pending review)
+    async fn banned_events(&self) -> StoreResult<Vec<ListEntry>>;
+
+    async fn is_event_banned(&self, id: &str) -> StoreResult<bool>;
+
+    /// Reported events still waiting for an admin decision
+    async fn events_needing_moderation(&self) -> StoreResult<Vec<ListEntry>>;
+
+    /// File a report on the admin message board. Returns how many distinct
+    /// pubkeys have open reports against the reported event (0 if none was named).
+    async fn record_report(&self, report: &Report<'_>) -> StoreResult<u64>;
+
+    /// Close the open reports against an event: `upheld` when it was banned,
+    /// not when it was allowed. Returns the number of reports closed.
+    async fn resolve_reports(&self, event_id: &str, upheld: bool) -> StoreResult<u64>;
+
+    /// Lift a `disallow_kind` of this kind, or else add it to the allow-list
+    async fn allow_kind(&self, kind: u64) -> StoreResult<()>;
+
+    /// Refuse a kind, taking it off the allow-list. Takes effect whether or not
+    /// the allow-list is empty.
+    async fn disallow_kind(&self, kind: u64) -> StoreResult<()>;
+
+    /// Empty means every kind that isn't disallowed is accepted
+    async fn allowed_kinds(&self) -> StoreResult<Vec<u64>>;
+
+    async fn disallowed_kinds(&self) -> StoreResult<Vec<u64>>;
+
+    async fn block_ip(&self, ip: &str, reason: Option<&str>) -> StoreResult<()>;
+
+    /// Returns the number of IPs unblocked
+    async fn unblock_ip(&self, ip: &str) -> StoreResult<u64>;
+
+    async fn blocked_ips(&self) -> StoreResult<Vec<ListEntry>>;
+
+    async fn is_ip_blocked(&self, ip: &str) -> StoreResult<bool>;
+
+    /// Relay information overrides set through NIP-86
+    async fn setting(&self, key: &str) -> StoreResult<Option<String>>;
+
+    async fn set_setting(&self, key: &str, value: &str) -> StoreResult<()>;
+
+    /// Newest `created_at` ingested from a REQ mirroring source
+    async fn mirror_cursor(&self, source: &str) -> StoreResult<Option<i64>>;
+
+    /// Move a source's cursor forward; an older `created_at` leaves it alone
+    async fn set_mirror_cursor(&self, source: &str, created_at: i64) -> StoreResult<()>;
+
+    async fn append_audit(&self, entry: &AuditEntry) -> StoreResult<AuditRecord>;
+
+    /// Newest first, optionally only entries older than `before_id`
+    async fn audit_log(&self, limit: i64, before_id: Option<i64>) -> StoreResult<Vec<AuditRecord>>;
+}
+
+/// Pick a backend from the database URL: `sqlite:` URLs use the embedded store,
+/// anything else is treated as Postgres.
+pub async fn connect(url: &str) -> StoreResult<Arc<dyn EventStore>> {
+    if url.starts_with("sqlite:") {
+        Ok(Arc::new(SqliteStore::connect(url).await?))
+    } else {
+        Ok(Arc::new(PostgresStore::connect(url).await?))
+    }
+}
+
+/// Keep only the events a NEG-OPEN can be answered from
+fn index_key(created_at: i64, id: &str) -> Option<IndexKey> {
+    Some((u64::try_from(created_at).ok()?, EventId::from_hex(id).ok()?))
+}
+
+/// Split a nostr timestamp into epoch seconds and a UTC timestamp.
+/// Returns `None` if either can't hold it.
+pub fn event_timestamps(event: &Event) -> Option<(i64, chrono::DateTime<chrono::Utc>)> {
+    let secs = i64::try_from(event.created_at.as_u64()).ok()?;
+    let dt = chrono::DateTime::from_timestamp(secs, 0)?;
+    Some((secs, dt))
+}
+
+/// Rebuild an event from stored columns
+fn build_event(
+    id: String,
+    pubkey: String,
+    created_at: i64,
+    kind: i64,
+    tags: serde_json::Value,
+    content: String,
+    sig: String,
+) -> Option<Event> {
+    let tags: Vec<Tag> = serde_json::from_value(tags).unwrap_or_default();
+
+    let event_json = serde_json::json!({
+        "id": id,
+        "pubkey": pubkey,
+        "created_at": created_at,
+        "kind": kind,
+        "tags": tags,
+        "content": content,
+        "sig": sig
+    });
+
+    Event::from_json(event_json.to_string()).ok()
+}
@@ -0,0 +1,865 @@
+// Postgres backend, sharing the schema managed by the Next.js app's Prisma models
+
+use super::{
+    build_event, event_timestamps, index_key, AuditEntry, AuditRecord, Deletion, EventStore, IndexKey, ListEntry, PrefixQuery, Report, StoreResult,
+    UserAccess, WhitelistStatus,
+};
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use nostr::{Event, Filter, PublicKey, ToBech32};
+use sqlx::{
+    migrate::Migrator,
+    postgres::{PgPoolOptions, PgRow},
+    PgConnection, Pool, Postgres, QueryBuilder, Row,
+};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+/// `created_at`, read from `createdAt` for rows the backfill hasn't reached yet
+macro_rules! created_at {
+    () => {
+        "CASE WHEN created_at = 0 THEN EXTRACT(EPOCH FROM \"createdAt\")::BIGINT ELSE created_at END"
+    };
+}
+
+const EVENT_COLUMNS: &str = concat!("\"eventId\", pubkey, kind, content, tags, sig, ", created_at!(), " AS created_at");
+/// The columns `row_to_index_key` reads
+const KEY_COLUMNS: &str = concat!("\"eventId\", ", created_at!(), " AS created_at");
+/// Unexpired and not banned through NIP-86
+const VISIBLE: &str = "(\"expiresAt\" IS NULL OR \"expiresAt\" > NOW())
+    AND NOT EXISTS (SELECT 1 FROM banned_events b WHERE b.event_id = events.\"eventId\")";
+
+/// Relay-owned schema, applied on startup. The web app's Prisma schema declares
+/// the same columns (and ignores `_sqlx_migrations`) so `db push` leaves them alone.
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+
+/// Indexes over `events.created_at`, built CONCURRENTLY by `maintain` rather than
+/// by a migration: sqlx runs every migration inside a transaction, which
+/// CONCURRENTLY refuses
+const CREATED_AT_INDEXES: [(&str, &str); 3] = [
+    ("events_created_at_idx", "created_at"),
+    ("events_kind_created_at_idx", "kind, created_at"),
+    ("events_pubkey_created_at_idx", "pubkey, created_at"),
+];
+
+/// Rows per `created_at` backfill transaction
+const BACKFILL_BATCH: i64 = 5000;
+
+/// Session advisory lock held by the instance doing `maintain`
+const MAINTENANCE_LOCK: i64 = 0x7265_6c61_7900_0001;
+
+pub struct PostgresStore {
+    pool: Pool<Postgres>,
+    /// Set once `maintain` has filled in `created_at` everywhere. Until then
+    /// since/until and ordering use the slower `created_at!()` expression, so
+    /// rows stored before the column existed aren't lost from time filters.
+    backfilled: Arc<AtomicBool>,
+}
+
+impl PostgresStore {
+    pub async fn connect(url: &str) -> StoreResult<Self> {
+        let pool = PgPoolOptions::new()
+            .max_connections(50)
+            .connect(url)
+            .await?;
+
+        MIGRATOR.run(&pool).await?;
+        Ok(Self { pool, backfilled: Arc::default() })
+    }
+
+    /// What to filter and order on: the indexed column once it's complete
+    fn time_column(&self) -> &'static str {
+        if self.backfilled.load(Ordering::Acquire) {
+            "created_at"
+        } else {
+            created_at!()
+        }
+    }
+
+    /// Schema work too slow for a startup migration on a large `events` table:
+    /// build the `created_at` indexes without blocking writes, then fill in
+    /// `created_at` for rows stored before the column existed. One instance
+    /// does it at a time, the others waiting and then finding nothing left to
+    /// do; an interrupted run is picked up on the next start.
+    async fn maintain(pool: Pool<Postgres>) -> StoreResult<()> {
+        let mut conn = pool.acquire().await?;
+        sqlx::query("SELECT pg_advisory_lock($1)").bind(MAINTENANCE_LOCK).execute(&mut *conn).await?;
+
+        let result = async {
+            for (name, columns) in CREATED_AT_INDEXES {
+                build_index(&mut conn, name, columns).await?;
+            }
+            backfill_created_at(&mut conn).await
+        }
+        .await;
+
+        sqlx::query("SELECT pg_advisory_unlock($1)").bind(MAINTENANCE_LOCK).execute(&mut *conn).await?;
+        result
+    }
+
+    async fn upsert_user_status(
+        &self,
+        npub: &str,
+        pubkey: &str,
+        status: WhitelistStatus,
+        reason: Option<&str>,
+    ) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO users (id, npub, pubkey, \"whitelistStatus\", \"whitelistNotes\", \"updatedAt\")
+             VALUES ($1, $2, $3, $4::\"WhitelistStatus\", $5, NOW())
+             ON CONFLICT (pubkey) DO UPDATE SET npub = EXCLUDED.npub,
+                 \"whitelistStatus\" = EXCLUDED.\"whitelistStatus\",
+                 \"whitelistNotes\" = COALESCE(EXCLUDED.\"whitelistNotes\", users.\"whitelistNotes\"),
+                 \"updatedAt\" = NOW()"
+        )
+        .bind(nanoid::nanoid!())
+        .bind(npub)
+        .bind(pubkey)
+        .bind(status.as_str())
+        .bind(reason)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Build an index CONCURRENTLY, first dropping any INVALID copy an interrupted
+/// build left behind (IF NOT EXISTS would otherwise keep it forever)
+async fn build_index(conn: &mut PgConnection, name: &str, columns: &str) -> StoreResult<()> {
+    let valid: Option<bool> = sqlx::query_scalar(
+        "SELECT i.indisvalid FROM pg_index i JOIN pg_class c ON c.oid = i.indexrelid WHERE c.relname = $1"
+    )
+    .bind(name)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    match valid {
+        Some(true) => return Ok(()),
+        Some(false) => {
+            sqlx::query(&format!("DROP INDEX CONCURRENTLY IF EXISTS {}", name)).execute(&mut *conn).await?;
+        }
+        None => {}
+    }
+
+    info!("Building index {} on events({})", name, columns);
+    sqlx::query(&format!("CREATE INDEX CONCURRENTLY IF NOT EXISTS {} ON events({})", name, columns))
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Copy `createdAt` into `created_at` for rows still holding the column default,
+/// walking the primary key in short transactions so no lock is held for long
+async fn backfill_created_at(conn: &mut PgConnection) -> StoreResult<()> {
+    // Events really created at the epoch keep 0 and don't count as pending
+    let pending: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM events WHERE created_at = 0 AND EXTRACT(EPOCH FROM \"createdAt\")::BIGINT <> 0)"
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    if !pending {
+        return Ok(());
+    }
+
+    info!("Backfilling events.created_at in batches of {}", BACKFILL_BATCH);
+    let mut after = String::new();
+    let mut updated = 0u64;
+    loop {
+        let row = sqlx::query(
+            "WITH batch AS (SELECT id FROM events WHERE id > $1 ORDER BY id LIMIT $2),
+                  updated AS (
+                      UPDATE events SET created_at = EXTRACT(EPOCH FROM \"createdAt\")::BIGINT
+                      WHERE id IN (SELECT id FROM batch) AND created_at = 0
+                      RETURNING 1
+                  )
+             SELECT (SELECT MAX(id) FROM batch) AS last, (SELECT COUNT(*) FROM updated) AS updated"
+        )
+        .bind(&after)
+        .bind(BACKFILL_BATCH)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        updated += row.get::<i64, _>("updated") as u64;
+        match row.get::<Option<String>, _>("last") {
+            Some(last) => after = last,
+            None => break,
+        }
+    }
+    info!("Backfilled created_at for {} events", updated);
+    Ok(())
+}
+
+fn row_to_index_key(row: &PgRow) -> Option<IndexKey> {
+    index_key(row.get("created_at"), row.get("eventId"))
+}
+
+fn row_to_entry(row: &PgRow) -> ListEntry {
+    ListEntry {
+        value: row.get("value"),
+        reason: row.try_get("reason").unwrap_or(None),
+    }
+}
+
+fn row_to_event(row: &PgRow) -> Option<Event> {
+    build_event(
+        row.try_get("eventId").ok()?,
+        row.try_get("pubkey").ok()?,
+        row.try_get("created_at").ok()?,
+        row.try_get::<i32, _>("kind").ok()? as i64,
+        row.try_get("tags").ok()?,
+        row.try_get("content").ok()?,
+        row.try_get("sig").ok()?,
+    )
+}
+
+/// Append the WHERE clause for a nostr filter, comparing times against `created_at`
+fn push_filter(qb: &mut QueryBuilder<Postgres>, filter: &Filter, created_at: &str) {
+    qb.push(" WHERE ").push(VISIBLE);
+
+    if let Some(ids) = &filter.ids {
+        if !ids.is_empty() {
+            let ids: Vec<String> = ids.iter().map(|id| id.to_hex()).collect();
+            qb.push(" AND \"eventId\" = ANY(").push_bind(ids).push(")");
+        }
+    }
+
+    if let Some(authors) = &filter.authors {
+        if !authors.is_empty() {
+            let authors: Vec<String> = authors.iter().map(|a| a.to_string()).collect();
+            qb.push(" AND pubkey = ANY(").push_bind(authors).push(")");
+        }
+    }
+
+    if let Some(kinds) = &filter.kinds {
+        if !kinds.is_empty() {
+            let kinds: Vec<i32> = kinds.iter().map(|k| k.as_u64() as i32).collect();
+            qb.push(" AND kind = ANY(").push_bind(kinds).push(")");
+        }
+    }
+
+    if let Some(since) = filter.since {
+        qb.push(format!(" AND {} >= ", created_at)).push_bind(since.as_i64());
+    }
+
+    if let Some(until) = filter.until {
+        qb.push(format!(" AND {} <= ", created_at)).push_bind(until.as_i64());
+    }
+
+    for (tag, values) in &filter.generic_tags {
+        let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        qb.push(" AND EXISTS (SELECT 1 FROM jsonb_array_elements(tags) AS t WHERE t->>0 = ")
+            .push_bind(tag.to_string())
+            .push(" AND t->>1 = ANY(")
+            .push_bind(values)
+            .push("))");
+    }
+}
+
+#[async_trait]
+impl EventStore for PostgresStore {
+    fn spawn_maintenance(&self) {
+        let pool = self.pool.clone();
+        let backfilled = self.backfilled.clone();
+        tokio::spawn(async move {
+            match Self::maintain(pool).await {
+                Ok(()) => backfilled.store(true, Ordering::Release),
+                Err(e) => error!("Background schema maintenance failed: {}", e),
+            }
+        });
+    }
+
+    async fn save_event(&self, event: &Event, expires_at: Option<i64>) -> StoreResult<bool> {
+        let tags_json = serde_json::to_value(&event.tags).unwrap_or(serde_json::Value::Null);
+        let (created_at_epoch, created_at) = event_timestamps(event)
+            .ok_or_else(|| sqlx::Error::Protocol("created_at out of range".to_string()))?;
+        let expires_at = expires_at.and_then(|ts| chrono::DateTime::from_timestamp(ts, 0));
+
+        let result = sqlx::query(
+            "INSERT INTO events (id, \"eventId\", pubkey, kind, content, tags, sig, \"createdAt\", created_at, \"receivedAt\", \"expiresAt\")
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, NOW(), $10)
+             ON CONFLICT (\"eventId\") DO NOTHING"
+        )
+        .bind(nanoid::nanoid!())
+        .bind(event.id.to_string())
+        .bind(event.pubkey.to_string())
+        .bind(event.kind.as_u64() as i32)
+        .bind(&event.content)
+        .bind(tags_json)
+        .bind(event.sig.to_string())
+        .bind(created_at)
+        .bind(created_at_epoch)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn query(&self, filter: &Filter, limit: usize) -> StoreResult<Vec<Event>> {
+        let mut qb = QueryBuilder::new(format!("SELECT {} FROM events", EVENT_COLUMNS));
+        push_filter(&mut qb, filter, self.time_column());
+        qb.push(format!(" ORDER BY {} DESC LIMIT ", self.time_column())).push_bind(limit as i64);
+
+        let rows = qb.build().fetch_all(&self.pool).await?;
+        Ok(rows.iter().filter_map(row_to_event).collect())
+    }
+
+    async fn query_prefix(&self, query: &PrefixQuery) -> StoreResult<Vec<Event>> {
+        let mut qb = QueryBuilder::new(format!("SELECT {} FROM events WHERE {}", EVENT_COLUMNS, VISIBLE));
+
+        if !query.kinds.is_empty() {
+            qb.push(" AND kind = ANY(").push_bind(query.kinds.clone()).push(")");
+        }
+
+        if !query.authors.is_empty() {
+            qb.push(" AND (");
+            let mut conditions = qb.separated(" OR ");
+            for author in &query.authors {
+                if author.len() == 64 {
+                    // Full pubkey - exact match
+                    conditions.push("pubkey = ").push_bind_unseparated(author.clone());
+                } else {
+                    // Prefix - use LIKE
+                    conditions.push("pubkey LIKE ").push_bind_unseparated(format!("{}%", author));
+                }
+            }
+            qb.push(")");
+        }
+
+        let created_at = self.time_column();
+        if let Some(since) = query.since {
+            qb.push(format!(" AND {} >= ", created_at)).push_bind(since);
+        }
+        if let Some(until) = query.until {
+            qb.push(format!(" AND {} <= ", created_at)).push_bind(until);
+        }
+
+        qb.push(format!(" ORDER BY {} DESC LIMIT ", created_at)).push_bind(query.limit);
+
+        let rows = qb.build().fetch_all(&self.pool).await?;
+        Ok(rows.iter().filter_map(row_to_event).collect())
+    }
+
+    async fn count(&self, filter: &Filter) -> StoreResult<usize> {
+        let mut qb = QueryBuilder::new("SELECT COUNT(*) AS count FROM events");
+        push_filter(&mut qb, filter, self.time_column());
+
+        let row = qb.build().fetch_one(&self.pool).await?;
+        Ok(row.try_get::<i64, _>("count")? as usize)
+    }
+
+    async fn stream(&self, filter: &Filter, tx: mpsc::Sender<Event>) -> StoreResult<u64> {
+        let mut qb = QueryBuilder::new(format!("SELECT {} FROM events", EVENT_COLUMNS));
+        push_filter(&mut qb, filter, self.time_column());
+        qb.push(format!(" ORDER BY {} ASC", self.time_column()));
+        if let Some(limit) = filter.limit {
+            qb.push(" LIMIT ").push_bind(limit as i64);
+        }
+
+        let mut sent = 0;
+        let mut rows = qb.build().fetch(&self.pool);
+        while let Some(row) = rows.try_next().await? {
+            if let Some(event) = row_to_event(&row) {
+                if tx.send(event).await.is_err() {
+                    break;
+                }
+                sent += 1;
+            }
+        }
+
+        Ok(sent)
+    }
+
+    async fn delete(&self, target: Deletion<'_>) -> StoreResult<Vec<IndexKey>> {
+        let rows = match target {
+            Deletion::Event { id, author } => {
+                sqlx::query(&format!("DELETE FROM events WHERE \"eventId\" = $1 AND pubkey = $2 RETURNING {}", KEY_COLUMNS))
+                    .bind(id)
+                    .bind(author)
+                    .fetch_all(&self.pool)
+                    .await?
+            }
+            Deletion::Address { author, kind, identifier } => {
+                sqlx::query(&format!(
+                    "DELETE FROM events WHERE pubkey = $1 AND kind = $2 AND
+                     EXISTS (SELECT 1 FROM jsonb_array_elements(tags) AS t
+                             WHERE t->>0 = 'd' AND (t->>1 = $3 OR ($3 = '' AND (t->>1 IS NULL OR t->>1 = ''))))
+                     RETURNING {}",
+                    KEY_COLUMNS
+                ))
+                .bind(author)
+                .bind(kind as i32)
+                .bind(identifier)
+                .fetch_all(&self.pool)
+                .await?
+            }
+            Deletion::Author(author) => {
+                sqlx::query(&format!("DELETE FROM events WHERE pubkey = $1 RETURNING {}", KEY_COLUMNS))
+                    .bind(author)
+                    .fetch_all(&self.pool)
+                    .await?
+            }
+        };
+
+        Ok(rows.iter().filter_map(row_to_index_key).collect())
+    }
+
+    async fn negentropy_items(&self, filter: &Filter, limit: usize) -> StoreResult<Vec<IndexKey>> {
+        let mut qb = QueryBuilder::new(format!("SELECT {} FROM events", KEY_COLUMNS));
+        push_filter(&mut qb, filter, self.time_column());
+        qb.push(" LIMIT ").push_bind(limit as i64);
+
+        let rows = qb.build().fetch_all(&self.pool).await?;
+
+        Ok(rows.iter().filter_map(row_to_index_key).collect())
+    }
+
+    async fn stream_index(&self, tx: mpsc::Sender<(IndexKey, Option<i64>)>) -> StoreResult<u64> {
+        let sql = format!("SELECT {}, EXTRACT(EPOCH FROM \"expiresAt\")::BIGINT AS expires_at FROM events WHERE {}", KEY_COLUMNS, VISIBLE);
+        let mut sent = 0;
+        let mut rows = sqlx::query(&sql).fetch(&self.pool);
+        while let Some(row) = rows.try_next().await? {
+            if let Some(key) = row_to_index_key(&row) {
+                if tx.send((key, row.get("expires_at"))).await.is_err() {
+                    break;
+                }
+                sent += 1;
+            }
+        }
+
+        Ok(sent)
+    }
+
+    async fn user_access(&self, pubkey: &str) -> StoreResult<Option<UserAccess>> {
+        let row = sqlx::query(
+            "SELECT \"isAdmin\", \"whitelistStatus\"::text as status, \"permanentlyBanned\" FROM users WHERE pubkey = $1"
+        )
+        .bind(pubkey)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| UserAccess {
+            is_admin: row.try_get("isAdmin").unwrap_or(false),
+            status: row
+                .try_get::<Option<String>, _>("status")
+                .unwrap_or(None)
+                .and_then(|s| WhitelistStatus::parse(&s)),
+            permanently_banned: row.try_get("permanentlyBanned").unwrap_or(false),
+        }))
+    }
+
+    async fn list_users(&self, status: WhitelistStatus) -> StoreResult<Vec<ListEntry>> {
+        let rows = sqlx::query(
+            "SELECT pubkey AS value, \"whitelistNotes\" AS reason FROM users
+             WHERE \"whitelistStatus\"::text = $1 AND NOT \"isRelayIdentity\""
+        )
+        .bind(status.as_str())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(row_to_entry).collect())
+    }
+
+    async fn banned_users(&self) -> StoreResult<Vec<ListEntry>> {
+        let rows = sqlx::query(
+            "SELECT pubkey AS value, \"whitelistNotes\" AS reason FROM users
+             WHERE \"whitelistStatus\"::text = 'REVOKED' OR \"permanentlyBanned\""
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(row_to_entry).collect())
+    }
+
+    async fn ensure_user(&self, npub: &str, pubkey: &str, status: WhitelistStatus, notes: &str) -> StoreResult<bool> {
+        let result = sqlx::query(
+            "INSERT INTO users (id, npub, pubkey, \"whitelistStatus\", \"whitelistNotes\", \"updatedAt\")
+             VALUES ($1, $2, $3, $4::\"WhitelistStatus\", $5, NOW())
+             ON CONFLICT (pubkey) DO NOTHING"
+        )
+        .bind(nanoid::nanoid!())
+        .bind(npub)
+        .bind(pubkey)
+        .bind(status.as_str())
+        .bind(notes)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn register_relay_user(&self, npub: &str, pubkey: &str) -> StoreResult<bool> {
+        let created: bool = sqlx::query_scalar(
+            "INSERT INTO users (id, npub, pubkey, \"whitelistStatus\", \"whitelistNotes\", \"isRelayIdentity\", \"updatedAt\")
+             VALUES ($1, $2, $3, 'ACTIVE', 'relay identity', true, NOW())
+             ON CONFLICT (pubkey) DO UPDATE SET \"isRelayIdentity\" = true
+             RETURNING xmax = 0"
+        )
+        .bind(nanoid::nanoid!())
+        .bind(npub)
+        .bind(pubkey)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(created)
+    }
+
+    async fn allow_user(&self, npub: &str, pubkey: &str, reason: Option<&str>) -> StoreResult<()> {
+        self.upsert_user_status(npub, pubkey, WhitelistStatus::Active, reason).await
+    }
+
+    async fn ban_user(&self, npub: &str, pubkey: &str, reason: Option<&str>) -> StoreResult<()> {
+        self.upsert_user_status(npub, pubkey, WhitelistStatus::Revoked, reason).await
+    }
+
+    async fn set_whitelist_status(&self, pubkey: &str, status: WhitelistStatus) -> StoreResult<u64> {
+        let result = sqlx::query(
+            "UPDATE users SET \"whitelistStatus\" = $2::\"WhitelistStatus\", \"updatedAt\" = NOW() WHERE pubkey = $1"
+        )
+        .bind(pubkey)
+        .bind(status.as_str())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn record_violation(&self, pubkey: &str) -> StoreResult<u64> {
+        let result = sqlx::query(
+            "UPDATE users SET \"blacklistViolations\" = \"blacklistViolations\" + 1, \"lastBlacklistViolation\" = NOW(), \"updatedAt\" = NOW()
+             WHERE pubkey = $1"
+        )
+        .bind(pubkey)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn set_admin(&self, pubkey: &str, is_admin: bool) -> StoreResult<u64> {
+        let result = sqlx::query("UPDATE users SET \"isAdmin\" = $2, \"updatedAt\" = NOW() WHERE pubkey = $1")
+            .bind(pubkey)
+            .bind(is_admin)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn ban_event(&self, id: &str, reason: Option<&str>) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO banned_events (event_id, reason) VALUES ($1, $2)
+             ON CONFLICT (event_id) DO UPDATE
+             SET reason = COALESCE(EXCLUDED.reason, banned_events.reason), pending_review = false"
+        )
+        .bind(id)
+        .bind(reason)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn hide_event(&self, id: &str, reason: &str) -> StoreResult<bool> {
+        let result = sqlx::query(
+            "INSERT INTO banned_events (event_id, reason, pending_review) VALUES ($1, $2, true)
+             ON CONFLICT (event_id) DO NOTHING"
+        )
+        .bind(id)
+        .bind(reason)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn allow_event(&self, id: &str) -> StoreResult<u64> {
+        let result = sqlx::query("DELETE FROM banned_events WHERE event_id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn banned_events(&self) -> StoreResult<Vec<ListEntry>> {
+        let rows = sqlx::query("SELECT event_id AS value, reason FROM banned_events WHERE NOT pending_review ORDER BY created_at")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.iter().map(row_to_entry).collect())
+    }
+
+    async fn is_event_banned(&self, id: &str) -> StoreResult<bool> {
+        let row = sqlx::query("SELECT 1 AS banned FROM banned_events WHERE event_id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.is_some())
+    }
+
+    async fn events_needing_moderation(&self) -> StoreResult<Vec<ListEntry>> {
+        let rows = sqlx::query(
+            "SELECT DISTINCT ON (metadata->>'eventId') metadata->>'eventId' AS value, content AS reason
+             FROM admin_messages
+             WHERE type = 'REPORT' AND status IN ('PENDING', 'IN_REVIEW') AND metadata->>'eventId' IS NOT NULL
+             ORDER BY metadata->>'eventId', \"createdAt\""
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(row_to_entry).collect())
+    }
+
+    async fn record_report(&self, report: &Report<'_>) -> StoreResult<u64> {
+        let npub = |hex: &str| PublicKey::from_hex(hex).ok().and_then(|pk| pk.to_bech32().ok());
+        let subject = match report.event_id {
+            Some(id) => format!("Report ({}) against event {}", report.report_type.unwrap_or("other"), id),
+            None => format!("Report ({}) against {}", report.report_type.unwrap_or("other"), report.target_pubkey),
+        };
+        let metadata = serde_json::json!({
+            "eventId": report.event_id,
+            "reportType": report.report_type,
+            "reportEventId": report.id,
+        });
+
+        sqlx::query(
+            "INSERT INTO admin_messages
+                (id, type, subject, content, \"submitterNpub\", \"submitterPubkey\", \"targetNpub\", \"targetPubkey\", metadata, \"updatedAt\")
+             VALUES ($1, 'REPORT', $2, $3, $4, $5, $6, $7, $8, NOW())"
+        )
+        .bind(nanoid::nanoid!())
+        .bind(subject)
+        .bind(report.content)
+        .bind(npub(report.reporter))
+        .bind(report.reporter)
+        .bind(npub(report.target_pubkey))
+        .bind(report.target_pubkey)
+        .bind(metadata)
+        .execute(&self.pool)
+        .await?;
+
+        let Some(event_id) = report.event_id else {
+            return Ok(0);
+        };
+        let reporters: i64 = sqlx::query_scalar(
+            "SELECT COUNT(DISTINCT \"submitterPubkey\") FROM admin_messages
+             WHERE type = 'REPORT' AND status IN ('PENDING', 'IN_REVIEW') AND metadata->>'eventId' = $1"
+        )
+        .bind(event_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(reporters as u64)
+    }
+
+    async fn resolve_reports(&self, event_id: &str, upheld: bool) -> StoreResult<u64> {
+        let (status, resolution) = if upheld {
+            ("APPROVED", "Event banned through NIP-86")
+        } else {
+            ("DENIED", "Event allowed through NIP-86")
+        };
+        let result = sqlx::query(
+            "UPDATE admin_messages
+             SET status = $2::\"AdminMessageStatus\", resolution = $3, \"resolvedAt\" = NOW(), \"updatedAt\" = NOW()
+             WHERE type = 'REPORT' AND status IN ('PENDING', 'IN_REVIEW') AND metadata->>'eventId' = $1"
+        )
+        .bind(event_id)
+        .bind(status)
+        .bind(resolution)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn allow_kind(&self, kind: u64) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        let lifted = sqlx::query("DELETE FROM disallowed_kinds WHERE kind = $1")
+            .bind(kind as i32)
+            .execute(&mut *tx)
+            .await?;
+        if lifted.rows_affected() == 0 {
+            sqlx::query("INSERT INTO allowed_kinds (kind) VALUES ($1) ON CONFLICT (kind) DO NOTHING")
+                .bind(kind as i32)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn disallow_kind(&self, kind: u64) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM allowed_kinds WHERE kind = $1")
+            .bind(kind as i32)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("INSERT INTO disallowed_kinds (kind) VALUES ($1) ON CONFLICT (kind) DO NOTHING")
+            .bind(kind as i32)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn allowed_kinds(&self) -> StoreResult<Vec<u64>> {
+        let rows = sqlx::query("SELECT kind FROM allowed_kinds ORDER BY kind")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.iter().map(|r| r.get::<i32, _>("kind") as u64).collect())
+    }
+
+    async fn disallowed_kinds(&self) -> StoreResult<Vec<u64>> {
+        let rows = sqlx::query("SELECT kind FROM disallowed_kinds ORDER BY kind")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.iter().map(|r| r.get::<i32, _>("kind") as u64).collect())
+    }
+
+    async fn block_ip(&self, ip: &str, reason: Option<&str>) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO blocked_ips (ip, reason) VALUES ($1, $2)
+             ON CONFLICT (ip) DO UPDATE SET reason = COALESCE(EXCLUDED.reason, blocked_ips.reason)"
+        )
+        .bind(ip)
+        .bind(reason)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn unblock_ip(&self, ip: &str) -> StoreResult<u64> {
+        let result = sqlx::query("DELETE FROM blocked_ips WHERE ip = $1")
+            .bind(ip)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn blocked_ips(&self) -> StoreResult<Vec<ListEntry>> {
+        let rows = sqlx::query("SELECT ip AS value, reason FROM blocked_ips ORDER BY created_at")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.iter().map(row_to_entry).collect())
+    }
+
+    async fn is_ip_blocked(&self, ip: &str) -> StoreResult<bool> {
+        let row = sqlx::query("SELECT 1 AS blocked FROM blocked_ips WHERE ip = $1")
+            .bind(ip)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.is_some())
+    }
+
+    async fn setting(&self, key: &str) -> StoreResult<Option<String>> {
+        let row = sqlx::query("SELECT value FROM relay_settings WHERE key = $1")
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|r| r.get("value")))
+    }
+
+    async fn set_setting(&self, key: &str, value: &str) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO relay_settings (key, value) VALUES ($1, $2)
+             ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value, updated_at = NOW()"
+        )
+        .bind(key)
+        .bind(value)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn mirror_cursor(&self, source: &str) -> StoreResult<Option<i64>> {
+        let row = sqlx::query("SELECT last_created_at FROM mirror_cursors WHERE source = $1")
+            .bind(source)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|r| r.get("last_created_at")))
+    }
+
+    async fn set_mirror_cursor(&self, source: &str, created_at: i64) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO mirror_cursors (source, last_created_at) VALUES ($1, $2)
+             ON CONFLICT (source) DO UPDATE
+             SET last_created_at = GREATEST(mirror_cursors.last_created_at, EXCLUDED.last_created_at),
+                 updated_at = NOW()"
+        )
+        .bind(source)
+        .bind(created_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn append_audit(&self, entry: &AuditEntry) -> StoreResult<AuditRecord> {
+        let row = sqlx::query(
+            "INSERT INTO admin_audit_log (admin_pubkey, source, method, params, result, success)
+             VALUES ($1, $2, $3, $4, $5, $6)
+             RETURNING id, EXTRACT(EPOCH FROM created_at)::BIGINT AS created_at"
+        )
+        .bind(&entry.admin_pubkey)
+        .bind(&entry.source)
+        .bind(&entry.method)
+        .bind(&entry.params)
+        .bind(&entry.result)
+        .bind(entry.success)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(AuditRecord {
+            id: row.get("id"),
+            created_at: row.get("created_at"),
+            entry: entry.clone(),
+        })
+    }
+
+    async fn audit_log(&self, limit: i64, before_id: Option<i64>) -> StoreResult<Vec<AuditRecord>> {
+        let rows = sqlx::query(
+            "SELECT id, admin_pubkey, source, method, params, result, success,
+                    EXTRACT(EPOCH FROM created_at)::BIGINT AS created_at
+             FROM admin_audit_log WHERE ($1::BIGINT IS NULL OR id < $1)
+             ORDER BY id DESC LIMIT $2"
+        )
+        .bind(before_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| AuditRecord {
+                id: row.get("id"),
+                created_at: row.get("created_at"),
+                entry: AuditEntry {
+                    admin_pubkey: row.get("admin_pubkey"),
+                    source: row.get("source"),
+                    method: row.get("method"),
+                    params: row.get("params"),
+                    result: row.get("result"),
+                    success: row.get("success"),
+                },
+            })
+            .collect())
+    }
+}
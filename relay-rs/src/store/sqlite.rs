@@ -0,0 +1,899 @@
+// Embedded SQLite backend for small deployments and local testing
+
+use super::{build_event, index_key, AuditEntry, AuditRecord, Deletion, EventStore, IndexKey, ListEntry, PrefixQuery, Report, StoreResult, UserAccess, WhitelistStatus};
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use nostr::{Event, Filter};
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions, SqliteRow},
+    Pool, QueryBuilder, Row, Sqlite,
+};
+use std::str::FromStr;
+use tokio::sync::mpsc;
+
+const EVENT_COLUMNS: &str = "event_id, pubkey, kind, content, tags, sig, created_at";
+/// Unexpired and not banned through NIP-86
+const VISIBLE: &str = "(expires_at IS NULL OR expires_at > CAST(strftime('%s', 'now') AS INTEGER))
+    AND NOT EXISTS (SELECT 1 FROM banned_events b WHERE b.event_id = events.event_id)";
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+pub struct SqliteStore {
+    pool: Pool<Sqlite>,
+}
+
+impl SqliteStore {
+    pub async fn connect(url: &str) -> StoreResult<Self> {
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+        // Every connection to an in-memory database gets its own copy, so keep just one
+        let max_connections = if url.contains(":memory:") { 1 } else { 5 };
+        let pool = SqlitePoolOptions::new()
+            .max_connections(max_connections)
+            .connect_with(options)
+            .await?;
+
+        MIGRATOR.run(&pool).await?;
+        Ok(Self { pool })
+    }
+
+    async fn upsert_user_status(
+        &self,
+        npub: &str,
+        pubkey: &str,
+        status: WhitelistStatus,
+        reason: Option<&str>,
+    ) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO users (pubkey, npub, whitelist_status, notes) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (pubkey) DO UPDATE SET npub = ?2, whitelist_status = ?3, notes = COALESCE(?4, notes)"
+        )
+        .bind(pubkey)
+        .bind(npub)
+        .bind(status.as_str())
+        .bind(reason)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+fn row_to_index_key(row: &SqliteRow) -> Option<IndexKey> {
+    index_key(row.get("created_at"), row.get("event_id"))
+}
+
+fn row_to_entry(row: &SqliteRow) -> ListEntry {
+    ListEntry {
+        value: row.get("value"),
+        reason: row.try_get("reason").unwrap_or(None),
+    }
+}
+
+fn row_to_event(row: &SqliteRow) -> Option<Event> {
+    let tags: String = row.try_get("tags").ok()?;
+    build_event(
+        row.try_get("event_id").ok()?,
+        row.try_get("pubkey").ok()?,
+        row.try_get("created_at").ok()?,
+        row.try_get("kind").ok()?,
+        serde_json::from_str(&tags).ok()?,
+        row.try_get("content").ok()?,
+        row.try_get("sig").ok()?,
+    )
+}
+
+/// Append `AND column IN (...)` for a non-empty list of values
+fn push_in<'a, T>(qb: &mut QueryBuilder<'a, Sqlite>, column: &str, values: Vec<T>)
+where
+    T: 'a + Send + sqlx::Encode<'a, Sqlite> + sqlx::Type<Sqlite>,
+{
+    if values.is_empty() {
+        return;
+    }
+    qb.push(format!(" AND {} IN (", column));
+    let mut list = qb.separated(", ");
+    for value in values {
+        list.push_bind(value);
+    }
+    qb.push(")");
+}
+
+/// Append the WHERE clause for a nostr filter
+fn push_filter(qb: &mut QueryBuilder<Sqlite>, filter: &Filter) {
+    qb.push(" WHERE ").push(VISIBLE);
+
+    if let Some(ids) = &filter.ids {
+        push_in(qb, "event_id", ids.iter().map(|id| id.to_hex()).collect());
+    }
+    if let Some(authors) = &filter.authors {
+        push_in(qb, "pubkey", authors.iter().map(|a| a.to_string()).collect());
+    }
+    if let Some(kinds) = &filter.kinds {
+        push_in(qb, "kind", kinds.iter().map(|k| k.as_u64() as i64).collect());
+    }
+
+    if let Some(since) = filter.since {
+        qb.push(" AND created_at >= ").push_bind(since.as_i64());
+    }
+    if let Some(until) = filter.until {
+        qb.push(" AND created_at <= ").push_bind(until.as_i64());
+    }
+
+    for (tag, values) in &filter.generic_tags {
+        qb.push(" AND EXISTS (SELECT 1 FROM json_each(events.tags) AS t WHERE json_extract(t.value, '$[0]') = ")
+            .push_bind(tag.to_string())
+            .push(" AND json_extract(t.value, '$[1]') IN (");
+        let mut list = qb.separated(", ");
+        for value in values {
+            list.push_bind(value.to_string());
+        }
+        qb.push("))");
+    }
+}
+
+#[async_trait]
+impl EventStore for SqliteStore {
+    async fn save_event(&self, event: &Event, expires_at: Option<i64>) -> StoreResult<bool> {
+        let tags_json = serde_json::to_string(&event.tags).unwrap_or_else(|_| "[]".to_string());
+
+        let result = sqlx::query(
+            "INSERT INTO events (event_id, pubkey, kind, content, tags, sig, created_at, received_at, expires_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, CAST(strftime('%s', 'now') AS INTEGER), ?)
+             ON CONFLICT (event_id) DO NOTHING"
+        )
+        .bind(event.id.to_string())
+        .bind(event.pubkey.to_string())
+        .bind(event.kind.as_u64() as i64)
+        .bind(&event.content)
+        .bind(tags_json)
+        .bind(event.sig.to_string())
+        .bind(event.created_at.as_i64())
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn query(&self, filter: &Filter, limit: usize) -> StoreResult<Vec<Event>> {
+        let mut qb = QueryBuilder::new(format!("SELECT {} FROM events", EVENT_COLUMNS));
+        push_filter(&mut qb, filter);
+        qb.push(" ORDER BY created_at DESC LIMIT ").push_bind(limit as i64);
+
+        let rows = qb.build().fetch_all(&self.pool).await?;
+        Ok(rows.iter().filter_map(row_to_event).collect())
+    }
+
+    async fn query_prefix(&self, query: &PrefixQuery) -> StoreResult<Vec<Event>> {
+        let mut qb = QueryBuilder::new(format!("SELECT {} FROM events WHERE {}", EVENT_COLUMNS, VISIBLE));
+
+        push_in(&mut qb, "kind", query.kinds.iter().map(|k| *k as i64).collect());
+
+        if !query.authors.is_empty() {
+            qb.push(" AND (");
+            let mut conditions = qb.separated(" OR ");
+            for author in &query.authors {
+                if author.len() == 64 {
+                    conditions.push("pubkey = ").push_bind_unseparated(author.clone());
+                } else {
+                    conditions.push("pubkey LIKE ").push_bind_unseparated(format!("{}%", author));
+                }
+            }
+            qb.push(")");
+        }
+
+        if let Some(since) = query.since {
+            qb.push(" AND created_at >= ").push_bind(since);
+        }
+        if let Some(until) = query.until {
+            qb.push(" AND created_at <= ").push_bind(until);
+        }
+
+        qb.push(" ORDER BY created_at DESC LIMIT ").push_bind(query.limit);
+
+        let rows = qb.build().fetch_all(&self.pool).await?;
+        Ok(rows.iter().filter_map(row_to_event).collect())
+    }
+
+    async fn count(&self, filter: &Filter) -> StoreResult<usize> {
+        let mut qb = QueryBuilder::new("SELECT COUNT(*) AS count FROM events");
+        push_filter(&mut qb, filter);
+
+        let row = qb.build().fetch_one(&self.pool).await?;
+        Ok(row.try_get::<i64, _>("count")? as usize)
+    }
+
+    async fn stream(&self, filter: &Filter, tx: mpsc::Sender<Event>) -> StoreResult<u64> {
+        let mut qb = QueryBuilder::new(format!("SELECT {} FROM events", EVENT_COLUMNS));
+        push_filter(&mut qb, filter);
+        qb.push(" ORDER BY created_at ASC");
+        if let Some(limit) = filter.limit {
+            qb.push(" LIMIT ").push_bind(limit as i64);
+        }
+
+        let mut sent = 0;
+        let mut rows = qb.build().fetch(&self.pool);
+        while let Some(row) = rows.try_next().await? {
+            if let Some(event) = row_to_event(&row) {
+                if tx.send(event).await.is_err() {
+                    break;
+                }
+                sent += 1;
+            }
+        }
+
+        Ok(sent)
+    }
+
+    async fn delete(&self, target: Deletion<'_>) -> StoreResult<Vec<IndexKey>> {
+        let rows = match target {
+            Deletion::Event { id, author } => {
+                sqlx::query("DELETE FROM events WHERE event_id = ? AND pubkey = ? RETURNING event_id, created_at")
+                    .bind(id)
+                    .bind(author)
+                    .fetch_all(&self.pool)
+                    .await?
+            }
+            Deletion::Address { author, kind, identifier } => {
+                sqlx::query(
+                    "DELETE FROM events WHERE pubkey = ?1 AND kind = ?2 AND
+                     EXISTS (SELECT 1 FROM json_each(events.tags) AS t
+                             WHERE json_extract(t.value, '$[0]') = 'd'
+                               AND COALESCE(json_extract(t.value, '$[1]'), '') = ?3)
+                     RETURNING event_id, created_at"
+                )
+                .bind(author)
+                .bind(kind as i64)
+                .bind(identifier)
+                .fetch_all(&self.pool)
+                .await?
+            }
+            Deletion::Author(author) => {
+                sqlx::query("DELETE FROM events WHERE pubkey = ? RETURNING event_id, created_at")
+                    .bind(author)
+                    .fetch_all(&self.pool)
+                    .await?
+            }
+        };
+
+        Ok(rows.iter().filter_map(row_to_index_key).collect())
+    }
+
+    async fn negentropy_items(&self, filter: &Filter, limit: usize) -> StoreResult<Vec<IndexKey>> {
+        let mut qb = QueryBuilder::new("SELECT event_id, created_at FROM events");
+        push_filter(&mut qb, filter);
+        qb.push(" LIMIT ").push_bind(limit as i64);
+
+        let rows = qb.build().fetch_all(&self.pool).await?;
+
+        Ok(rows.iter().filter_map(row_to_index_key).collect())
+    }
+
+    async fn stream_index(&self, tx: mpsc::Sender<(IndexKey, Option<i64>)>) -> StoreResult<u64> {
+        let sql = format!("SELECT event_id, created_at, expires_at FROM events WHERE {}", VISIBLE);
+        let mut sent = 0;
+        let mut rows = sqlx::query(&sql).fetch(&self.pool);
+        while let Some(row) = rows.try_next().await? {
+            if let Some(key) = row_to_index_key(&row) {
+                if tx.send((key, row.get("expires_at"))).await.is_err() {
+                    break;
+                }
+                sent += 1;
+            }
+        }
+
+        Ok(sent)
+    }
+
+    async fn user_access(&self, pubkey: &str) -> StoreResult<Option<UserAccess>> {
+        let row = sqlx::query("SELECT is_admin, whitelist_status, permanently_banned FROM users WHERE pubkey = ?")
+            .bind(pubkey)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|row| UserAccess {
+            is_admin: row.try_get("is_admin").unwrap_or(false),
+            status: row
+                .try_get::<String, _>("whitelist_status")
+                .ok()
+                .and_then(|s| WhitelistStatus::parse(&s)),
+            permanently_banned: row.try_get("permanently_banned").unwrap_or(false),
+        }))
+    }
+
+    async fn list_users(&self, status: WhitelistStatus) -> StoreResult<Vec<ListEntry>> {
+        let rows = sqlx::query("SELECT pubkey AS value, notes AS reason FROM users WHERE whitelist_status = ? AND NOT is_relay_identity")
+            .bind(status.as_str())
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.iter().map(row_to_entry).collect())
+    }
+
+    async fn banned_users(&self) -> StoreResult<Vec<ListEntry>> {
+        let rows = sqlx::query(
+            "SELECT pubkey AS value, notes AS reason FROM users WHERE whitelist_status = 'REVOKED' OR permanently_banned"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(row_to_entry).collect())
+    }
+
+    async fn ensure_user(&self, npub: &str, pubkey: &str, status: WhitelistStatus, notes: &str) -> StoreResult<bool> {
+        let result = sqlx::query(
+            "INSERT INTO users (pubkey, npub, whitelist_status, notes) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (pubkey) DO NOTHING"
+        )
+        .bind(pubkey)
+        .bind(npub)
+        .bind(status.as_str())
+        .bind(notes)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn register_relay_user(&self, npub: &str, pubkey: &str) -> StoreResult<bool> {
+        let mut tx = self.pool.begin().await?;
+        let marked = sqlx::query("UPDATE users SET is_relay_identity = 1 WHERE pubkey = ?")
+            .bind(pubkey)
+            .execute(&mut *tx)
+            .await?;
+        if marked.rows_affected() == 0 {
+            sqlx::query(
+                "INSERT INTO users (pubkey, npub, whitelist_status, notes, is_relay_identity)
+                 VALUES (?, ?, 'ACTIVE', 'relay identity', 1)"
+            )
+            .bind(pubkey)
+            .bind(npub)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(marked.rows_affected() == 0)
+    }
+
+    async fn allow_user(&self, npub: &str, pubkey: &str, reason: Option<&str>) -> StoreResult<()> {
+        self.upsert_user_status(npub, pubkey, WhitelistStatus::Active, reason).await
+    }
+
+    async fn ban_user(&self, npub: &str, pubkey: &str, reason: Option<&str>) -> StoreResult<()> {
+        self.upsert_user_status(npub, pubkey, WhitelistStatus::Revoked, reason).await
+    }
+
+    async fn set_whitelist_status(&self, pubkey: &str, status: WhitelistStatus) -> StoreResult<u64> {
+        let result = sqlx::query("UPDATE users SET whitelist_status = ? WHERE pubkey = ?")
+            .bind(status.as_str())
+            .bind(pubkey)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn record_violation(&self, pubkey: &str) -> StoreResult<u64> {
+        let result = sqlx::query(
+            "UPDATE users SET blacklist_violations = blacklist_violations + 1,
+                 last_violation_at = CAST(strftime('%s', 'now') AS INTEGER)
+             WHERE pubkey = ?"
+        )
+        .bind(pubkey)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn set_admin(&self, pubkey: &str, is_admin: bool) -> StoreResult<u64> {
+        let result = sqlx::query("UPDATE users SET is_admin = ? WHERE pubkey = ?")
+            .bind(is_admin)
+            .bind(pubkey)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn ban_event(&self, id: &str, reason: Option<&str>) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO banned_events (event_id, reason) VALUES (?1, ?2)
+             ON CONFLICT (event_id) DO UPDATE SET reason = COALESCE(?2, reason), pending_review = 0"
+        )
+        .bind(id)
+        .bind(reason)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn hide_event(&self, id: &str, reason: &str) -> StoreResult<bool> {
+        let result = sqlx::query(
+            "INSERT INTO banned_events (event_id, reason, pending_review) VALUES (?, ?, 1)
+             ON CONFLICT (event_id) DO NOTHING"
+        )
+        .bind(id)
+        .bind(reason)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn allow_event(&self, id: &str) -> StoreResult<u64> {
+        let result = sqlx::query("DELETE FROM banned_events WHERE event_id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn banned_events(&self) -> StoreResult<Vec<ListEntry>> {
+        let rows = sqlx::query("SELECT event_id AS value, reason FROM banned_events WHERE pending_review = 0 ORDER BY created_at")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.iter().map(row_to_entry).collect())
+    }
+
+    async fn is_event_banned(&self, id: &str) -> StoreResult<bool> {
+        let row = sqlx::query("SELECT 1 FROM banned_events WHERE event_id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.is_some())
+    }
+
+    async fn events_needing_moderation(&self) -> StoreResult<Vec<ListEntry>> {
+        let rows = sqlx::query(
+            "SELECT event_id AS value, MIN(content) AS reason FROM admin_messages
+             WHERE type = 'REPORT' AND status IN ('PENDING', 'IN_REVIEW') AND event_id IS NOT NULL
+             GROUP BY event_id ORDER BY MIN(created_at)"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(row_to_entry).collect())
+    }
+
+    async fn record_report(&self, report: &Report<'_>) -> StoreResult<u64> {
+        let subject = match report.event_id {
+            Some(id) => format!("Report ({}) against event {}", report.report_type.unwrap_or("other"), id),
+            None => format!("Report ({}) against {}", report.report_type.unwrap_or("other"), report.target_pubkey),
+        };
+
+        sqlx::query(
+            "INSERT INTO admin_messages (id, type, subject, content, submitter_pubkey, target_pubkey, event_id, report_type)
+             VALUES (?, 'REPORT', ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(nanoid::nanoid!())
+        .bind(subject)
+        .bind(report.content)
+        .bind(report.reporter)
+        .bind(report.target_pubkey)
+        .bind(report.event_id)
+        .bind(report.report_type)
+        .execute(&self.pool)
+        .await?;
+
+        let Some(event_id) = report.event_id else {
+            return Ok(0);
+        };
+        let reporters: i64 = sqlx::query_scalar(
+            "SELECT COUNT(DISTINCT submitter_pubkey) FROM admin_messages
+             WHERE type = 'REPORT' AND status IN ('PENDING', 'IN_REVIEW') AND event_id = ?"
+        )
+        .bind(event_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(reporters as u64)
+    }
+
+    async fn resolve_reports(&self, event_id: &str, upheld: bool) -> StoreResult<u64> {
+        let (status, resolution) = if upheld {
+            ("APPROVED", "Event banned through NIP-86")
+        } else {
+            ("DENIED", "Event allowed through NIP-86")
+        };
+        let result = sqlx::query(
+            "UPDATE admin_messages
+             SET status = ?, resolution = ?, resolved_at = CAST(strftime('%s', 'now') AS INTEGER)
+             WHERE type = 'REPORT' AND status IN ('PENDING', 'IN_REVIEW') AND event_id = ?"
+        )
+        .bind(status)
+        .bind(resolution)
+        .bind(event_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn allow_kind(&self, kind: u64) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        let lifted = sqlx::query("DELETE FROM disallowed_kinds WHERE kind = ?")
+            .bind(kind as i64)
+            .execute(&mut *tx)
+            .await?;
+        if lifted.rows_affected() == 0 {
+            sqlx::query("INSERT INTO allowed_kinds (kind) VALUES (?) ON CONFLICT (kind) DO NOTHING")
+                .bind(kind as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn disallow_kind(&self, kind: u64) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM allowed_kinds WHERE kind = ?")
+            .bind(kind as i64)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("INSERT INTO disallowed_kinds (kind) VALUES (?) ON CONFLICT (kind) DO NOTHING")
+            .bind(kind as i64)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn allowed_kinds(&self) -> StoreResult<Vec<u64>> {
+        let rows = sqlx::query("SELECT kind FROM allowed_kinds ORDER BY kind")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.iter().map(|r| r.get::<i64, _>("kind") as u64).collect())
+    }
+
+    async fn disallowed_kinds(&self) -> StoreResult<Vec<u64>> {
+        let rows = sqlx::query("SELECT kind FROM disallowed_kinds ORDER BY kind")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.iter().map(|r| r.get::<i64, _>("kind") as u64).collect())
+    }
+
+    async fn block_ip(&self, ip: &str, reason: Option<&str>) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO blocked_ips (ip, reason) VALUES (?1, ?2)
+             ON CONFLICT (ip) DO UPDATE SET reason = COALESCE(?2, reason)"
+        )
+        .bind(ip)
+        .bind(reason)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn unblock_ip(&self, ip: &str) -> StoreResult<u64> {
+        let result = sqlx::query("DELETE FROM blocked_ips WHERE ip = ?")
+            .bind(ip)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn blocked_ips(&self) -> StoreResult<Vec<ListEntry>> {
+        let rows = sqlx::query("SELECT ip AS value, reason FROM blocked_ips ORDER BY created_at")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.iter().map(row_to_entry).collect())
+    }
+
+    async fn is_ip_blocked(&self, ip: &str) -> StoreResult<bool> {
+        let row = sqlx::query("SELECT 1 FROM blocked_ips WHERE ip = ?")
+            .bind(ip)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.is_some())
+    }
+
+    async fn setting(&self, key: &str) -> StoreResult<Option<String>> {
+        let row = sqlx::query("SELECT value FROM relay_settings WHERE key = ?")
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|r| r.get("value")))
+    }
+
+    async fn set_setting(&self, key: &str, value: &str) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO relay_settings (key, value) VALUES (?1, ?2)
+             ON CONFLICT (key) DO UPDATE SET value = ?2"
+        )
+        .bind(key)
+        .bind(value)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn mirror_cursor(&self, source: &str) -> StoreResult<Option<i64>> {
+        let row = sqlx::query("SELECT last_created_at FROM mirror_cursors WHERE source = ?")
+            .bind(source)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|r| r.get("last_created_at")))
+    }
+
+    async fn set_mirror_cursor(&self, source: &str, created_at: i64) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO mirror_cursors (source, last_created_at) VALUES (?1, ?2)
+             ON CONFLICT (source) DO UPDATE
+             SET last_created_at = MAX(last_created_at, ?2),
+                 updated_at = CAST(strftime('%s', 'now') AS INTEGER)"
+        )
+        .bind(source)
+        .bind(created_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn append_audit(&self, entry: &AuditEntry) -> StoreResult<AuditRecord> {
+        // Not RETURNING: an unfinished statement would keep the write transaction open
+        let created_at = chrono::Utc::now().timestamp();
+        let result = sqlx::query(
+            "INSERT INTO admin_audit_log (admin_pubkey, source, method, params, result, success, created_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(&entry.admin_pubkey)
+        .bind(&entry.source)
+        .bind(&entry.method)
+        .bind(entry.params.to_string())
+        .bind(entry.result.to_string())
+        .bind(entry.success)
+        .bind(created_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(AuditRecord {
+            id: result.last_insert_rowid(),
+            created_at,
+            entry: entry.clone(),
+        })
+    }
+
+    async fn audit_log(&self, limit: i64, before_id: Option<i64>) -> StoreResult<Vec<AuditRecord>> {
+        let rows = sqlx::query(
+            "SELECT id, admin_pubkey, source, method, params, result, success, created_at
+             FROM admin_audit_log WHERE (?1 IS NULL OR id < ?1)
+             ORDER BY id DESC LIMIT ?2"
+        )
+        .bind(before_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let json = |row: &SqliteRow, column: &str| {
+            serde_json::from_str(&row.get::<String, _>(column)).unwrap_or(serde_json::Value::Null)
+        };
+        Ok(rows
+            .iter()
+            .map(|row| AuditRecord {
+                id: row.get("id"),
+                created_at: row.get("created_at"),
+                entry: AuditEntry {
+                    admin_pubkey: row.get("admin_pubkey"),
+                    source: row.get("source"),
+                    method: row.get("method"),
+                    params: json(row, "params"),
+                    result: json(row, "result"),
+                    success: row.get("success"),
+                },
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::{EventBuilder, EventId, Keys, Kind, Tag, Timestamp};
+
+    async fn store() -> SqliteStore {
+        SqliteStore::connect("sqlite::memory:").await.unwrap()
+    }
+
+    fn event(keys: &Keys, kind: u64, created_at: u64, tags: Vec<Vec<&str>>) -> Event {
+        let tags: Vec<Tag> = tags.into_iter().map(|t| Tag::parse(t).unwrap()).collect();
+        EventBuilder::new(Kind::from(kind), "", tags)
+            .custom_created_at(Timestamp::from(created_at))
+            .to_event(keys)
+            .unwrap()
+    }
+
+    async fn save(store: &SqliteStore, events: &[&Event]) {
+        for event in events {
+            assert!(store.save_event(event, None).await.unwrap());
+        }
+    }
+
+    async fn ids(store: &SqliteStore, filter: Filter) -> Vec<EventId> {
+        store.query(&filter, 100).await.unwrap().iter().map(|e| e.id).collect()
+    }
+
+    #[tokio::test]
+    async fn filters_by_ids_authors_and_kinds() {
+        let store = store().await;
+        let (alice, bob) = (Keys::generate(), Keys::generate());
+        let note = event(&alice, 1, 100, vec![]);
+        let profile = event(&alice, 0, 101, vec![]);
+        let other = event(&bob, 1, 102, vec![]);
+        save(&store, &[&note, &profile, &other]).await;
+
+        assert_eq!(ids(&store, Filter::new().id(profile.id)).await, vec![profile.id]);
+        assert_eq!(ids(&store, Filter::new().author(alice.public_key())).await, vec![profile.id, note.id]);
+        assert_eq!(ids(&store, Filter::new().kind(Kind::TextNote)).await, vec![other.id, note.id]);
+        assert_eq!(ids(&store, Filter::new().author(alice.public_key()).kind(Kind::TextNote)).await, vec![note.id]);
+        assert_eq!(store.count(&Filter::new().kind(Kind::TextNote)).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn filters_by_generic_tags() {
+        let store = store().await;
+        let keys = Keys::generate();
+        let recipient = Keys::generate().public_key();
+        let tagged = event(&keys, 1, 100, vec![vec!["t", "nostr"], vec!["p", &recipient.to_hex()]]);
+        let other_tag = event(&keys, 1, 101, vec![vec!["t", "bitcoin"]]);
+        let untagged = event(&keys, 1, 102, vec![]);
+        save(&store, &[&tagged, &other_tag, &untagged]).await;
+
+        assert_eq!(ids(&store, Filter::new().hashtag("nostr")).await, vec![tagged.id]);
+        assert_eq!(ids(&store, Filter::new().hashtags(["nostr", "bitcoin"])).await, vec![other_tag.id, tagged.id]);
+        assert_eq!(ids(&store, Filter::new().pubkey(recipient)).await, vec![tagged.id]);
+        // Every tag condition must hold
+        assert!(ids(&store, Filter::new().hashtag("bitcoin").pubkey(recipient)).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn filters_by_time_range_and_limit() {
+        let store = store().await;
+        let keys = Keys::generate();
+        let events: Vec<Event> = (0..5).map(|i| event(&keys, 1, 100 + i, vec![])).collect();
+        save(&store, &events.iter().collect::<Vec<_>>()).await;
+
+        let range = Filter::new().since(Timestamp::from(101)).until(Timestamp::from(103));
+        assert_eq!(ids(&store, range).await, vec![events[3].id, events[2].id, events[1].id]);
+
+        let newest: Vec<EventId> = store.query(&Filter::new(), 2).await.unwrap().iter().map(|e| e.id).collect();
+        assert_eq!(newest, vec![events[4].id, events[3].id]);
+    }
+
+    #[tokio::test]
+    async fn replaces_addressable_events() {
+        let store = store().await;
+        let keys = Keys::generate();
+        let first = event(&keys, 30023, 100, vec![vec!["d", "post"]]);
+        let sibling = event(&keys, 30023, 101, vec![vec!["d", "other"]]);
+        save(&store, &[&first, &sibling]).await;
+
+        let author = keys.public_key().to_hex();
+        let removed = store.delete(Deletion::Address { author: &author, kind: 30023, identifier: "post" }).await.unwrap();
+        assert_eq!(removed, vec![(100, first.id)]);
+        let second = event(&keys, 30023, 102, vec![vec!["d", "post"]]);
+        save(&store, &[&second]).await;
+
+        assert_eq!(ids(&store, Filter::new().kind(Kind::from(30023))).await, vec![second.id, sibling.id]);
+    }
+
+    #[tokio::test]
+    async fn deletes_only_the_authors_events() {
+        let store = store().await;
+        let (alice, bob) = (Keys::generate(), Keys::generate());
+        let mine = event(&alice, 1, 100, vec![]);
+        let theirs = event(&bob, 1, 101, vec![]);
+        save(&store, &[&mine, &theirs]).await;
+
+        let (alice_hex, bob_hex) = (alice.public_key().to_hex(), bob.public_key().to_hex());
+        let id = theirs.id.to_hex();
+        assert!(store.delete(Deletion::Event { id: &id, author: &alice_hex }).await.unwrap().is_empty());
+        assert_eq!(store.delete(Deletion::Event { id: &id, author: &bob_hex }).await.unwrap(), vec![(101, theirs.id)]);
+
+        save(&store, &[&event(&alice, 1, 102, vec![])]).await;
+        assert_eq!(store.delete(Deletion::Author(&alice_hex)).await.unwrap().len(), 2);
+        assert!(ids(&store, Filter::new()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn hides_expired_and_banned_events() {
+        let store = store().await;
+        let keys = Keys::generate();
+        let now = chrono::Utc::now().timestamp();
+        let expired = event(&keys, 1, 100, vec![]);
+        let expiring = event(&keys, 1, 101, vec![]);
+        let banned = event(&keys, 1, 102, vec![]);
+        let hidden = event(&keys, 1, 103, vec![]);
+        store.save_event(&expired, Some(now - 10)).await.unwrap();
+        store.save_event(&expiring, Some(now + 3600)).await.unwrap();
+        save(&store, &[&banned, &hidden]).await;
+
+        store.ban_event(&banned.id.to_hex(), Some("spam")).await.unwrap();
+        assert!(store.hide_event(&hidden.id.to_hex(), "reported").await.unwrap());
+
+        assert_eq!(ids(&store, Filter::new()).await, vec![expiring.id]);
+        assert_eq!(store.count(&Filter::new()).await.unwrap(), 1);
+        assert_eq!(store.negentropy_items(&Filter::new(), 10).await.unwrap(), vec![(101, expiring.id)]);
+        // Only admin bans are listed, not events hidden pending review
+        let listed: Vec<String> = store.banned_events().await.unwrap().into_iter().map(|e| e.value).collect();
+        assert_eq!(listed, vec![banned.id.to_hex()]);
+
+        assert_eq!(store.allow_event(&hidden.id.to_hex()).await.unwrap(), 1);
+        assert_eq!(ids(&store, Filter::new()).await, vec![hidden.id, expiring.id]);
+    }
+
+    #[tokio::test]
+    async fn lists_revoked_and_permanently_banned_users_as_banned() {
+        let store = store().await;
+        store.ban_user("npub-revoked", "revoked", Some("spam")).await.unwrap();
+        store.allow_user("npub-member", "member", None).await.unwrap();
+        store.allow_user("npub-permanent", "permanent", None).await.unwrap();
+        sqlx::query("UPDATE users SET permanently_banned = 1 WHERE pubkey = 'permanent'")
+            .execute(&store.pool)
+            .await
+            .unwrap();
+
+        let mut banned: Vec<String> = store.banned_users().await.unwrap().into_iter().map(|e| e.value).collect();
+        banned.sort();
+        assert_eq!(banned, vec!["permanent", "revoked"]);
+    }
+
+    #[tokio::test]
+    async fn the_relay_pubkey_is_active_but_not_listed_as_a_member() {
+        let store = store().await;
+        store.allow_user("npub-member", "member", None).await.unwrap();
+        assert!(store.register_relay_user("npub-relay", "relay").await.unwrap());
+        assert!(!store.register_relay_user("npub-relay", "relay").await.unwrap());
+
+        let active: Vec<String> =
+            store.list_users(WhitelistStatus::Active).await.unwrap().into_iter().map(|e| e.value).collect();
+        assert_eq!(active, vec!["member"]);
+        let access = store.user_access("relay").await.unwrap().unwrap();
+        assert_eq!(access.status, Some(WhitelistStatus::Active));
+
+        // A pubkey that was already a user is flagged without changing their status
+        store.ban_user("npub-former", "former", None).await.unwrap();
+        assert!(!store.register_relay_user("npub-former", "former").await.unwrap());
+        assert!(store.list_users(WhitelistStatus::Revoked).await.unwrap().is_empty());
+        let access = store.user_access("former").await.unwrap().unwrap();
+        assert_eq!(access.status, Some(WhitelistStatus::Revoked));
+    }
+
+    #[tokio::test]
+    async fn disallowing_a_kind_takes_effect_with_an_empty_allow_list() {
+        let store = store().await;
+        store.disallow_kind(4).await.unwrap();
+        assert_eq!(store.disallowed_kinds().await.unwrap(), vec![4]);
+        assert!(store.allowed_kinds().await.unwrap().is_empty());
+
+        // Allowing it again lifts the refusal rather than starting an allow-list
+        store.allow_kind(4).await.unwrap();
+        assert!(store.disallowed_kinds().await.unwrap().is_empty());
+        assert!(store.allowed_kinds().await.unwrap().is_empty());
+
+        store.allow_kind(1).await.unwrap();
+        store.allow_kind(7).await.unwrap();
+        store.disallow_kind(7).await.unwrap();
+        assert_eq!(store.allowed_kinds().await.unwrap(), vec![1]);
+        assert_eq!(store.disallowed_kinds().await.unwrap(), vec![7]);
+    }
+}
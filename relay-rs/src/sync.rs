@@ -0,0 +1,271 @@
+// NIP-77 client: reconcile with peer relays
+//
+// The relay opens a negentropy session with each peer as the initiator, fetches
+// the events only the peer has and runs them through `process_event` under the
+// rules a client's events face, except that rejections don't count against the
+// author. With `upload` set it also sends the peer the events only we have.
+
+use crate::{config::SyncPeer, nip77::FRAME_SIZE_LIMIT, peer::PeerConnection, process_event, Accepted, AppState, Origin};
+use negentropy::{Bytes, Negentropy};
+use nostr::{Event, EventId, Filter};
+use serde_json::json;
+use std::{collections::{BTreeMap, HashSet}, sync::Arc, time::Duration};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, warn};
+
+/// Event ids asked for, or uploaded, per round trip
+const BATCH_SIZE: usize = 500;
+
+/// Shortest interval the scheduler will sync a peer at
+const MIN_INTERVAL_SECS: u64 = 60;
+
+const SUB_ID: &str = "relay-rs-sync";
+
+/// What one sync did
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// Events only we had
+    pub have: usize,
+    /// Events only the peer had
+    pub need: usize,
+    pub stored: u64,
+    pub duplicates: u64,
+    /// Downloaded events `process_event` refused, by reason
+    pub rejected: BTreeMap<String, u64>,
+    pub uploaded: u64,
+    pub upload_rejected: u64,
+}
+
+impl SyncReport {
+    pub fn rejected_total(&self) -> u64 {
+        self.rejected.values().sum()
+    }
+}
+
+/// Sync every configured peer on its own interval, starting now
+pub fn spawn_scheduler(state: &Arc<AppState>) {
+    for peer in state.config.sync_peers.clone() {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(peer.interval_secs.max(MIN_INTERVAL_SECS)));
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                match sync_peer(&state, &peer).await {
+                    Ok(report) => info!(
+                        "Synced with {}: {} events fetched ({} stored, {} duplicates, {} rejected), {} uploaded ({} rejected)",
+                        peer.url, report.need, report.stored, report.duplicates, report.rejected_total(),
+                        report.uploaded, report.upload_rejected,
+                    ),
+                    Err(e) => warn!("Sync with {} failed: {}", peer.url, e),
+                }
+            }
+        });
+    }
+    if !state.config.sync_peers.is_empty() {
+        info!("Negentropy sync scheduled with {} peer(s)", state.config.sync_peers.len());
+    }
+}
+
+/// Reconcile `peer.filter` with one peer
+pub async fn sync_peer(state: &Arc<AppState>, peer: &SyncPeer) -> Result<SyncReport, String> {
+    let mut conn = PeerConnection::connect(state, &peer.url).await?;
+    let (have, need) = reconcile(&mut conn, state, &peer.filter).await?;
+
+    let mut report = SyncReport { have: have.len(), need: need.len(), ..Default::default() };
+    for batch in need.chunks(BATCH_SIZE) {
+        fetch(&mut conn, state, batch, &mut report).await?;
+    }
+    if peer.upload {
+        for batch in have.chunks(BATCH_SIZE) {
+            upload(&mut conn, state, batch, &mut report).await?;
+        }
+    }
+
+    conn.close().await;
+    Ok(report)
+}
+
+/// Run the negentropy exchange, returning the ids only we have and only the peer has
+async fn reconcile(conn: &mut PeerConnection, state: &Arc<AppState>, filter: &Filter) -> Result<(Vec<EventId>, Vec<EventId>), String> {
+    let max_records = state.config.negentropy_max_records;
+    let items = state.store
+        .negentropy_items(filter, max_records + 1)
+        .await
+        .map_err(|e| format!("could not load local events: {}", e))?;
+    if items.len() > max_records {
+        return Err(format!("filter matches more than {} local events", max_records));
+    }
+
+    let mut neg = Negentropy::new(32, Some(FRAME_SIZE_LIMIT)).map_err(|e| e.to_string())?;
+    for (created_at, id) in items {
+        neg.add_item(created_at, Bytes::from_slice(id.as_bytes())).map_err(|e| e.to_string())?;
+    }
+    neg.seal().map_err(|e| e.to_string())?;
+    let initial = neg.initiate().map_err(|e| e.to_string())?;
+    conn.send(json!(["NEG-OPEN", SUB_ID, filter, initial.to_hex()])).await?;
+
+    let mut have_ids = Vec::new();
+    let mut need_ids = Vec::new();
+    loop {
+        let reply = conn.recv().await?;
+        if reply.get(1).and_then(|v| v.as_str()) != Some(SUB_ID) {
+            continue;
+        }
+        match reply[0].as_str() {
+            Some("NEG-MSG") => {
+                let query = reply.get(2)
+                    .and_then(|v| v.as_str())
+                    .and_then(|hex_msg| Bytes::from_hex(hex_msg).ok())
+                    .ok_or("peer sent a NEG-MSG that is not hex")?;
+                let next = neg
+                    .reconcile_with_ids(&query, &mut have_ids, &mut need_ids)
+                    .map_err(|e| format!("reconciliation failed: {}", e))?;
+                match next {
+                    Some(message) => conn.send(json!(["NEG-MSG", SUB_ID, message.to_hex()])).await?,
+                    None => break,
+                }
+            }
+            Some("NEG-ERR") => {
+                return Err(format!("peer refused: {}", reply.get(2).and_then(|v| v.as_str()).unwrap_or("")));
+            }
+            _ => {}
+        }
+    }
+    conn.send(json!(["NEG-CLOSE", SUB_ID])).await?;
+
+    Ok((to_event_ids(have_ids), to_event_ids(need_ids)))
+}
+
+/// Download events by id and store them under the rules a client's would face
+async fn fetch(conn: &mut PeerConnection, state: &Arc<AppState>, ids: &[EventId], report: &mut SyncReport) -> Result<(), String> {
+    conn.send(json!(["REQ", SUB_ID, Filter::new().ids(ids.iter().copied())])).await?;
+    let wanted: HashSet<&EventId> = ids.iter().collect();
+
+    loop {
+        let reply = conn.recv().await?;
+        if reply.get(1).and_then(|v| v.as_str()) != Some(SUB_ID) {
+            continue;
+        }
+        match reply[0].as_str() {
+            Some("EVENT") => {
+                let outcome = match reply.get(2).and_then(|v| serde_json::from_value::<Event>(v.clone()).ok()) {
+                    Some(event) if wanted.contains(&event.id) => process_event(event, state, Origin::Upstream).await,
+                    Some(_) => Err("invalid: not an event we asked for".to_string()),
+                    None => Err("invalid: malformed event JSON".to_string()),
+                };
+                match outcome {
+                    Ok(Accepted::Stored) => report.stored += 1,
+                    Ok(Accepted::Duplicate) => report.duplicates += 1,
+                    Err(reason) => *report.rejected.entry(reason).or_default() += 1,
+                }
+            }
+            Some("EOSE") => break,
+            Some("CLOSED") => {
+                return Err(format!("peer closed the fetch: {}", reply.get(2).and_then(|v| v.as_str()).unwrap_or("")));
+            }
+            _ => {}
+        }
+    }
+    conn.send(json!(["CLOSE", SUB_ID])).await
+}
+
+/// Publish our copies of `ids` to the peer and wait for each OK
+async fn upload(conn: &mut PeerConnection, state: &Arc<AppState>, ids: &[EventId], report: &mut SyncReport) -> Result<(), String> {
+    let events = state.store
+        .query(&Filter::new().ids(ids.iter().copied()), ids.len())
+        .await
+        .map_err(|e| format!("could not load events to upload: {}", e))?;
+
+    let mut pending = HashSet::new();
+    for event in events {
+        pending.insert(event.id.to_hex());
+        conn.send(json!(["EVENT", event])).await?;
+    }
+
+    while !pending.is_empty() {
+        let reply = conn.recv().await?;
+        if reply[0].as_str() != Some("OK") {
+            continue;
+        }
+        let Some(id) = reply.get(1).and_then(|v| v.as_str()) else { continue };
+        if pending.remove(id) {
+            if reply.get(2).and_then(|v| v.as_bool()) == Some(true) {
+                report.uploaded += 1;
+            } else {
+                report.upload_rejected += 1;
+                debug!("{} rejected {}: {}", conn.url, id, reply.get(3).and_then(|v| v.as_str()).unwrap_or(""));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn to_event_ids(ids: Vec<Bytes>) -> Vec<EventId> {
+    ids.iter().filter_map(|id| EventId::from_slice(id.as_bytes()).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{router, tests::state};
+    use nostr::{EventBuilder, Keys, ToBech32};
+    use std::net::SocketAddr;
+
+    /// Serve `state` on a local port, returning its websocket URL
+    async fn listen(state: Arc<AppState>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, router(state).into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+        });
+        format!("ws://{}", addr)
+    }
+
+    async fn allow(state: &AppState, keys: &Keys) {
+        let pubkey = keys.public_key();
+        state.store.allow_user(&pubkey.to_bech32().unwrap(), &pubkey.to_hex(), None).await.unwrap();
+    }
+
+    async fn note(state: &AppState, keys: &Keys, content: &str) -> EventId {
+        let event = EventBuilder::text_note(content, []).to_event(keys).unwrap();
+        assert!(state.store.save_event(&event, None).await.unwrap());
+        event.id
+    }
+
+    async fn ids(state: &AppState) -> HashSet<EventId> {
+        state.store.query(&Filter::new(), 100).await.unwrap().into_iter().map(|e| e.id).collect()
+    }
+
+    #[tokio::test]
+    async fn upload_sync_converges_both_relays() {
+        let (local, remote) = (state().await, state().await);
+        let (alice, bob) = (Keys::generate(), Keys::generate());
+        for relay in [&local, &remote] {
+            allow(relay, &alice).await;
+            allow(relay, &bob).await;
+        }
+
+        let shared = EventBuilder::text_note("on both", []).to_event(&alice).unwrap();
+        for relay in [&local, &remote] {
+            relay.store.save_event(&shared, None).await.unwrap();
+        }
+        let only_local = [note(&local, &alice, "local 1").await, note(&local, &bob, "local 2").await];
+        let only_remote = [note(&remote, &bob, "remote 1").await];
+
+        let peer = SyncPeer { url: listen(remote.clone()).await, filter: Filter::new(), interval_secs: 0, upload: true };
+        let report = sync_peer(&local, &peer).await.unwrap();
+
+        assert_eq!((report.have, report.need), (only_local.len(), only_remote.len()));
+        assert_eq!((report.stored, report.uploaded, report.upload_rejected), (1, 2, 0));
+        assert!(report.rejected.is_empty(), "{:?}", report.rejected);
+
+        let expected: HashSet<EventId> = [shared.id].into_iter().chain(only_local).chain(only_remote).collect();
+        assert_eq!(ids(&local).await, expected);
+        assert_eq!(ids(&remote).await, expected);
+
+        // Nothing left to exchange
+        let again = sync_peer(&local, &peer).await.unwrap();
+        assert_eq!((again.have, again.need), (0, 0));
+    }
+}